@@ -0,0 +1,69 @@
+//! Compression of terminal output before it is encrypted.
+//!
+//! Encrypted data does not compress, so output is compressed by the client
+//! that runs the shell, when the server agrees to it. Each chunk is compressed
+//! separately with raw deflate, so that viewers can start from any chunk the
+//! server still has. Keep this file consistent with the browser implementation
+//! in `src/lib/compress.ts`.
+//!
+//! The server also uses these functions to compress large WebSocket messages
+//! that are not encrypted, like the list of users in a session.
+
+use std::io::{self, Read, Write};
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+
+/// Maximum size of a chunk of output after decompression.
+///
+/// Clients send much smaller chunks, so this only guards viewers against
+/// chunks that expand without bound.
+pub const MAX_INFLATED_BYTES: u64 = 1 << 20;
+
+/// Compress a chunk of output with raw deflate.
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec cannot fail");
+    encoder.finish().expect("writing to a Vec cannot fail")
+}
+
+/// Decompress a chunk of output that was compressed with [`deflate`].
+pub fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    DeflateDecoder::new(data)
+        .take(MAX_INFLATED_BYTES + 1)
+        .read_to_end(&mut buf)?;
+    if buf.len() as u64 > MAX_INFLATED_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "chunk is too large after decompression",
+        ));
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deflate, inflate, MAX_INFLATED_BYTES};
+
+    #[test]
+    fn roundtrip() {
+        let data = "compiling sshx-core v0.4.1\n".repeat(100);
+        let compressed = deflate(data.as_bytes());
+        assert!(compressed.len() < data.len() / 10);
+        assert_eq!(inflate(&compressed).unwrap(), data.as_bytes());
+        assert_eq!(inflate(&deflate(b"")).unwrap(), b"");
+    }
+
+    #[test]
+    fn too_large() {
+        let data = vec![0; MAX_INFLATED_BYTES as usize + 1];
+        assert!(inflate(&deflate(&data)).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(inflate(b"not deflate data").is_err());
+    }
+}
@@ -1,7 +1,22 @@
 //! Encryption of byte streams based on a random key.
+//!
+//! This is used for end-to-end encryption between the terminal source and its
+//! viewers, so the server never sees plaintext. Keep this file consistent with
+//! the browser implementation in `src/lib/encrypt.ts`.
+//!
+//! Every byte stream is encrypted with AES-128 in CTR mode. The first 8 bytes
+//! of the IV hold a nonzero stream number and the last 8 bytes hold the block
+//! counter, so a byte at offset `n` of a stream can be encrypted or decrypted
+//! independently of the bytes before it. Stream numbers are assigned as:
+//!
+//! - `0`: reserved for the [`Encrypt::zeros`] verifier block.
+//! - [`OUTPUT_STREAM`]` | sid`: terminal output of the shell `sid`.
+//! - [`INPUT_STREAM`]: terminal input from viewers, for all shells.
 
 use aes::cipher::{KeyIvInit, StreamCipher, StreamCipherSeek};
 
+use crate::Sid;
+
 type Aes128Ctr64BE = ctr::Ctr64BE<aes::Aes128>;
 
 // Note: The KDF salt is public, as it needs to be used from the web client. It
@@ -9,14 +24,31 @@ type Aes128Ctr64BE = ctr::Ctr64BE<aes::Aes128>;
 const SALT: &str =
     "This is a non-random salt for sshx.io, since we want to stretch the security of 83-bit keys!";
 
+/// Base stream number for terminal output, combined with the shell ID.
+pub const OUTPUT_STREAM: u64 = 0x100000000;
+
+/// Stream number for terminal input sent to the client, shared by all shells.
+///
+/// Viewers choose their own offsets into this stream, so callers should avoid
+/// reusing an offset for different data.
+pub const INPUT_STREAM: u64 = 0x200000000;
+
+/// Returns the stream number used to encrypt the output of a shell.
+pub const fn output_stream(id: Sid) -> u64 {
+    OUTPUT_STREAM | id.0 as u64
+}
+
 /// Encrypts byte streams using the Argon2 hash of a random key.
 #[derive(Clone)]
 pub struct Encrypt {
-    aes_key: [u8; 16], // 16-bit
+    aes_key: [u8; 16], // 128-bit
 }
 
 impl Encrypt {
     /// Construct a new encryptor.
+    ///
+    /// This runs the key derivation function, which is deliberately slow, so
+    /// async callers should run it on a blocking thread.
     pub fn new(key: &str) -> Self {
         use argon2::{Algorithm, Argon2, Params, Version};
         // These parameters must match the browser implementation.
@@ -33,6 +65,9 @@ impl Encrypt {
     }
 
     /// Get the encrypted zero block.
+    ///
+    /// This is sent to the server in place of the key, so that viewers can
+    /// prove they have the same key without revealing it.
     pub fn zeros(&self) -> Vec<u8> {
         let mut zeros = [0; 16];
         let mut cipher = Aes128Ctr64BE::new(&self.aes_key.into(), &zeros.into());
@@ -60,7 +95,8 @@ impl Encrypt {
 
 #[cfg(test)]
 mod tests {
-    use super::Encrypt;
+    use super::{output_stream, Encrypt, INPUT_STREAM};
+    use crate::Sid;
 
     #[test]
     fn make_encrypt() {
@@ -99,4 +135,35 @@ mod tests {
         let encrypt = Encrypt::new("this is a test key");
         encrypt.segment(0, 0, b"hello world");
     }
+
+    #[test]
+    fn stream_numbers() {
+        assert_eq!(output_stream(Sid(1)), 0x100000001);
+        assert_eq!(output_stream(Sid(u32::MAX)), 0x1ffffffff);
+        assert_ne!(output_stream(Sid(0)), INPUT_STREAM);
+    }
+
+    // These vectors were checked against `Encrypt` in `src/lib/encrypt.ts`,
+    // which pads unaligned offsets to a block boundary before encrypting.
+    #[test]
+    fn browser_vectors() {
+        let encrypt = Encrypt::new("MYuf2Y7gZmxAqu");
+        assert_eq!(
+            encrypt.aes_key,
+            [202, 45, 56, 51, 253, 229, 217, 199, 231, 201, 252, 208, 201, 203, 163, 84],
+        );
+        assert_eq!(
+            encrypt.zeros(),
+            [141, 215, 145, 170, 211, 28, 44, 221, 152, 115, 134, 69, 156, 51, 89, 168],
+        );
+        assert_eq!(
+            encrypt.segment(output_stream(Sid(1)), 0, b"hello world\r\n"),
+            [123, 18, 69, 106, 89, 32, 100, 8, 253, 231, 42, 209, 224],
+        );
+        assert_eq!(
+            encrypt.segment(output_stream(Sid(7)), 37, b"$ ls -la\r\n"),
+            [85, 29, 63, 232, 58, 111, 50, 117, 246, 202],
+        );
+        assert_eq!(encrypt.segment(INPUT_STREAM, 4242, b"ls\r"), [28, 1, 175],);
+    }
 }
@@ -8,6 +8,19 @@ use std::sync::atomic::{AtomicU32, Ordering};
 
 use serde::{Deserialize, Serialize};
 
+pub mod compress;
+pub mod crypto;
+pub mod tunnel;
+pub mod web;
+
+/// Version of the protocol spoken between clients and the server.
+///
+/// Clients send this when opening a session and when connecting over
+/// WebSocket, and the server replies with its own. Clients from before the
+/// version was sent are treated as version 0. Increment it whenever a message
+/// changes shape or meaning, and keep it consistent with `src/lib/protocol.ts`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
 /// Protocol buffer and gRPC definitions, automatically generated by Tonic.
 #[allow(missing_docs, non_snake_case)]
 #[allow(clippy::derive_partial_eq_without_eq)]
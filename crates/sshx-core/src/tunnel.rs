@@ -0,0 +1,91 @@
+//! Calls from the command-line client tunneled over WebSocket, for networks
+//! where gRPC over HTTP/2 does not work.
+//!
+//! Each call is a WebSocket connection to `/api/cli/{method}`, where `method`
+//! is `open`, `channel`, or `close`. Every binary message holds one protobuf:
+//! the request or [`ClientUpdate`] messages from the client, and the response
+//! or [`ServerUpdate`] messages from the server. Errors close the connection
+//! with a code that carries the gRPC status code.
+//!
+//! [`ClientUpdate`]: crate::proto::ClientUpdate
+//! [`ServerUpdate`]: crate::proto::ServerUpdate
+
+use tonic::{Code, Status};
+
+/// Close codes from this value carry a gRPC status code, added to it.
+const STATUS_CLOSE_BASE: u16 = 4000;
+
+/// Longest reason allowed in a WebSocket close frame, in bytes.
+const MAX_CLOSE_REASON: usize = 123;
+
+/// Returns the WebSocket URL for a call to a server at an HTTP(S) origin.
+pub fn url(origin: &str, method: &str) -> String {
+    let origin = origin.trim_end_matches('/');
+    let origin = if let Some(host) = origin.strip_prefix("https://") {
+        format!("wss://{host}")
+    } else if let Some(host) = origin.strip_prefix("http://") {
+        format!("ws://{host}")
+    } else {
+        origin.to_string()
+    };
+    format!("{origin}/api/cli/{method}")
+}
+
+/// Returns the close code and reason that report a status to the client.
+pub fn status_to_close(status: &Status) -> (u16, String) {
+    let mut reason = status.message().to_string();
+    if reason.len() > MAX_CLOSE_REASON {
+        let mut end = MAX_CLOSE_REASON;
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        reason.truncate(end);
+    }
+    (STATUS_CLOSE_BASE + status.code() as u16, reason)
+}
+
+/// Returns the status reported by a close code and reason from the server.
+pub fn close_to_status(code: u16, reason: &str) -> Status {
+    match code.checked_sub(STATUS_CLOSE_BASE) {
+        Some(code) if code <= Code::Unauthenticated as u16 => {
+            Status::new(Code::from(code as i32), reason)
+        }
+        _ => Status::unavailable(format!("connection closed ({code}): {reason}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{Code, Status};
+
+    use super::{close_to_status, status_to_close, url};
+
+    #[test]
+    fn websocket_url() {
+        assert_eq!(url("https://sshx.io", "open"), "wss://sshx.io/api/cli/open");
+        assert_eq!(
+            url("http://localhost:8051/", "channel"),
+            "ws://localhost:8051/api/cli/channel"
+        );
+    }
+
+    #[test]
+    fn status_roundtrip() {
+        let status = Status::unauthenticated("invalid token");
+        let (code, reason) = status_to_close(&status);
+        let status = close_to_status(code, &reason);
+        assert_eq!(status.code(), Code::Unauthenticated);
+        assert_eq!(status.message(), "invalid token");
+
+        let status = close_to_status(1011, "internal error");
+        assert_eq!(status.code(), Code::Unavailable);
+    }
+
+    #[test]
+    fn long_reason() {
+        let status = Status::internal("é".repeat(100));
+        let (_, reason) = status_to_close(&status);
+        assert!(reason.len() <= 123);
+        assert!(reason.chars().all(|c| c == 'é'));
+    }
+}
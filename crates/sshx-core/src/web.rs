@@ -1,8 +1,12 @@
-//! Serializable types sent and received by the web server.
+//! Serializable types sent and received over WebSocket by the web server.
+//!
+//! Messages are encoded with CBOR. These are shared by the server, the browser
+//! frontend in `src/lib/protocol.ts`, and Rust clients that join a session.
 
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use sshx_core::{Sid, Uid};
+
+use crate::{Sid, Uid};
 
 /// Real-time message conveying the position and size of a terminal.
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
@@ -48,7 +52,11 @@ pub struct WsUser {
 #[serde(rename_all = "camelCase")]
 pub enum WsServer {
     /// Initial server message, with the user's ID and session metadata.
-    Hello(Uid, String),
+    ///
+    /// The third field is whether terminal output is compressed with
+    /// [`crate::compress::deflate`] before it is encrypted, and the last is
+    /// the server's [`crate::PROTOCOL_VERSION`].
+    Hello(Uid, String, bool, u32),
     /// The user's authentication was invalid.
     InvalidAuth(),
     /// A snapshot of all current users in the session.
@@ -67,6 +75,12 @@ pub enum WsServer {
     Pong(u64),
     /// Alert the client of an application error.
     Error(String),
+    /// Another message, encoded with CBOR and compressed with
+    /// [`crate::compress::deflate`].
+    ///
+    /// This is only sent to clients of protocol version 2 or later, for large
+    /// messages. Terminal output is never sent this way, since it is encrypted.
+    Deflated(Bytes),
 }
 
 /// A real-time message sent from the client over WebSocket.
@@ -74,8 +88,8 @@ pub enum WsServer {
 #[serde(rename_all = "camelCase")]
 pub enum WsClient {
     /// Authenticate the user's encryption key by zeros block and write password
-    /// (if provided).
-    Authenticate(Bytes, Option<Bytes>),
+    /// (if provided), along with the client's [`crate::PROTOCOL_VERSION`].
+    Authenticate(Bytes, Option<Bytes>, u32),
     /// Set the name of the current user.
     SetName(String),
     /// Send real-time information about the user's cursor.
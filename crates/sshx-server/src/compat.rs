@@ -0,0 +1,104 @@
+//! Compatibility with clients that speak older versions of the protocol.
+//!
+//! Clients send their [`PROTOCOL_VERSION`] when opening a session and when
+//! authenticating over WebSocket. Messages from older clients are translated
+//! here, so the rest of the server only handles the current protocol. Clients
+//! that are newer than the server adapt to the version it replies with.
+//!
+//! [`PROTOCOL_VERSION`]: sshx_core::PROTOCOL_VERSION
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::Deserialize;
+use sshx_core::proto::{Hello, LiveShell};
+
+use crate::web::protocol::{WsClient, WsWinsize};
+
+/// Protocol version of clients from before the version was sent.
+pub const LEGACY_VERSION: u32 = 0;
+
+/// First protocol version where viewers can decompress terminal output.
+const COMPRESSION_VERSION: u32 = 1;
+
+/// First protocol version where viewers accept compressed messages.
+const DEFLATE_VERSION: u32 = 2;
+
+/// WebSocket messages from clients before version 1, where they differ from
+/// [`WsClient`].
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum LegacyWsClient {
+    Authenticate(Bytes, Option<Bytes>),
+}
+
+/// Decode a message from a WebSocket client of any supported version.
+pub fn decode_ws_client(msg: &[u8]) -> Result<WsClient> {
+    let err = match ciborium::de::from_reader(msg) {
+        Ok(msg) => return Ok(msg),
+        Err(err) => err,
+    };
+    match ciborium::de::from_reader(msg) {
+        Ok(LegacyWsClient::Authenticate(zeros, write_zeros)) => {
+            Ok(WsClient::Authenticate(zeros, write_zeros, LEGACY_VERSION))
+        }
+        Err(_) => Err(err.into()),
+    }
+}
+
+/// Parse the first channel message from clients before version 1, of the form
+/// `"name,token;id,id,..."`.
+///
+/// These clients do not send the windows of their shells, so shells are
+/// recovered at the origin with the default size.
+pub fn parse_legacy_hello(hello: &str) -> Option<Hello> {
+    let (creds, ids) = hello.split_once(';').unwrap_or((hello, ""));
+    let (name, token) = creds.split_once(',')?;
+    let winsize = WsWinsize::default();
+    let shells = ids
+        .split(',')
+        .filter_map(|id| id.parse().ok())
+        .map(|id| LiveShell {
+            id,
+            x: winsize.x,
+            y: winsize.y,
+            rows: winsize.rows.into(),
+            cols: winsize.cols.into(),
+        })
+        .collect();
+    Some(Hello {
+        name: name.into(),
+        token: token.into(),
+        shells,
+    })
+}
+
+/// Check that a client is recent enough to connect, returning a message for
+/// the user if it is not.
+pub fn check_client(version: u32, min_version: u32) -> Result<(), String> {
+    if version < min_version {
+        return Err(format!(
+            "this client is too old for the server (protocol version {version}, \
+             need at least {min_version}), please upgrade sshx"
+        ));
+    }
+    Ok(())
+}
+
+/// Check that a viewer is recent enough to display a session, returning a
+/// message for the user if it is not.
+pub fn check_viewer(version: u32, min_version: u32, compressed: bool) -> Result<(), String> {
+    check_client(version, min_version)?;
+    if compressed && version < COMPRESSION_VERSION {
+        return Err(format!(
+            "this session compresses its output, which needs protocol version \
+             {COMPRESSION_VERSION} or later, please upgrade sshx"
+        ));
+    }
+    Ok(())
+}
+
+/// Returns whether a viewer accepts messages wrapped in
+/// [`WsServer::Deflated`](crate::web::protocol::WsServer::Deflated).
+pub fn can_deflate(version: u32) -> bool {
+    version >= DEFLATE_VERSION
+}
@@ -4,25 +4,30 @@ use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use base64::prelude::{Engine as _, BASE64_STANDARD};
-use hmac::Mac;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use sshx_core::proto::{
     client_update::ClientMessage, server_update::ServerMessage, sshx_service_server::SshxService,
-    ClientUpdate, CloseRequest, CloseResponse, OpenRequest, OpenResponse, ServerUpdate,
+    ClientUpdate, CloseRequest, CloseResponse, Compression, DrainRequest, DrainResponse,
+    OpenRequest, OpenResponse, ServerUpdate,
 };
-use sshx_core::{rand_alphanumeric, Sid};
+use sshx_core::{rand_alphanumeric, Sid, PROTOCOL_VERSION};
 use tokio::sync::mpsc;
 use tokio::time::{self, MissedTickBehavior};
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
 use tonic::{Request, Response, Status, Streaming};
 use tracing::{error, info, warn};
 
+use crate::compat;
+use crate::ratelimit::{Action, ClientAddr};
 use crate::session::{Metadata, Session};
+use crate::web::protocol::WsWinsize;
 use crate::ServerState;
 
-/// Interval for synchronizing sequence numbers with the client.
+/// Default interval for synchronizing sequence numbers with the client.
 pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
 
-/// Interval for measuring client latency.
+/// Default interval for measuring client latency.
 pub const PING_INTERVAL: Duration = Duration::from_secs(2);
 
 /// Server that handles gRPC requests from the sshx command-line client.
@@ -34,6 +39,71 @@ impl GrpcServer {
     pub fn new(state: Arc<ServerState>) -> Self {
         Self(state)
     }
+
+    /// Start streaming updates with a client, over gRPC or the WebSocket
+    /// transport in [`crate::web`].
+    pub(crate) async fn start_channel(
+        &self,
+        client: ClientAddr,
+        mut stream: impl Stream<Item = Result<ClientUpdate, Status>> + Send + Unpin + 'static,
+    ) -> Result<ReceiverStream<Result<ServerUpdate, Status>>, Status> {
+        if !self.0.check_rate(client, Action::Channel) {
+            return Err(Status::resource_exhausted("too many connections, try again later"));
+        }
+        let first_update = match stream.next().await {
+            Some(result) => result?,
+            None => return Err(Status::invalid_argument("missing first message")),
+        };
+        let hello = match first_update.client_message {
+            Some(ClientMessage::Hello(hello)) => hello,
+            Some(ClientMessage::LegacyHello(hello)) => compat::parse_legacy_hello(&hello)
+                .ok_or_else(|| Status::invalid_argument("missing name and token"))?,
+            _ => return Err(Status::invalid_argument("invalid first message")),
+        };
+        validate_token(self.0.mac(), &hello.name, &hello.token)?;
+        let session = match self.0.backend_connect(&hello.name).await {
+            Ok(Some(session)) => session,
+            Ok(None) => return Err(Status::not_found("session not found")),
+            Err(err) => {
+                error!(?err, "failed to connect to backend session");
+                return Err(Status::internal(err.to_string()));
+            }
+        };
+
+        // Recover shells that are still running on the client, in case this
+        // server lost them. Shells that the session still has are kept as is.
+        for shell in hello.shells {
+            let id = Sid(shell.id);
+            let winsize = WsWinsize {
+                x: shell.x,
+                y: shell.y,
+                rows: shell.rows as u16,
+                cols: shell.cols as u16,
+            };
+            if session.restore_shell(id, winsize).is_ok() {
+                // Make sure the terminal matches its restored window.
+                if let Err(err) = session.send_resize(id, winsize).await {
+                    warn!(?err, "failed to resize recovered shell");
+                }
+            }
+        }
+
+        // We now spawn an asynchronous task that sends updates to the client. Note that
+        // when this task finishes, the sender end is dropped, so the receiver is
+        // automatically closed.
+        let (tx, rx) = mpsc::channel(16);
+        let state = Arc::clone(&self.0);
+        tokio::spawn(async move {
+            let metrics = state.metrics();
+            metrics.grpc_streams.inc();
+            if let Err(err) = handle_streaming(&tx, &session, &state, stream).await {
+                warn!(?err, "connection exiting early due to an error");
+            }
+            metrics.grpc_streams.dec();
+        });
+
+        Ok(ReceiverStream::new(rx))
+    }
 }
 
 type RR<T> = Result<Response<T>, Status>;
@@ -43,11 +113,28 @@ impl SshxService for GrpcServer {
     type ChannelStream = ReceiverStream<Result<ServerUpdate, Status>>;
 
     async fn open(&self, request: Request<OpenRequest>) -> RR<OpenResponse> {
+        if !self.0.check_rate(client_addr(&request), Action::Open) {
+            return Err(Status::resource_exhausted("too many sessions opened, try again later"));
+        }
+        if self.0.at_session_limit() {
+            return Err(Status::resource_exhausted("server has too many open sessions"));
+        }
         let request = request.into_inner();
+        let min_version = self.0.limits().min_protocol_version;
+        if let Err(msg) = compat::check_client(request.protocol_version, min_version) {
+            return Err(Status::failed_precondition(msg));
+        }
         let origin = self.0.override_origin().unwrap_or(request.origin.clone());
         if origin.is_empty() {
             return Err(Status::invalid_argument("origin is empty"));
         }
+        // Viewers can always decompress output, so use it whenever the client
+        // opts in. Clients leave it off by default, since it leaks lengths.
+        let compression = if request.compression().any(|c| c == Compression::Deflate) {
+            Compression::Deflate
+        } else {
+            Compression::None
+        };
         
         // Check for session ID reuse (RECONNECT:old_id|...)
         let (session_id, clean_name) = if request.name.starts_with("RECONNECT:") {
@@ -121,8 +208,9 @@ impl SshxService for GrpcServer {
                     cpu,
                     memory_mb,
                     os_info,
+                    compression,
                 };
-                self.0.insert(&session_id, Arc::new(Session::new(metadata)));
+                self.0.insert(&session_id, Arc::new(self.0.new_session(metadata)));
             }
         };
         let token = self.0.mac().chain_update(&session_id).finalize();
@@ -142,62 +230,15 @@ impl SshxService for GrpcServer {
             name: session_id,
             token: BASE64_STANDARD.encode(token.into_bytes()),
             url,
+            compression: compression.into(),
+            protocol_version: PROTOCOL_VERSION,
         }))
     }
 
     async fn channel(&self, request: Request<Streaming<ClientUpdate>>) -> RR<Self::ChannelStream> {
-        let mut stream = request.into_inner();
-        let first_update = match stream.next().await {
-            Some(result) => result?,
-            None => return Err(Status::invalid_argument("missing first message")),
-        };
-        let (session_name, ids_to_recover) = match first_update.client_message {
-            Some(ClientMessage::Hello(hello)) => {
-                let (creds, ids_str) = hello.split_once(';').unwrap_or((&hello, ""));
-                let (name, token) = creds
-                    .split_once(',')
-                    .ok_or_else(|| Status::invalid_argument("missing name and token"))?;
-                validate_token(self.0.mac(), name, token)?;
-                (name.to_string(), ids_str.to_string())
-            }
-            _ => return Err(Status::invalid_argument("invalid first message")),
-        };
-        let session = match self.0.backend_connect(&session_name).await {
-            Ok(Some(session)) => session,
-            Ok(None) => return Err(Status::not_found("session not found")),
-            Err(err) => {
-                error!(?err, "failed to connect to backend session");
-                return Err(Status::internal(err.to_string()));
-            }
-        };
-        
-        // Recover shells from client state
-        if !ids_to_recover.is_empty() {
-            for id_str in ids_to_recover.split(',') {
-                if let Ok(id) = id_str.parse::<u32>() {
-                    let sid = Sid(id);
-                    // Try to re-register the shell if it doesn't exist.
-                    // We use (0, 0) as dummy center, client/frontend will update later if needed.
-                    let _ = session.add_shell(sid, (0, 0));
-                    // Force resize to trigger repaint (SIGWINCH)
-                    if let Err(e) = session.send_resize(sid, 24, 80).await {
-                         warn!(?e, "failed to send force resize");
-                    }
-                }
-            }
-        }
-
-        // We now spawn an asynchronous task that sends updates to the client. Note that
-        // when this task finishes, the sender end is dropped, so the receiver is
-        // automatically closed.
-        let (tx, rx) = mpsc::channel(16);
-        tokio::spawn(async move {
-            if let Err(err) = handle_streaming(&tx, &session, stream).await {
-                warn!(?err, "connection exiting early due to an error");
-            }
-        });
-
-        Ok(Response::new(ReceiverStream::new(rx)))
+        let client = client_addr(&request);
+        let stream = self.start_channel(client, request.into_inner()).await?;
+        Ok(Response::new(stream))
     }
 
     async fn close(&self, request: Request<CloseRequest>) -> RR<CloseResponse> {
@@ -210,6 +251,50 @@ impl SshxService for GrpcServer {
         }
         Ok(Response::new(CloseResponse {}))
     }
+
+    async fn drain(&self, request: Request<DrainRequest>) -> RR<DrainResponse> {
+        let request = request.into_inner();
+        let message = drain_message(&request.origin);
+        validate_token(self.0.mac(), &message, &request.token)?;
+        if request.origin.is_empty() {
+            return Err(Status::invalid_argument("origin is empty"));
+        }
+        info!("draining sessions to {}", request.origin);
+        match self.0.drain(&request.origin).await {
+            Ok((count, skipped)) => Ok(Response::new(DrainResponse {
+                sessions: count as u32,
+                skipped,
+            })),
+            Err(err) => {
+                error!(?err, "failed to drain sessions");
+                Err(Status::internal(err.to_string()))
+            }
+        }
+    }
+}
+
+/// Returns the address of the client that made a request.
+fn client_addr<T>(request: &Request<T>) -> ClientAddr {
+    request
+        .extensions()
+        .get::<ClientAddr>()
+        .copied()
+        .unwrap_or_default()
+}
+
+/// Returns the token that authorizes draining a server to a peer origin.
+pub fn drain_token(secret: &str, origin: &str) -> String {
+    let mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+    let token = mac.chain_update(drain_message(origin)).finalize();
+    BASE64_STANDARD.encode(token.into_bytes())
+}
+
+/// Message signed in a drain token.
+///
+/// This contains a `|` character, which never appears in session names, so it
+/// cannot be confused with the token of a session.
+fn drain_message(origin: &str) -> String {
+    format!("|drain|{origin}")
 }
 
 /// Validate the client token for a session.
@@ -229,12 +314,14 @@ type ServerTx = mpsc::Sender<Result<ServerUpdate, Status>>;
 async fn handle_streaming(
     tx: &ServerTx,
     session: &Session,
-    mut stream: Streaming<ClientUpdate>,
+    state: &ServerState,
+    mut stream: impl Stream<Item = Result<ClientUpdate, Status>> + Unpin,
 ) -> Result<(), &'static str> {
-    let mut sync_interval = time::interval(SYNC_INTERVAL);
+    let limits = state.limits();
+    let mut sync_interval = time::interval(limits.sync_interval);
     sync_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
-    let mut ping_interval = time::interval(PING_INTERVAL);
+    let mut ping_interval = time::interval(limits.ping_interval);
     ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
     loop {
@@ -259,7 +346,7 @@ async fn handle_streaming(
             // Handle incoming client messages.
             maybe_update = stream.next() => {
                 if let Some(Ok(update)) = maybe_update {
-                    if !handle_update(tx, session, update).await {
+                    if !handle_update(tx, session, state, update).await {
                         return Err("error responding to client update");
                     }
                 } else {
@@ -278,19 +365,32 @@ async fn handle_streaming(
 }
 
 /// Handles a singe update from the client. Returns `true` on success.
-async fn handle_update(tx: &ServerTx, session: &Session, update: ClientUpdate) -> bool {
+async fn handle_update(
+    tx: &ServerTx,
+    session: &Session,
+    state: &ServerState,
+    update: ClientUpdate,
+) -> bool {
     session.access();
     match update.client_message {
-        Some(ClientMessage::Hello(_)) => {
+        Some(ClientMessage::Hello(_) | ClientMessage::LegacyHello(_)) => {
             return send_err(tx, "unexpected hello".into()).await;
         }
         Some(ClientMessage::Data(data)) => {
+            state.metrics().data_bytes.inc_by(data.data.len() as u64);
+            if data.compression() != session.metadata().compression {
+                return send_err(tx, "data does not use the session's compression".into()).await;
+            }
             if let Err(err) = session.add_data(Sid(data.id), data.data, data.seq) {
                 return send_err(tx, format!("add data: {:?}", err)).await;
             }
         }
         Some(ClientMessage::CreatedShell(new_shell)) => {
             let id = Sid(new_shell.id);
+            if state.at_shell_limit() {
+                send_msg(tx, ServerMessage::CloseShell(id.0)).await;
+                return send_err(tx, "server has too many open shells".into()).await;
+            }
             let center = (new_shell.x, new_shell.y);
             if let Err(err) = session.add_shell(id, center) {
                 return send_err(tx, format!("add shell: {:?}", err)).await;
@@ -12,20 +12,29 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
-use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+use std::{fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc};
 
 use anyhow::Result;
 use axum::serve::{Listener, ListenerExt};
 use tokio::net::TcpListener;
-use tracing::debug;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::{debug, error};
 use utils::Shutdown;
 
-use crate::state::ServerState;
+use crate::config::Limits;
+use crate::state::{storage::SessionStore, ServerState};
+use crate::tls::ServerTls;
 
+mod compat;
+pub mod config;
 pub mod grpc;
 mod listen;
+pub mod metrics;
+pub mod ratelimit;
 pub mod session;
 pub mod state;
+pub mod tls;
 pub mod utils;
 pub mod web;
 
@@ -39,24 +48,104 @@ pub struct ServerOptions {
     /// Override the origin returned for the Open() RPC.
     pub override_origin: Option<String>,
 
-    /// URL of the Redis server that stores session data.
+    /// URL of the Redis server that stores session data. With Sentinel or
+    /// cluster mode, this is a comma-separated list of URLs.
     pub redis_url: Option<String>,
 
+    /// Name of the Redis master to discover through the Sentinels at
+    /// `redis_url`, if enabled.
+    pub redis_sentinel_master: Option<String>,
+
+    /// Connect to a Redis Cluster, with `redis_url` listing the seed nodes.
+    pub redis_cluster: bool,
+
+    /// ACL username for Redis, overriding any in `redis_url`.
+    pub redis_username: Option<String>,
+
+    /// Password for Redis, overriding any in `redis_url`.
+    pub redis_password: Option<String>,
+
+    /// PEM bundle of CA certificates to verify Redis servers with.
+    pub redis_ca: Option<PathBuf>,
+
+    /// PEM client certificate for Redis servers that require mutual TLS.
+    pub redis_cert: Option<PathBuf>,
+
+    /// PEM private key of the Redis client certificate.
+    pub redis_key: Option<PathBuf>,
+
+    /// Path to a local database file that stores session data, if not using
+    /// Redis. Sessions are only kept in memory if neither is provided.
+    pub storage_path: Option<PathBuf>,
+
     /// Hostname of this server, if running multiple servers.
     pub host: Option<String>,
+
+    /// Directory to write encrypted session recordings to, if enabled.
+    pub recording_dir: Option<PathBuf>,
+
+    /// Directory to spill output pruned from memory to, if enabled.
+    pub scrollback_dir: Option<PathBuf>,
+
+    /// Directory to save sessions to on shutdown, and restore them from on the
+    /// next start.
+    pub state_dir: Option<PathBuf>,
+
+    /// Connect to peers in the mesh over TLS, with `wss://`.
+    pub peer_tls: bool,
+
+    /// Bundle of PEM certificates to verify peers with, instead of the public
+    /// web roots.
+    pub peer_ca: Option<PathBuf>,
+
+    /// Secret shared by servers in the mesh, used to authenticate connections
+    /// that are proxied between them.
+    pub mesh_secret: Option<String>,
+
+    /// Allow connections between servers to be authenticated with the mesh
+    /// secret over plain HTTP, such as behind a proxy that terminates TLS.
+    pub mesh_allow_plaintext: bool,
+
+    /// PEM certificate chain to serve TLS with, in [`Server::bind`].
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate.
+    pub tls_key: Option<PathBuf>,
+
+    /// Serve TLS with a generated, self-signed certificate for development.
+    pub tls_self_signed: bool,
+
+    /// Limits on resources used by sessions.
+    pub limits: Limits,
+
+    /// Password for the admin dashboard, instead of the built-in default.
+    pub admin_password: Option<String>,
 }
 
 /// Stateful object that manages the sshx server, with graceful termination.
 pub struct Server {
     state: Arc<ServerState>,
+    tls: Option<Arc<ServerTls>>,
     shutdown: Shutdown,
 }
 
 impl Server {
     /// Create a new application server, but do not listen for connections yet.
     pub fn new(options: ServerOptions) -> Result<Self> {
+        let tls = ServerTls::from_options(&options)?.map(Arc::new);
         Ok(Self {
             state: Arc::new(ServerState::new(options)?),
+            tls,
+            shutdown: Shutdown::new(),
+        })
+    }
+
+    /// Create a new application server that uses a custom session store.
+    pub fn with_storage(options: ServerOptions, storage: Arc<dyn SessionStore>) -> Result<Self> {
+        let tls = ServerTls::from_options(&options)?.map(Arc::new);
+        Ok(Self {
+            state: Arc::new(ServerState::with_storage(options, storage)?),
+            tls,
             shutdown: Shutdown::new(),
         })
     }
@@ -67,17 +156,23 @@ impl Server {
     }
 
     /// Run the application server, listening on a stream of connections.
+    ///
+    /// Sessions saved in the state directory, if any, are restored first.
     pub async fn listen<L>(&self, listener: L) -> Result<()>
     where
         L: Listener,
-        L::Addr: Debug,
+        L::Addr: Debug + 'static,
     {
+        self.state.restore_sessions()?;
+
         let state = self.state.clone();
         let terminated = self.shutdown.wait();
         tokio::spawn(async move {
-            let background_tasks = futures_util::future::join(
+            let background_tasks = futures_util::future::join4(
                 state.listen_for_transfers(),
                 state.close_old_sessions(),
+                state.prune_rate_limits(),
+                state.prune_scrollback(),
             );
             tokio::select! {
                 _ = terminated => {}
@@ -88,23 +183,96 @@ impl Server {
         listen::start_server(self.state(), listener, self.shutdown.wait()).await
     }
 
-    /// Convenience function to call [`Server::listen`] bound to a TCP address.
+    /// Convenience function to call [`Server::listen_tcp`] bound to an address.
+    pub async fn bind(&self, addr: &SocketAddr) -> Result<()> {
+        self.listen_tcp(TcpListener::bind(addr).await?).await
+    }
+
+    /// Run the application server on a TCP listener, with TLS if configured.
     ///
     /// This also sets `TCP_NODELAY` on the incoming connections for performance
     /// reasons, as a reasonable default.
-    pub async fn bind(&self, addr: &SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr).await?.tap_io(|tcp_stream| {
+    pub async fn listen_tcp(&self, listener: TcpListener) -> Result<()> {
+        let listener = listener.tap_io(|tcp_stream| {
             if let Err(err) = tcp_stream.set_nodelay(true) {
                 debug!("failed to set TCP_NODELAY on incoming connection: {err:#}");
             }
         });
+        self.listen_tls(listener).await
+    }
+
+    /// Run the application server on a Unix domain socket, with TLS if
+    /// configured.
+    #[cfg(unix)]
+    pub async fn listen_unix(&self, listener: UnixListener) -> Result<()> {
+        self.listen_tls(listener).await
+    }
+
+    /// Run the application server, terminating TLS if it is configured.
+    ///
+    /// Certificate files are reloaded when they change.
+    async fn listen_tls<L>(&self, listener: L) -> Result<()>
+    where
+        L: Listener,
+        L::Addr: Clone + Debug + 'static,
+    {
+        let Some(tls) = &self.tls else {
+            return self.listen(listener).await;
+        };
+
+        let listener = tls.listen(listener)?;
+        let tls = Arc::clone(tls);
+        let terminated = self.shutdown.wait();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = terminated => {}
+                _ = tls.watch() => {}
+            }
+        });
         self.listen(listener).await
     }
 
+    /// Serve Prometheus metrics at `/metrics` on a separate listener, until the
+    /// server is shut down.
+    ///
+    /// This is kept apart from the main listener so that metrics do not need
+    /// to be publicly reachable.
+    pub async fn listen_metrics(&self, listener: TcpListener) -> Result<()> {
+        let app = web::metrics_app().with_state(self.state());
+        axum::serve(listener, app)
+            .with_graceful_shutdown(self.shutdown.wait())
+            .await?;
+        Ok(())
+    }
+
+    /// Reload the TLS certificate files, if serving TLS from files.
+    pub fn reload_tls(&self) -> Result<()> {
+        match &self.tls {
+            Some(tls) => tls.reload(),
+            None => Ok(()),
+        }
+    }
+
+    /// Apply the settings in `options` that can change while running, and
+    /// reload the TLS certificate files.
+    ///
+    /// Other settings, such as storage and listening addresses, are ignored
+    /// until the server is restarted.
+    pub fn reload(&self, options: &ServerOptions) -> Result<()> {
+        self.state.reload(options)?;
+        self.reload_tls()
+    }
+
     /// Send a graceful shutdown signal to the server.
+    ///
+    /// If a state directory is configured, sessions are saved to it first.
     pub fn shutdown(&self) {
         // Stop receiving new network connections.
         self.shutdown.shutdown();
+        // Save sessions for the next server, before they are terminated.
+        if let Err(err) = self.state.save_sessions() {
+            error!(?err, "failed to save sessions");
+        }
         // Terminate each of the existing sessions.
         self.state.shutdown();
     }
@@ -1,15 +1,15 @@
-use std::{fmt::Debug, future::Future, sync::Arc};
+use std::{any::Any, convert::Infallible, fmt::Debug, future::Future, net::SocketAddr, sync::Arc};
 
 use anyhow::Result;
 use axum::body::Body;
-use axum::serve::Listener;
+use axum::serve::{IncomingStream, Listener};
 use http::{header::CONTENT_TYPE, Request};
 use sshx_core::proto::{sshx_service_server::SshxServiceServer, FILE_DESCRIPTOR_SET};
 use tonic::service::Routes as TonicRoutes;
-use tower::{make::Shared, steer::Steer, ServiceExt};
+use tower::{service_fn, steer::Steer, ServiceExt};
 use tower_http::trace::TraceLayer;
 
-use crate::{grpc::GrpcServer, web, ServerState};
+use crate::{grpc::GrpcServer, ratelimit::ClientAddr, web, ServerState};
 
 /// Bind and listen from the application, with a state and termination signal.
 ///
@@ -22,7 +22,7 @@ pub(crate) async fn start_server<L>(
 ) -> Result<()>
 where
     L: Listener,
-    L::Addr: Debug,
+    L::Addr: Debug + 'static,
 {
     let http_service = web::app()
         .with_state(state.clone())
@@ -55,7 +55,16 @@ where
             }
         },
     );
-    let make_svc = Shared::new(svc);
+    // Each connection's requests are tagged with the client's address, which is
+    // used for rate limiting in both the HTTP and gRPC services.
+    let make_svc = service_fn(move |stream: IncomingStream<'_, L>| {
+        let client = client_addr(stream.remote_addr());
+        let svc = svc.clone().map_request(move |mut req: Request<Body>| {
+            req.extensions_mut().insert(client);
+            req
+        });
+        async move { Ok::<_, Infallible>(svc) }
+    });
 
     axum::serve(listener, make_svc)
         .with_graceful_shutdown(signal)
@@ -63,3 +72,9 @@ where
 
     Ok(())
 }
+
+/// Returns the address of a remote client, with an IP address if it connected
+/// over TCP.
+fn client_addr(addr: &dyn Any) -> ClientAddr {
+    ClientAddr(addr.downcast_ref::<SocketAddr>().map(|addr| addr.ip()))
+}
@@ -1,57 +1,342 @@
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::{AddrParseError, IpAddr, SocketAddr},
+    path::{Path, PathBuf},
     process::ExitCode,
+    str::FromStr,
 };
 
-use anyhow::Result;
-use clap::Parser;
-use sshx_server::{Server, ServerOptions};
-use tracing::{error, info};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use listenfd::ListenFd;
+use sshx_core::proto::{sshx_service_client::SshxServiceClient, DrainRequest};
+use sshx_server::{config::ConfigFile, grpc::drain_token, Server};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
 
 /// The sshx server CLI interface.
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Specify port to listen on.
-    #[clap(long, default_value_t = 8051)]
-    port: u16,
+    /// Path to a TOML configuration file. Options on the command line take
+    /// precedence over the file, which is reloaded on SIGHUP.
+    #[clap(long, env = "SSHX_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Validate the configuration and print the effective settings, without
+    /// starting the server.
+    #[clap(long)]
+    check_config: bool,
 
-    /// Which IP address or network interface to listen on.
-    #[clap(long, value_parser, default_value = "0.0.0.0")]
-    listen: IpAddr,
+    /// Specify port to listen on [default: 8051].
+    #[clap(long)]
+    port: Option<u16>,
+
+    /// Which IP address or network interface to listen on, or
+    /// `unix:<path>` to listen on a Unix domain socket [default: 0.0.0.0].
+    ///
+    /// If sockets are passed by a service manager with `LISTEN_FDS`, the first
+    /// one is used instead.
+    #[clap(long)]
+    listen: Option<String>,
+
+    /// Permissions of the Unix domain socket, in octal like `660`.
+    #[clap(long)]
+    socket_mode: Option<String>,
+
+    /// Owner of the Unix domain socket, as `user` or `user:group`.
+    #[clap(long)]
+    socket_owner: Option<String>,
 
     /// Secret used for signing session tokens.
-    #[clap(long, env = "SSHX_SECRET")]
+    #[clap(long, env = "SSHX_SECRET", global = true)]
     secret: Option<String>,
 
     /// Override the origin URL returned by the Open() RPC.
     #[clap(long)]
     override_origin: Option<String>,
 
-    /// URL of the Redis server that stores session data.
-    #[clap(long, env = "SSHX_REDIS_URL")]
+    /// URL of the Redis server that stores session data, or a comma-separated
+    /// list of URLs for Sentinel or cluster mode.
+    #[clap(long, env = "SSHX_REDIS_URL", conflicts_with = "storage_path")]
     redis_url: Option<String>,
 
+    /// Discover the Redis master with this name through the Sentinels at
+    /// --redis-url.
+    #[clap(long, env = "SSHX_REDIS_SENTINEL_MASTER", requires = "redis_url")]
+    redis_sentinel_master: Option<String>,
+
+    /// Connect to a Redis Cluster, with --redis-url listing the seed nodes.
+    #[clap(long, requires = "redis_url", conflicts_with = "redis_sentinel_master")]
+    redis_cluster: bool,
+
+    /// ACL username for Redis, overriding any in --redis-url.
+    #[clap(long, env = "SSHX_REDIS_USERNAME", requires = "redis_url")]
+    redis_username: Option<String>,
+
+    /// Password for Redis, overriding any in --redis-url.
+    #[clap(long, env = "SSHX_REDIS_PASSWORD", requires = "redis_url")]
+    redis_password: Option<String>,
+
+    /// PEM bundle of CA certificates to verify `rediss://` servers with.
+    #[clap(long, requires = "redis_url")]
+    redis_ca: Option<PathBuf>,
+
+    /// PEM client certificate for Redis servers that require mutual TLS.
+    #[clap(long, requires_all = ["redis_url", "redis_key"])]
+    redis_cert: Option<PathBuf>,
+
+    /// PEM private key of the Redis client certificate.
+    #[clap(long, requires = "redis_cert")]
+    redis_key: Option<PathBuf>,
+
+    /// Path to a local database file that stores session data, so a single
+    /// server can restore sessions after restarting without Redis.
+    #[clap(long, env = "SSHX_STORAGE_PATH")]
+    storage_path: Option<PathBuf>,
+
     /// Hostname of this server, if running multiple servers.
     #[clap(long)]
     host: Option<String>,
+
+    /// Record the encrypted output of every session to files in a directory,
+    /// which can be replayed at /api/replay/{name}.
+    #[clap(long, env = "SSHX_RECORDING_DIR")]
+    recording_dir: Option<PathBuf>,
+
+    /// Keep older output of each shell in files in a directory, after it is
+    /// pruned from memory, so viewers can still scroll back to it.
+    #[clap(long, env = "SSHX_SCROLLBACK_DIR")]
+    scrollback_dir: Option<PathBuf>,
+
+    /// Save sessions to this directory on shutdown, and restore them on the
+    /// next start, so clients can reconnect across server upgrades.
+    #[clap(long, env = "SSHX_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+
+    /// Connect to other servers in the mesh over TLS, when proxying viewers.
+    #[clap(long)]
+    peer_tls: bool,
+
+    /// PEM bundle of CA certificates to verify other servers in the mesh with,
+    /// instead of the public web roots.
+    #[clap(long, requires = "peer_tls")]
+    peer_ca: Option<PathBuf>,
+
+    /// Secret shared by all servers in the mesh, used to authenticate
+    /// connections proxied between them.
+    #[clap(long, env = "SSHX_MESH_SECRET")]
+    mesh_secret: Option<String>,
+
+    /// Send and accept connections authenticated with the mesh secret over
+    /// plain HTTP, such as behind a proxy that terminates TLS.
+    #[clap(long)]
+    mesh_allow_plaintext: bool,
+
+    /// PEM certificate chain to serve HTTPS and gRPC over TLS with. It is
+    /// reloaded when the file changes, or on SIGHUP.
+    #[clap(long, env = "SSHX_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the TLS certificate.
+    #[clap(long, env = "SSHX_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serve TLS with a generated, self-signed certificate, for development.
+    #[clap(long, conflicts_with = "tls_cert")]
+    tls_self_signed: bool,
+
+    /// Serve Prometheus metrics at `/metrics` on this address, which should not
+    /// be publicly reachable.
+    #[clap(long, env = "SSHX_METRICS_LISTEN")]
+    metrics_listen: Option<SocketAddr>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
 }
 
-#[tokio::main]
-async fn start(args: Args) -> Result<()> {
-    let addr = SocketAddr::new(args.listen, args.port);
+/// Operator commands for a running server.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Move all sessions on a running server to a peer, so it can be taken
+    /// down without disconnecting clients.
+    Drain {
+        /// Endpoint of the server to drain.
+        #[clap(long, default_value = "http://127.0.0.1:8051")]
+        server: String,
+
+        /// Origin of the peer server that clients should reconnect to.
+        #[clap(long)]
+        to: String,
+    },
+}
+
+/// Address that the server listens on.
+#[derive(Clone, Debug)]
+enum ListenAddr {
+    /// An IP address, with the port from `--port`.
+    Ip(IpAddr),
+
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => s.parse().map(Self::Ip),
+        }
+    }
+}
+
+/// Where and how the server listens, from the effective configuration.
+struct Listen {
+    addr: ListenAddr,
+    port: u16,
+    socket_mode: Option<u32>,
+    socket_owner: Option<String>,
+}
+
+impl Listen {
+    fn from_config(config: &ConfigFile) -> Result<Self> {
+        let server = &config.server;
+        let addr = server.listen.as_deref().unwrap_or("0.0.0.0");
+        let socket_mode = server.socket_mode.as_deref().map(|mode| {
+            u32::from_str_radix(mode, 8).with_context(|| format!("invalid socket mode {mode:?}"))
+        });
+        Ok(Self {
+            addr: addr
+                .parse()
+                .with_context(|| format!("invalid listen address {addr:?}"))?,
+            port: server.port.unwrap_or(8051),
+            socket_mode: socket_mode.transpose()?,
+            socket_owner: server.socket_owner.clone(),
+        })
+    }
+}
+
+/// Load the configuration file, if any, and apply options from the command
+/// line on top of it.
+fn load_config(args: &Args) -> Result<ConfigFile> {
+    let mut config = match &args.config {
+        Some(path) => ConfigFile::load(path)?,
+        None => ConfigFile::default(),
+    };
+
+    fn set<T: Clone>(value: &mut Option<T>, arg: &Option<T>) {
+        if arg.is_some() {
+            value.clone_from(arg);
+        }
+    }
+    let server = &mut config.server;
+    set(&mut server.listen, &args.listen);
+    set(&mut server.port, &args.port);
+    set(&mut server.socket_mode, &args.socket_mode);
+    set(&mut server.socket_owner, &args.socket_owner);
+    set(&mut server.host, &args.host);
+    set(&mut server.secret, &args.secret);
+    set(&mut server.override_origin, &args.override_origin);
+
+    let storage = &mut config.storage;
+    set(&mut storage.redis_url, &args.redis_url);
+    set(
+        &mut storage.redis_sentinel_master,
+        &args.redis_sentinel_master,
+    );
+    storage.redis_cluster |= args.redis_cluster;
+    set(&mut storage.redis_username, &args.redis_username);
+    set(&mut storage.redis_password, &args.redis_password);
+    set(&mut storage.redis_ca, &args.redis_ca);
+    set(&mut storage.redis_cert, &args.redis_cert);
+    set(&mut storage.redis_key, &args.redis_key);
+    set(&mut storage.path, &args.storage_path);
+    set(&mut storage.state_dir, &args.state_dir);
+    set(&mut storage.recording_dir, &args.recording_dir);
+    set(&mut storage.scrollback_dir, &args.scrollback_dir);
+
+    let mesh = &mut config.mesh;
+    mesh.peer_tls |= args.peer_tls;
+    set(&mut mesh.peer_ca, &args.peer_ca);
+    set(&mut mesh.secret, &args.mesh_secret);
+    mesh.allow_plaintext |= args.mesh_allow_plaintext;
+
+    let tls = &mut config.tls;
+    set(&mut tls.cert, &args.tls_cert);
+    set(&mut tls.key, &args.tls_key);
+    tls.self_signed |= args.tls_self_signed;
+
+    set(&mut config.metrics.listen, &args.metrics_listen);
+    Ok(config)
+}
 
-    let mut options = ServerOptions::default();
-    options.secret = args.secret;
-    options.override_origin = args.override_origin;
-    options.redis_url = args.redis_url;
-    options.host = args.host;
+/// Validate the configuration and print the effective settings.
+fn check_config(args: &Args) -> Result<()> {
+    let config = load_config(args)?;
+    Listen::from_config(&config)?;
+    config.validate()?;
+    print!("{}", config.redacted().to_toml()?);
+    Ok(())
+}
+
+/// Reload the configuration on SIGHUP, applying what can change while running.
+fn reload_config(server: &Server, args: &Args, config: &mut ConfigFile) {
+    let new_config = match load_config(args) {
+        Ok(new_config) => new_config,
+        Err(err) => {
+            error!(?err, "failed to reload configuration");
+            return;
+        }
+    };
+    let sections = config.restart_required(&new_config);
+    if !sections.is_empty() {
+        warn!(
+            ?sections,
+            "some changes will only take effect after a restart"
+        );
+    }
+    match server.reload(&new_config.options()) {
+        Ok(()) => info!("reloaded configuration"),
+        Err(err) => error!(?err, "failed to reload configuration"),
+    }
+    *config = new_config;
+}
 
-    let server = Server::new(options)?;
+#[tokio::main]
+async fn start(args: Args, mut listenfd: ListenFd) -> Result<()> {
+    let mut config = load_config(&args)?;
+    let listen = Listen::from_config(&config)?;
+    let server = Server::new(config.options())?;
+    let metrics_addr = config.metrics.listen;
 
     let serve_task = async {
-        info!("server listening at {addr}");
-        server.bind(&addr).await
+        if listenfd.len() > 0 {
+            info!("server listening on a socket from the service manager");
+            return listen_activated(&server, &mut listenfd).await;
+        }
+        match &listen.addr {
+            ListenAddr::Ip(ip) => {
+                let addr = SocketAddr::new(*ip, listen.port);
+                info!("server listening at {addr}");
+                server.bind(&addr).await
+            }
+            ListenAddr::Unix(path) => {
+                let owner = listen.socket_owner.as_deref();
+                listen_unix(&server, path, listen.socket_mode, owner).await
+            }
+        }
+    };
+
+    let metrics_task = async {
+        let Some(addr) = metrics_addr else {
+            return Ok(());
+        };
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to bind metrics listener at {addr}"))?;
+        info!("serving metrics at http://{addr}/metrics");
+        server.listen_metrics(listener).await
     };
 
     let signals_task = async {
@@ -60,10 +345,14 @@ async fn start(args: Args) -> Result<()> {
             use tokio::signal::unix::{signal, SignalKind};
             let mut sigterm = signal(SignalKind::terminate())?;
             let mut sigint = signal(SignalKind::interrupt())?;
-            tokio::select! {
-                Some(()) = sigterm.recv() => {},
-                Some(()) = sigint.recv() => {},
-                else => return Ok::<(), anyhow::Error>(()),
+            let mut sighup = signal(SignalKind::hangup())?;
+            loop {
+                tokio::select! {
+                    Some(()) = sigterm.recv() => break,
+                    Some(()) = sigint.recv() => break,
+                    Some(()) = sighup.recv() => reload_config(&server, &args, &mut config),
+                    else => return Ok::<(), anyhow::Error>(()),
+                }
             }
         }
 
@@ -77,19 +366,144 @@ async fn start(args: Args) -> Result<()> {
         Ok(())
     };
 
-    tokio::try_join!(serve_task, signals_task)?;
+    tokio::try_join!(serve_task, metrics_task, signals_task)?;
+    Ok(())
+}
+
+/// Listen on the first socket passed by a service manager with `LISTEN_FDS`,
+/// as in systemd socket activation.
+async fn listen_activated(server: &Server, listenfd: &mut ListenFd) -> Result<()> {
+    if let Ok(Some(listener)) = listenfd.take_tcp_listener(0) {
+        listener.set_nonblocking(true)?;
+        return server.listen_tcp(TcpListener::from_std(listener)?).await;
+    }
+    #[cfg(unix)]
+    if let Ok(Some(listener)) = listenfd.take_unix_listener(0) {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+        return server.listen_unix(listener).await;
+    }
+    bail!("socket from the service manager is not a TCP or Unix stream listener")
+}
+
+/// Listen on a Unix domain socket, removing it when the server stops.
+#[cfg(unix)]
+async fn listen_unix(
+    server: &Server,
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<&str>,
+) -> Result<()> {
+    let listener = bind_unix(path, mode, owner)?;
+    info!("server listening at {}", path.display());
+    let result = server.listen_unix(listener).await;
+    _ = std::fs::remove_file(path);
+    result
+}
+
+#[cfg(not(unix))]
+async fn listen_unix(
+    _server: &Server,
+    _path: &Path,
+    _mode: Option<u32>,
+    _owner: Option<&str>,
+) -> Result<()> {
+    bail!("Unix domain sockets are not supported on this platform")
+}
+
+/// Bind a Unix domain socket, replacing a stale one from a previous server.
+#[cfg(unix)]
+fn bind_unix(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<&str>,
+) -> Result<tokio::net::UnixListener> {
+    use std::fs;
+    use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("{} is in use by another server", path.display());
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("failed to bind {}", path.display()))?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    if let Some(owner) = owner {
+        let (uid, gid) = lookup_owner(owner)?;
+        chown(path, uid, gid).with_context(|| format!("failed to chown {}", path.display()))?;
+    }
+    Ok(listener)
+}
+
+/// Look up the user and group IDs of an owner like `user:group`, where each
+/// part is a name or a numeric ID and may be omitted.
+#[cfg(unix)]
+fn lookup_owner(owner: &str) -> Result<(Option<u32>, Option<u32>)> {
+    use nix::unistd::{Group, User};
+
+    let (user, group) = owner.split_once(':').unwrap_or((owner, ""));
+    let uid = match user {
+        "" => None,
+        user => Some(match user.parse() {
+            Ok(uid) => uid,
+            Err(_) => User::from_name(user)?
+                .with_context(|| format!("unknown user {user}"))?
+                .uid
+                .as_raw(),
+        }),
+    };
+    let gid = match group {
+        "" => None,
+        group => Some(match group.parse() {
+            Ok(gid) => gid,
+            Err(_) => Group::from_name(group)?
+                .with_context(|| format!("unknown group {group}"))?
+                .gid
+                .as_raw(),
+        }),
+    };
+    Ok((uid, gid))
+}
+
+#[tokio::main]
+async fn drain(server: String, to: String, secret: Option<String>) -> Result<()> {
+    let secret = secret.context("the server secret is required to drain sessions")?;
+    let mut client = SshxServiceClient::connect(server).await?;
+    let req = DrainRequest {
+        token: drain_token(&secret, &to),
+        origin: to,
+    };
+    let resp = client.drain(req).await?.into_inner();
+    info!("asked {} sessions to move", resp.sessions);
+    if !resp.skipped.is_empty() {
+        warn!("could not move sessions: {}", resp.skipped.join(", "));
+    }
     Ok(())
 }
 
 fn main() -> ExitCode {
-    let args = Args::parse();
+    let mut args = Args::parse();
+
+    // Take sockets from the environment before starting any threads.
+    let listenfd = ListenFd::from_env();
 
     tracing_subscriber::fmt()
         .with_env_filter(std::env::var("RUST_LOG").unwrap_or("info".into()))
         .with_writer(std::io::stderr)
         .init();
 
-    match start(args) {
+    let result = match args.command.take() {
+        Some(Command::Drain { server, to }) => {
+            load_config(&args).and_then(|config| drain(server, to, config.server.secret))
+        }
+        None if args.check_config => check_config(&args),
+        None => start(args, listenfd),
+    };
+    match result {
         Ok(()) => ExitCode::SUCCESS,
         Err(err) => {
             error!("{err:?}");
@@ -0,0 +1,186 @@
+//! Prometheus metrics for monitoring a running server.
+//!
+//! These are served in the text exposition format by [`Server::listen_metrics`],
+//! which should be bound to an address that is not publicly reachable.
+//!
+//! [`Server::listen_metrics`]: crate::Server::listen_metrics
+
+use std::fmt;
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use prometheus_client::collector::Collector;
+use prometheus_client::encoding::{text, DescriptorEncoder, EncodeMetric};
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::gauge::{ConstGauge, Gauge};
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::metrics::MetricType;
+use prometheus_client::registry::Registry;
+
+use crate::session::Session;
+use crate::state::storage::SessionStore;
+
+/// Upper bounds of the buckets for the number of shells in each session.
+const SHELL_BUCKETS: [f64; 6] = [1.0, 2.0, 4.0, 8.0, 16.0, 32.0];
+
+/// Metrics that are updated as the server runs.
+///
+/// Gauges describing the open sessions are computed when metrics are scraped.
+pub struct Metrics {
+    registry: Registry,
+
+    /// Number of open gRPC `Channel` streams from command-line clients.
+    pub grpc_streams: Gauge,
+
+    /// Bytes of terminal output received from command-line clients.
+    pub data_bytes: Counter,
+
+    /// Times a WebSocket viewer fell behind the broadcast stream and was
+    /// resynchronized.
+    pub lagged_resyncs: Counter,
+
+    /// Messages of terminal output sent to WebSocket viewers.
+    pub chunk_messages: Counter,
+
+    /// Size in bytes of compressed snapshots and deltas synced to storage.
+    pub snapshot_bytes: Histogram,
+
+    /// Time in seconds to take and store each snapshot or delta.
+    pub snapshot_seconds: Histogram,
+
+    /// WebSocket connections proxied to the server that owns their session.
+    pub proxy_redirects: Counter,
+
+    /// Requests rejected because a client exceeded its rate limit.
+    pub rate_limited: Counter,
+
+    /// Bytes of scrollback pruned to keep within the server's budget.
+    pub scrollback_pruned_bytes: Counter,
+}
+
+impl Metrics {
+    /// Create the metrics for a server's sessions and storage.
+    pub fn new(store: Arc<DashMap<String, Arc<Session>>>, storage: &dyn SessionStore) -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("sshx"),
+            grpc_streams: Gauge::default(),
+            data_bytes: Counter::default(),
+            lagged_resyncs: Counter::default(),
+            chunk_messages: Counter::default(),
+            snapshot_bytes: Histogram::new(exponential_buckets(256.0, 4.0, 8)),
+            snapshot_seconds: Histogram::new(exponential_buckets(0.001, 4.0, 8)),
+            proxy_redirects: Counter::default(),
+            rate_limited: Counter::default(),
+            scrollback_pruned_bytes: Counter::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "grpc_streams",
+            "Open gRPC streams from command-line clients",
+            metrics.grpc_streams.clone(),
+        );
+        registry.register(
+            "data_bytes",
+            "Bytes of terminal output received from clients",
+            metrics.data_bytes.clone(),
+        );
+        registry.register(
+            "lagged_resyncs",
+            "WebSocket viewers resynchronized after falling behind the broadcast stream",
+            metrics.lagged_resyncs.clone(),
+        );
+        registry.register(
+            "chunk_messages",
+            "Messages of terminal output sent to WebSocket viewers",
+            metrics.chunk_messages.clone(),
+        );
+        registry.register(
+            "snapshot_bytes",
+            "Size of compressed snapshots and deltas synced to storage",
+            metrics.snapshot_bytes.clone(),
+        );
+        registry.register(
+            "snapshot_duration_seconds",
+            "Time to take and store each snapshot or delta",
+            metrics.snapshot_seconds.clone(),
+        );
+        registry.register(
+            "proxy_redirects",
+            "WebSocket connections proxied to the owner of their session",
+            metrics.proxy_redirects.clone(),
+        );
+        registry.register(
+            "rate_limited",
+            "Requests rejected because a client exceeded its rate limit",
+            metrics.rate_limited.clone(),
+        );
+        registry.register(
+            "scrollback_pruned_bytes",
+            "Bytes of scrollback pruned to keep within the server's budget",
+            metrics.scrollback_pruned_bytes.clone(),
+        );
+        registry.register_collector(Box::new(SessionCollector(store)));
+        storage.register_metrics(registry);
+        metrics
+    }
+
+    /// Encode all metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buf = String::new();
+        text::encode(&mut buf, &self.registry).expect("writing to a string cannot fail");
+        buf
+    }
+}
+
+/// Computes metrics about the sessions open on this server when scraped.
+struct SessionCollector(Arc<DashMap<String, Arc<Session>>>);
+
+impl fmt::Debug for SessionCollector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionCollector").finish_non_exhaustive()
+    }
+}
+
+impl Collector for SessionCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> fmt::Result {
+        let mut sessions = 0u64;
+        let mut users = 0u64;
+        let mut scrollback = 0u64;
+        let mut shells_sum = 0;
+        let mut buckets: Vec<(f64, u64)> = SHELL_BUCKETS.iter().map(|&b| (b, 0)).collect();
+        buckets.push((f64::MAX, 0));
+        for entry in self.0.iter() {
+            let session = entry.value();
+            let shells = session.shell_count();
+            sessions += 1;
+            users += session.list_users().len() as u64;
+            scrollback += session.scrollback().iter().map(|s| s.bytes).sum::<u64>();
+            shells_sum += shells;
+            if let Some(bucket) = buckets.iter_mut().find(|(b, _)| shells as f64 <= *b) {
+                bucket.1 += 1;
+            }
+        }
+
+        for (name, help, value) in [
+            ("sessions", "Sessions open on this server", sessions),
+            ("web_users", "Web users connected to sessions", users),
+            (
+                "scrollback_bytes",
+                "Bytes of terminal output stored in memory",
+                scrollback,
+            ),
+        ] {
+            let gauge = ConstGauge::new(value as i64);
+            gauge.encode(encoder.encode_descriptor(name, help, None, gauge.metric_type())?)?;
+        }
+
+        let mut metric = encoder.encode_descriptor(
+            "session_shells",
+            "Number of shells in each open session",
+            None,
+            MetricType::Histogram,
+        )?;
+        metric.encode_histogram::<()>(shells_sum as f64, sessions, &buckets, None)
+    }
+}
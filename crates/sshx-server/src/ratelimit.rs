@@ -0,0 +1,166 @@
+//! Per-client rate limits, to protect the server from abuse.
+//!
+//! Clients are identified by their IP address. IPv6 addresses are grouped by
+//! their /64 prefix, since a single host is usually given a whole prefix.
+//! Connections without an IP address, such as those over a Unix domain socket,
+//! are not limited per client.
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::config::Limits;
+
+/// Address of the client that made a request, stored in its extensions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ClientAddr(pub Option<IpAddr>);
+
+/// Kinds of requests that are rate limited for each client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    /// Creating a session with the `Open` RPC.
+    Open,
+
+    /// Connecting to a session with the `Channel` RPC.
+    Channel,
+
+    /// Connecting to a session over WebSocket.
+    WebSocket,
+}
+
+impl Action {
+    /// Returns the number of requests allowed each minute, or zero if
+    /// unlimited.
+    fn per_minute(self, limits: &Limits) -> u32 {
+        match self {
+            Action::Open => limits.open_per_minute,
+            Action::Channel => limits.channel_per_minute,
+            Action::WebSocket => limits.websocket_per_minute,
+        }
+    }
+}
+
+/// A token bucket, which holds up to one minute of requests.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Refill the bucket for the time elapsed since it was last updated.
+    fn refill(&mut self, per_minute: u32) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let capacity = per_minute as f64;
+        self.tokens = (self.tokens + elapsed * capacity / 60.0).min(capacity);
+        self.updated = now;
+    }
+}
+
+/// Failed authentication attempts from a client.
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    /// Time that the client must wait after its last failure, doubling with
+    /// each consecutive failure.
+    fn backoff(&self, max: Duration) -> Duration {
+        let exponent = self.count.saturating_sub(1).min(31);
+        Duration::from_secs(1 << exponent).min(max)
+    }
+}
+
+/// Tracks requests and failed authentication attempts from each client.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: DashMap<(IpAddr, Action), Bucket>,
+    failures: DashMap<IpAddr, Failures>,
+}
+
+impl RateLimiter {
+    /// Create a new, empty rate limiter.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a token for a request, returning `false` if the client has made
+    /// too many requests of this kind recently.
+    pub fn check(&self, client: ClientAddr, action: Action, limits: &Limits) -> bool {
+        let per_minute = action.per_minute(limits);
+        let Some(ip) = client_key(client) else {
+            return true;
+        };
+        if per_minute == 0 {
+            return true;
+        }
+        let mut bucket = self.buckets.entry((ip, action)).or_insert_with(|| Bucket {
+            tokens: per_minute as f64,
+            updated: Instant::now(),
+        });
+        bucket.refill(per_minute);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns how much longer the client must wait before trying to
+    /// authenticate again, if it has failed recently.
+    pub fn auth_backoff(&self, client: ClientAddr, limits: &Limits) -> Option<Duration> {
+        let failures = self.failures.get(&client_key(client)?)?;
+        let elapsed = failures.last.elapsed();
+        let backoff = failures.backoff(limits.auth_backoff_max);
+        (elapsed < backoff).then(|| backoff - elapsed)
+    }
+
+    /// Record a failed authentication attempt from a client.
+    pub fn auth_failed(&self, client: ClientAddr) {
+        if let Some(ip) = client_key(client) {
+            let mut failures = self.failures.entry(ip).or_insert(Failures {
+                count: 0,
+                last: Instant::now(),
+            });
+            failures.count = failures.count.saturating_add(1);
+            failures.last = Instant::now();
+        }
+    }
+
+    /// Forget the failed authentication attempts of a client.
+    pub fn auth_succeeded(&self, client: ClientAddr) {
+        if let Some(ip) = client_key(client) {
+            self.failures.remove(&ip);
+        }
+    }
+
+    /// Remove clients that are no longer limited, to free memory.
+    ///
+    /// Failed authentication attempts are forgotten once a client has not
+    /// failed for the maximum backoff time.
+    pub fn prune(&self, limits: &Limits) {
+        self.buckets.retain(|&(_, action), bucket| {
+            let per_minute = action.per_minute(limits);
+            bucket.refill(per_minute);
+            bucket.tokens < per_minute as f64
+        });
+        self.failures
+            .retain(|_, failures| failures.last.elapsed() < limits.auth_backoff_max);
+    }
+}
+
+/// Returns the key that a client is limited by.
+fn client_key(client: ClientAddr) -> Option<IpAddr> {
+    match client.0?.to_canonical() {
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !((1 << 64) - 1);
+            Some(IpAddr::V6(Ipv6Addr::from(prefix)))
+        }
+        ip => Some(ip),
+    }
+}
@@ -2,29 +2,35 @@
 
 use std::collections::HashMap;
 use std::ops::DerefMut;
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, RwLock, RwLockWriteGuard};
 use sshx_core::{
-    proto::{server_update::ServerMessage, SequenceNumbers},
+    proto::{server_update::ServerMessage, Compression, SequenceNumbers, TerminalSize},
     IdCounter, Sid, Uid,
 };
-use tokio::sync::{broadcast, watch, Notify};
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+use tokio::task;
 use tokio::time::Instant;
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, WatchStream};
 use tokio_stream::Stream;
 use tracing::{debug, warn};
 
-use crate::utils::Shutdown;
+use crate::config::Limits;
+use crate::utils::{unix_millis, Shutdown};
 use crate::web::protocol::{WsServer, WsUser, WsWinsize};
 
-mod snapshot;
+pub use self::recording::{Record, RecordReader};
+pub use self::snapshot::SyncPosition;
+use self::spill::{ShellSpill, SpillRead};
 
-/// Store a rolling buffer with at most this quantity of output, per shell.
-/// Reduced to 512 KiB for low-resource VPS environments.
-const SHELL_STORED_BYTES: u64 = 1 << 19; // 512 KiB (was 2 MiB)
+mod recording;
+mod snapshot;
+mod spill;
 
 /// Static metadata for this session.
 #[derive(Debug, Clone)]
@@ -52,6 +58,10 @@ pub struct Metadata {
 
     /// Operating system info.
     pub os_info: String,
+
+    /// Compression of terminal output, applied by the client before
+    /// encryption.
+    pub compression: Compression,
 }
 
 /// In-memory state for a single sshx session.
@@ -93,6 +103,21 @@ pub struct Session {
 
     /// Set when this session has been closed and removed.
     shutdown: Shutdown,
+
+    /// Sends records to be appended to the session recording, if enabled.
+    recorder: OnceLock<mpsc::Sender<Record>>,
+
+    /// Epoch of this server's lease on the session in storage, or zero.
+    epoch: AtomicU64,
+
+    /// Limits on stored output, shared with the server so they can be reloaded.
+    limits: Arc<RwLock<Limits>>,
+
+    /// Directory that output pruned from memory is spilled to, if enabled.
+    spill_dir: OnceLock<PathBuf>,
+
+    /// Wakes the task that writes pruned output to disk, if spilling.
+    spill_tx: OnceLock<mpsc::Sender<()>>,
 }
 
 /// Internal state for each shell.
@@ -115,11 +140,90 @@ struct State {
 
     /// Updated when any of the above fields change.
     notify: Arc<Notify>,
+
+    /// Number of clients currently subscribed to this shell's output.
+    viewers: usize,
+
+    /// When a client last stopped viewing this shell, if ever.
+    viewed: Option<Instant>,
+
+    /// Output pruned from memory and spilled to disk, if enabled.
+    spill: Option<ShellSpill>,
+
+    /// Number of chunks at the start of `data` that were pruned, but are
+    /// still being written to disk.
+    unspilled: usize,
+
+    /// Number of bytes in the chunks still being written to disk.
+    unspilled_bytes: u64,
+}
+
+impl State {
+    /// Returns the bytes of output stored in memory, not counting chunks that
+    /// are being written to disk.
+    fn stored_bytes(&self) -> u64 {
+        self.seqnum - self.byte_offset - self.unspilled_bytes
+    }
+
+    /// Returns the number and total size of the oldest chunks to prune, after
+    /// any being written to disk, so that at most `max_bytes` are stored.
+    fn prunable(&self, max_bytes: u64) -> (usize, u64) {
+        let mut stored_bytes = self.stored_bytes();
+        let mut pruned_bytes = 0;
+        let mut count = 0;
+        for chunk in &self.data[self.unspilled..] {
+            if stored_bytes <= max_bytes {
+                break;
+            }
+            stored_bytes -= chunk.len() as u64;
+            pruned_bytes += chunk.len() as u64;
+            count += 1;
+        }
+        (count, pruned_bytes)
+    }
+
+    /// Remove the oldest chunks from memory, with their total size.
+    fn remove_chunks(&mut self, count: usize, bytes: u64) {
+        self.chunk_offset += count as u64;
+        self.byte_offset += bytes;
+        self.data.drain(..count);
+    }
+
+    /// Returns when this shell was last viewed, for pruning the least recently
+    /// viewed shells first. Closed shells are never viewed again.
+    fn last_viewed(&self) -> Option<Instant> {
+        if self.closed {
+            None
+        } else if self.viewers > 0 {
+            Some(Instant::now())
+        } else {
+            self.viewed
+        }
+    }
+}
+
+/// Scrollback stored for a shell, used to enforce a global memory budget.
+#[derive(Clone, Copy, Debug)]
+pub struct Scrollback {
+    /// ID of the shell.
+    pub id: Sid,
+
+    /// Bytes of output stored in memory.
+    pub bytes: u64,
+
+    /// When the shell was last viewed, or `None` if it has not been viewed
+    /// since it was restored, or is closed.
+    pub last_viewed: Option<Instant>,
 }
 
 impl Session {
     /// Construct a new session.
     pub fn new(metadata: Metadata) -> Self {
+        Self::with_limits(metadata, Default::default())
+    }
+
+    /// Construct a new session, with limits that may change while it runs.
+    pub fn with_limits(metadata: Metadata, limits: Arc<RwLock<Limits>>) -> Self {
         let now = Instant::now();
         let (update_tx, update_rx) = async_channel::bounded(256);
         Session {
@@ -134,6 +238,11 @@ impl Session {
             update_rx,
             sync_notify: Notify::new(),
             shutdown: Shutdown::new(),
+            recorder: OnceLock::new(),
+            epoch: AtomicU64::new(0),
+            limits,
+            spill_dir: OnceLock::new(),
+            spill_tx: OnceLock::new(),
         }
     }
 
@@ -159,6 +268,16 @@ impl Session {
         SequenceNumbers { map }
     }
 
+    /// Returns the ordered list of open shells and their sizes.
+    pub fn shells(&self) -> Vec<(Sid, WsWinsize)> {
+        self.source.borrow().clone()
+    }
+
+    /// Returns the number of open shells.
+    pub fn shell_count(&self) -> usize {
+        self.source.borrow().len()
+    }
+
     /// Receive a notification on broadcasted message events.
     pub fn subscribe_broadcast(
         &self,
@@ -178,7 +297,23 @@ impl Session {
         mut chunknum: u64,
     ) -> impl Stream<Item = (u64, Vec<Bytes>)> + '_ {
         async_stream::stream! {
+            let _viewer = self.add_viewer(id);
             while !self.shutdown.is_terminated() {
+                // Older chunks are read from disk, if they were spilled there.
+                if let Some(spilled) = self.locate_spilled(id, chunknum) {
+                    let (seqnum, end_chunk) = (spilled.seqnum, spilled.end_chunk);
+                    let result = match task::spawn_blocking(move || spilled.read()).await {
+                        Ok(result) => result,
+                        Err(err) => Err(err.into()),
+                    };
+                    match result {
+                        Ok(chunks) => yield (seqnum, chunks),
+                        Err(err) => warn!(?err, %id, "failed to read spilled output"),
+                    }
+                    chunknum = end_chunk;
+                    continue;
+                }
+
                 // We absolutely cannot hold `shells` across an await point,
                 // since that would cause deadlocks.
                 let (seqnum, chunks, notified) = {
@@ -212,33 +347,91 @@ impl Session {
         }
     }
 
+    /// Locate chunks of a shell that were spilled to disk, if `chunknum` is
+    /// before the output stored in memory.
+    fn locate_spilled(&self, id: Sid, chunknum: u64) -> Option<SpillRead> {
+        let shells = self.shells.read();
+        let shell = shells.get(&id)?;
+        if chunknum >= shell.chunk_offset {
+            return None;
+        }
+        shell.spill.as_ref()?.locate(chunknum)
+    }
+
+    /// Prune the output of a shell to at most `max_bytes` in memory, returning
+    /// the bytes freed.
+    ///
+    /// If spilling is enabled, the pruned chunks stay readable in memory until
+    /// they have been written to disk by a separate task, so that disk IO is
+    /// never done while holding the lock on `shells`.
+    fn prune_shell(&self, shell: &mut State, max_bytes: u64) -> u64 {
+        let (count, bytes) = shell.prunable(max_bytes);
+        if count > 0 {
+            if let Some(spill_tx) = self.spill_tx.get() {
+                shell.unspilled += count;
+                shell.unspilled_bytes += bytes;
+                spill_tx.try_send(()).ok(); // The task may already be woken.
+            } else {
+                shell.remove_chunks(count, bytes);
+            }
+        }
+        bytes
+    }
+
+    /// Count a client as viewing a shell, until the returned guard is dropped.
+    fn add_viewer(&self, id: Sid) -> impl Drop + '_ {
+        struct ViewerGuard<'a>(&'a Session, Sid);
+        impl Drop for ViewerGuard<'_> {
+            fn drop(&mut self) {
+                if let Some(shell) = self.0.shells.write().get_mut(&self.1) {
+                    shell.viewers = shell.viewers.saturating_sub(1);
+                    shell.viewed = Some(Instant::now());
+                }
+            }
+        }
+
+        if let Some(shell) = self.shells.write().get_mut(&id) {
+            shell.viewers += 1;
+        }
+        ViewerGuard(self, id)
+    }
+
     /// Add a new shell to the session.
     pub fn add_shell(&self, id: Sid, center: (i32, i32)) -> Result<()> {
+        let winsize = WsWinsize {
+            x: center.0,
+            y: center.1,
+            ..Default::default()
+        };
+        self.restore_shell(id, winsize)
+    }
+
+    /// Add a shell that is still running on a reconnecting client, with the
+    /// window it last had.
+    pub fn restore_shell(&self, id: Sid, winsize: WsWinsize) -> Result<()> {
         use std::collections::hash_map::Entry::*;
         let _guard = match self.shells.write().entry(id) {
             Occupied(_) => bail!("shell already exists with id={id}"),
-            Vacant(v) => v.insert(State::default()),
-        };
-        self.source.send_modify(|source| {
-            let winsize = WsWinsize {
-                x: center.0,
-                y: center.1,
+            Vacant(v) => v.insert(State {
+                viewed: Some(Instant::now()),
                 ..Default::default()
-            };
-            source.push((id, winsize));
-        });
+            }),
+        };
+        self.update_source(|source| source.push((id, winsize)));
         self.sync_now();
         Ok(())
     }
 
     /// Send a resize message to the client for a specific shell.
-    pub async fn send_resize(&self, id: Sid, rows: u32, cols: u32) -> Result<()> {
-        use sshx_core::proto::TerminalSize;
-        self.update_tx.send(ServerMessage::Resize(TerminalSize { 
-            id: id.0, 
-            rows, 
-            cols 
-        })).await?;
+    pub async fn send_resize(&self, id: Sid, winsize: WsWinsize) -> Result<()> {
+        let msg = ServerMessage::Resize(TerminalSize {
+            id: id.0,
+            rows: winsize.rows.into(),
+            cols: winsize.cols.into(),
+            x: winsize.x,
+            y: winsize.y,
+        });
+        self.update_tx.send(msg).await?;
         Ok(())
     }
 
@@ -252,13 +445,19 @@ impl Session {
             Some(_) => return Ok(()),
             None => bail!("cannot close shell with id={id}, does not exist"),
         }
-        self.source.send_modify(|source| {
+        self.update_source(|source| {
             source.retain(|&(x, _)| x != id);
         });
         self.sync_now();
         Ok(())
     }
 
+    /// Modify the list of open shells, recording the change if enabled.
+    fn update_source(&self, f: impl FnOnce(&mut Vec<(Sid, WsWinsize)>)) {
+        self.source.send_modify(f);
+        self.record(|| Record::Shells(unix_millis(), self.source.borrow().clone()));
+    }
+
     fn get_shell_mut(&self, id: Sid) -> Result<impl DerefMut<Target = State> + '_> {
         let shells = self.shells.write();
         match shells.get(&id) {
@@ -273,7 +472,7 @@ impl Session {
     /// Change the size of a terminal, notifying clients if necessary.
     pub fn move_shell(&self, id: Sid, winsize: Option<WsWinsize>) -> Result<()> {
         let _guard = self.get_shell_mut(id)?; // Ensures mutual exclusion.
-        self.source.send_modify(|source| {
+        self.update_source(|source| {
             if let Some(idx) = source.iter().position(|&(sid, _)| sid == id) {
                 let (_, oldsize) = source.remove(idx);
                 source.push((id, winsize.unwrap_or(oldsize)));
@@ -288,24 +487,22 @@ impl Session {
 
         if seq <= shell.seqnum && seq + data.len() as u64 > shell.seqnum {
             let start = shell.seqnum - seq;
+            if start > 0 && self.metadata.compression != Compression::None {
+                // Compressed chunks cannot be split, but clients only send
+                // them again from the start.
+                bail!("compressed data overlaps existing output");
+            }
             let segment = data.slice(start as usize..);
             debug!(%id, bytes = segment.len(), "adding data to shell");
+            self.record(|| {
+                Record::Data(unix_millis(), id, shell.seqnum, segment.clone())
+            });
             shell.seqnum += segment.len() as u64;
             shell.data.push(segment);
 
             // Prune old chunks if we've exceeded the maximum stored bytes.
-            let mut stored_bytes = shell.seqnum - shell.byte_offset;
-            if stored_bytes > SHELL_STORED_BYTES {
-                let mut offset = 0;
-                while offset < shell.data.len() && stored_bytes > SHELL_STORED_BYTES {
-                    let bytes = shell.data[offset].len() as u64;
-                    stored_bytes -= bytes;
-                    shell.chunk_offset += 1;
-                    shell.byte_offset += bytes;
-                    offset += 1;
-                }
-                shell.data.drain(..offset);
-            }
+            let max_bytes = self.limits.read().shell_stored_bytes;
+            self.prune_shell(&mut shell, max_bytes);
 
             shell.notify.notify_waiters();
         }
@@ -313,6 +510,31 @@ impl Session {
         Ok(())
     }
 
+    /// Returns the scrollback stored in memory for each shell.
+    pub fn scrollback(&self) -> Vec<Scrollback> {
+        self.shells
+            .read()
+            .iter()
+            .map(|(&id, shell)| Scrollback {
+                id,
+                bytes: shell.stored_bytes(),
+                last_viewed: shell.last_viewed(),
+            })
+            .collect()
+    }
+
+    /// Prune the scrollback of a shell to at most `max_bytes`, returning the
+    /// number of bytes freed.
+    ///
+    /// Clients that later subscribe from an earlier chunk receive only the
+    /// remaining output, unless it was spilled to disk.
+    pub fn prune_scrollback(&self, id: Sid, max_bytes: u64) -> u64 {
+        match self.shells.write().get_mut(&id) {
+            Some(shell) => self.prune_shell(shell, max_bytes),
+            None => 0,
+        }
+    }
+
     /// List all the users in the session.
     pub fn list_users(&self) -> Vec<(Uid, WsUser)> {
         self.users
@@ -439,6 +661,19 @@ impl Session {
         self.sync_notify.notified().await
     }
 
+    /// Returns the epoch of the storage lease held for this session.
+    ///
+    /// This is stored in snapshots, so a restored session never acquires a
+    /// lease older than the one it was saved under.
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Relaxed)
+    }
+
+    /// Set the epoch of the storage lease held for this session.
+    pub fn set_epoch(&self, epoch: u64) {
+        self.epoch.store(epoch, Ordering::Relaxed);
+    }
+
     /// Send a termination signal to exit this session.
     pub fn shutdown(&self) {
         self.shutdown.shutdown()
@@ -449,3 +684,13 @@ impl Session {
         self.shutdown.wait().await
     }
 }
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(dir) = self.spill_dir.get() {
+            if let Err(err) = std::fs::remove_dir_all(dir) {
+                warn!(?err, "failed to remove spilled output");
+            }
+        }
+    }
+}
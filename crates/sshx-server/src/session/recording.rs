@@ -0,0 +1,161 @@
+//! Opt-in recording of encrypted session output, for later playback.
+//!
+//! A recording is a file of consecutive CBOR-encoded [`Record`] values. The
+//! terminal data in it is stored exactly as received from the client, still
+//! encrypted, so the server can replay it without being able to read it.
+
+use std::fs::OpenOptions;
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use sshx_core::{proto::Compression, Sid};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::sync::mpsc;
+use tracing::{error, warn};
+
+use super::Session;
+use crate::utils::unix_millis;
+use crate::web::protocol::WsWinsize;
+
+/// Number of records buffered in memory before they are written to the file.
+const RECORD_BUFFER_SIZE: usize = 1024;
+
+/// Number of bytes read from a recording file at a time.
+const READ_BUFFER_SIZE: usize = 1 << 16;
+
+/// A single entry in a session recording file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum Record {
+    /// Written once, at the start of a new recording file.
+    Header {
+        /// Name of the session, as shown in its title.
+        name: String,
+        /// Used to validate that viewers have the correct encryption key.
+        encrypted_zeros: Bytes,
+        /// Whether terminal output is compressed before encryption.
+        #[serde(default)]
+        compressed: bool,
+    },
+    /// Ordered list of open shells and their sizes, at a Unix time in ms.
+    Shells(u64, Vec<(Sid, WsWinsize)>),
+    /// Encrypted output of a shell at a sequence number, at a Unix time in ms.
+    Data(u64, Sid, u64, Bytes),
+}
+
+impl Record {
+    /// Returns the Unix time in ms at which this record was written, if any.
+    pub fn time(&self) -> Option<u64> {
+        match self {
+            Record::Header { .. } => None,
+            Record::Shells(time, _) | Record::Data(time, ..) => Some(*time),
+        }
+    }
+}
+
+/// Reads the records in a recording file one at a time, so that long
+/// recordings are never loaded into memory at once.
+pub struct RecordReader {
+    file: tokio::fs::File,
+    buf: BytesMut,
+    eof: bool,
+}
+
+impl RecordReader {
+    /// Open a recording file for reading.
+    pub async fn open(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            file: tokio::fs::File::open(path).await?,
+            buf: BytesMut::new(),
+            eof: false,
+        })
+    }
+
+    /// Read the next record, or `None` at the end of the file.
+    ///
+    /// A truncated record at the end of the file is ignored, since the server
+    /// may have stopped in the middle of writing it.
+    pub async fn next(&mut self) -> Result<Option<Record>> {
+        loop {
+            let mut data = &self.buf[..];
+            match ciborium::de::from_reader(&mut data) {
+                Ok(record) => {
+                    self.buf.advance(self.buf.len() - data.len());
+                    return Ok(Some(record));
+                }
+                Err(ciborium::de::Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {}
+                Err(err) => return Err(err).context("failed to parse recording"),
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            self.buf.reserve(READ_BUFFER_SIZE);
+            self.eof = self.file.read_buf(&mut self.buf).await? == 0;
+        }
+    }
+}
+
+impl Session {
+    /// Start appending encrypted output and shell changes to a recording file.
+    ///
+    /// If the file already exists, for example when a session is restored on
+    /// this server, then new records are appended to the end of it.
+    pub fn start_recording(&self, path: &Path) -> Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            let header = Record::Header {
+                name: self.metadata.name.clone(),
+                encrypted_zeros: self.metadata.encrypted_zeros.clone(),
+                compressed: self.metadata.compression != Compression::None,
+            };
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(&header, &mut buf)?;
+            file.write_all(&buf)?;
+        }
+
+        let (tx, rx) = mpsc::channel(RECORD_BUFFER_SIZE);
+        tx.try_send(Record::Shells(unix_millis(), self.source.borrow().clone()))?;
+        if self.recorder.set(tx).is_err() {
+            bail!("session is already being recorded");
+        }
+
+        let file = tokio::fs::File::from_std(file);
+        let path = path.to_owned();
+        tokio::spawn(async move {
+            if let Err(err) = write_records(file, rx).await {
+                error!(?err, path = %path.display(), "failed to write session recording");
+            }
+        });
+        Ok(())
+    }
+
+    /// Append a record to this session's recording, if enabled.
+    pub(super) fn record(&self, f: impl FnOnce() -> Record) {
+        if let Some(recorder) = self.recorder.get() {
+            if let Err(mpsc::error::TrySendError::Full(_)) = recorder.try_send(f()) {
+                // Output is encrypted with its sequence number, so replays
+                // can still show the rest of the recording.
+                warn!(name = %self.metadata.name, "recording fell behind, dropping a record");
+            }
+        }
+    }
+}
+
+/// Write records to a file until the sending session is dropped.
+async fn write_records(file: tokio::fs::File, mut rx: mpsc::Receiver<Record>) -> Result<()> {
+    let mut writer = BufWriter::new(file);
+    let mut buf = Vec::new();
+    while let Some(record) = rx.recv().await {
+        buf.clear();
+        ciborium::ser::into_writer(&record, &mut buf)?;
+        writer.write_all(&buf).await?;
+        if rx.is_empty() {
+            writer.flush().await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
@@ -1,80 +1,143 @@
 //! Snapshot and restore sessions from serialized state.
 
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 
 use anyhow::{ensure, Context, Result};
+use parking_lot::RwLock;
 use prost::Message;
 use sshx_core::{
-    proto::{SerializedSession, SerializedShell},
+    proto::{SerializedDelta, SerializedSession, SerializedShell},
     Sid, Uid,
 };
 
 use super::{Metadata, Session, State};
+use crate::config::Limits;
 use crate::web::protocol::WsWinsize;
 
-/// Persist at most this many bytes of output in storage, per shell.
-const SHELL_SNAPSHOT_BYTES: u64 = 1 << 15; // 32 KiB
-
 /// Reduced for low-resource VPS.
 const MAX_SNAPSHOT_SIZE: usize = 1 << 20; // 1 MiB (was 4 MiB)
 
+/// Position of each shell's data at the last sync to storage.
+///
+/// This is the number of chunks that have been synced, so a delta from
+/// [`Session::snapshot_delta`] only needs to contain the chunks after it.
+#[derive(Debug, Default, Clone)]
+pub struct SyncPosition(BTreeMap<Sid, u64>);
+
 impl Session {
     /// Snapshot the session, returning a compressed representation.
     pub fn snapshot(&self) -> Result<Vec<u8>> {
+        Ok(self.snapshot_position()?.0)
+    }
+
+    /// Snapshot the session, also returning the position it was taken at.
+    pub fn snapshot_position(&self) -> Result<(Vec<u8>, SyncPosition)> {
         let ids = self.counter.get_current_values();
-        let winsizes: BTreeMap<Sid, WsWinsize> = self.source.borrow().iter().cloned().collect();
+        let (shells, position) = self.serialize_shells(None);
         let message = SerializedSession {
             encrypted_zeros: self.metadata().encrypted_zeros.clone(),
-            shells: self
-                .shells
-                .read()
-                .iter()
-                .map(|(sid, shell)| {
-                    // Prune off data until its total length is at most `SHELL_SNAPSHOT_BYTES`.
-                    let mut prefix = 0;
-                    let mut chunk_offset = shell.chunk_offset;
-                    let mut byte_offset = shell.byte_offset;
-
-                    for i in 0..shell.data.len() {
-                        if shell.seqnum - byte_offset > SHELL_SNAPSHOT_BYTES {
-                            prefix += 1;
-                            chunk_offset += 1;
-                            byte_offset += shell.data[i].len() as u64;
-                        } else {
-                            break;
-                        }
-                    }
-
-                    let winsize = winsizes.get(sid).cloned().unwrap_or_default();
-                    let shell = SerializedShell {
-                        seqnum: shell.seqnum,
-                        data: shell.data[prefix..].to_vec(),
-                        chunk_offset,
-                        byte_offset,
-                        closed: shell.closed,
-                        winsize_x: winsize.x,
-                        winsize_y: winsize.y,
-                        winsize_rows: winsize.rows.into(),
-                        winsize_cols: winsize.cols.into(),
-                    };
-                    (sid.0, shell)
-                })
-                .collect(),
+            shells,
             next_sid: ids.0 .0,
             next_uid: ids.1 .0,
             name: self.metadata().name.clone(),
             write_password_hash: self.metadata().write_password_hash.clone().unwrap_or_default(),
+            epoch: self.epoch(),
+            compression: self.metadata().compression.into(),
         };
-        let data = message.encode_to_vec();
-        ensure!(data.len() < MAX_SNAPSHOT_SIZE, "snapshot too large");
-        Ok(zstd::bulk::compress(&data, 3)?)
+        Ok((compress(message)?, position))
+    }
+
+    /// Serialize the changes to the session since a previous sync position.
+    ///
+    /// The result is much smaller than a full snapshot, as it only contains
+    /// new chunks of data. Deltas are applied in order on top of a snapshot by
+    /// [`Session::restore_with_deltas`].
+    pub fn snapshot_delta(&self, since: &SyncPosition) -> Result<(Vec<u8>, SyncPosition)> {
+        let ids = self.counter.get_current_values();
+        let (shells, position) = self.serialize_shells(Some(since));
+        let message = SerializedDelta {
+            shells,
+            next_sid: ids.0 .0,
+            next_uid: ids.1 .0,
+        };
+        Ok((compress(message)?, position))
+    }
+
+    /// Serialize every shell, only including data after a position if given.
+    fn serialize_shells(
+        &self,
+        since: Option<&SyncPosition>,
+    ) -> (HashMap<u32, SerializedShell>, SyncPosition) {
+        let winsizes: BTreeMap<Sid, WsWinsize> = self.source.borrow().iter().cloned().collect();
+        let max_bytes = self.limits.read().shell_snapshot_bytes;
+        let mut position = SyncPosition::default();
+        let shells = self
+            .shells
+            .read()
+            .iter()
+            .map(|(sid, shell)| {
+                let current_chunks = shell.chunk_offset + shell.data.len() as u64;
+                position.0.insert(*sid, current_chunks);
+
+                // Skip data that was already synced.
+                let mut prefix = match since.and_then(|since| since.0.get(sid)) {
+                    Some(&synced) => synced.saturating_sub(shell.chunk_offset) as usize,
+                    None => 0,
+                };
+                prefix = prefix.min(shell.data.len());
+                let skipped: u64 = shell.data[..prefix].iter().map(|x| x.len() as u64).sum();
+                let mut byte_offset = shell.byte_offset + skipped;
+
+                // Prune off data until its total length is at most `max_bytes`.
+                while prefix < shell.data.len() && shell.seqnum - byte_offset > max_bytes {
+                    byte_offset += shell.data[prefix].len() as u64;
+                    prefix += 1;
+                }
+
+                let winsize = winsizes.get(sid).cloned().unwrap_or_default();
+                let shell = SerializedShell {
+                    seqnum: shell.seqnum,
+                    data: shell.data[prefix..].to_vec(),
+                    chunk_offset: shell.chunk_offset + prefix as u64,
+                    byte_offset,
+                    closed: shell.closed,
+                    winsize_x: winsize.x,
+                    winsize_y: winsize.y,
+                    winsize_rows: winsize.rows.into(),
+                    winsize_cols: winsize.cols.into(),
+                };
+                (sid.0, shell)
+            })
+            .collect();
+        (shells, position)
     }
 
     /// Restore the session from a previous compressed snapshot.
     pub fn restore(data: &[u8]) -> Result<Self> {
-        let data = zstd::bulk::decompress(data, MAX_SNAPSHOT_SIZE)?;
-        let message = SerializedSession::decode(&*data)?;
+        Self::restore_with_deltas(data, &[])
+    }
+
+    /// Restore the session from a snapshot and the deltas synced after it.
+    pub fn restore_with_deltas(data: &[u8], deltas: &[Vec<u8>]) -> Result<Self> {
+        Self::restore_with_limits(data, deltas, Default::default())
+    }
+
+    /// Restore the session from a snapshot and deltas, with limits that may
+    /// change while it runs.
+    pub fn restore_with_limits(
+        data: &[u8],
+        deltas: &[Vec<u8>],
+        limits: Arc<RwLock<Limits>>,
+    ) -> Result<Self> {
+        let max_bytes = limits.read().shell_snapshot_bytes;
+        let mut message = SerializedSession::decode(&*decompress(data)?)?;
+        for delta in deltas {
+            let delta = SerializedDelta::decode(&*decompress(delta)?)?;
+            apply_delta(&mut message, delta, max_bytes);
+        }
 
+        let compression = message.compression();
         let metadata = Metadata {
             encrypted_zeros: message.encrypted_zeros,
             name: message.name,
@@ -88,9 +151,10 @@ impl Session {
             cpu: String::from("Unknown"),
             memory_mb: 0,
             os_info: String::from("Unknown OS"),
+            compression,
         };
 
-        let session = Self::new(metadata);
+        let session = Self::with_limits(metadata, limits);
         let mut shells = session.shells.write();
         let mut winsizes = Vec::new();
         for (sid, shell) in message.shells {
@@ -110,11 +174,17 @@ impl Session {
                 byte_offset: shell.byte_offset,
                 closed: shell.closed,
                 notify: Default::default(),
+                viewers: 0,
+                viewed: None,
+                spill: None,
+                unspilled: 0,
+                unspilled_bytes: 0,
             };
             shells.insert(Sid(sid), shell);
         }
         drop(shells);
         session.source.send_replace(winsizes);
+        session.set_epoch(message.epoch);
         session
             .counter
             .set_current_values(Sid(message.next_sid), Uid(message.next_uid));
@@ -122,3 +192,54 @@ impl Session {
         Ok(session)
     }
 }
+
+fn compress(message: impl Message) -> Result<Vec<u8>> {
+    let data = message.encode_to_vec();
+    ensure!(data.len() < MAX_SNAPSHOT_SIZE, "snapshot too large");
+    Ok(zstd::bulk::compress(&data, 3)?)
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::bulk::decompress(data, MAX_SNAPSHOT_SIZE)?)
+}
+
+/// Apply the changes in a delta to a serialized session.
+///
+/// Output is pruned to at most `max_bytes` per shell, as in a full snapshot.
+fn apply_delta(message: &mut SerializedSession, delta: SerializedDelta, max_bytes: u64) {
+    message.next_sid = delta.next_sid;
+    message.next_uid = delta.next_uid;
+    for (sid, update) in delta.shells {
+        let Some(shell) = message.shells.get_mut(&sid) else {
+            message.shells.insert(sid, update);
+            continue;
+        };
+
+        let mut current_chunks = shell.chunk_offset + shell.data.len() as u64;
+        if update.chunk_offset > current_chunks {
+            // Some chunks were pruned before they could be synced, so the
+            // data cannot be contiguous. Start over from the delta instead.
+            shell.data.clear();
+            shell.chunk_offset = update.chunk_offset;
+            shell.byte_offset = update.byte_offset;
+            current_chunks = update.chunk_offset;
+        }
+        let overlap = (current_chunks - update.chunk_offset) as usize;
+        shell.data.extend(update.data.into_iter().skip(overlap));
+        shell.seqnum = update.seqnum;
+        shell.closed = update.closed;
+        shell.winsize_x = update.winsize_x;
+        shell.winsize_y = update.winsize_y;
+        shell.winsize_rows = update.winsize_rows;
+        shell.winsize_cols = update.winsize_cols;
+
+        // Prune off data until its total length is at most `max_bytes`.
+        let mut prefix = 0;
+        while prefix < shell.data.len() && shell.seqnum - shell.byte_offset > max_bytes {
+            shell.byte_offset += shell.data[prefix].len() as u64;
+            shell.chunk_offset += 1;
+            prefix += 1;
+        }
+        shell.data.drain(..prefix);
+    }
+}
@@ -0,0 +1,369 @@
+//! Opt-in storage of old terminal output on disk, after it is pruned from
+//! memory.
+//!
+//! Each shell has a directory of append-only segment files, holding chunks of
+//! output exactly as received from the client, still encrypted. The index of
+//! chunks in each segment is kept in memory, so files from a previous server
+//! process are never read.
+//!
+//! Pruned chunks are written by a task for each session, without holding the
+//! lock on its shells, and stay readable in memory until they are on disk.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use bytes::Bytes;
+use sshx_core::Sid;
+use tokio::sync::mpsc;
+use tokio::task;
+use tracing::warn;
+
+use super::Session;
+
+/// Size at which a segment file is closed and a new one is started.
+const SEGMENT_BYTES: u64 = 1 << 18;
+
+/// Maximum bytes of output read from disk for one message to a viewer.
+const READ_BATCH_BYTES: u64 = 1 << 19;
+
+impl Session {
+    /// Start spilling output that is pruned from memory to files in a
+    /// directory, which is deleted when the session is dropped.
+    pub fn start_spilling(self: &Arc<Self>, dir: &Path) -> Result<()> {
+        fs::create_dir_all(dir)?;
+        if self.spill_dir.set(dir.to_owned()).is_err() {
+            bail!("session is already spilling output to disk");
+        }
+
+        // Pruning only needs to wake the task, so one message is enough.
+        let (tx, mut rx) = mpsc::channel(1);
+        self.spill_tx.set(tx).unwrap();
+        let session = Arc::downgrade(self);
+        tokio::spawn(async move {
+            // The sender is dropped along with the session, which ends this.
+            while rx.recv().await.is_some() {
+                let Some(session) = session.upgrade() else {
+                    break;
+                };
+                session.write_spilled().await;
+            }
+        });
+        Ok(())
+    }
+
+    /// Write chunks pruned from memory to disk, then remove them from memory.
+    async fn write_spilled(&self) {
+        let ids: Vec<Sid> = (self.shells.read().iter())
+            .filter(|(_, shell)| shell.unspilled > 0)
+            .map(|(&id, _)| id)
+            .collect();
+        for id in ids {
+            let disk_bytes = self.limits.read().shell_disk_bytes;
+            let Some(write) = self.prepare_spill(id, disk_bytes) else {
+                continue;
+            };
+            let write = Arc::new(write);
+            let task = task::spawn_blocking({
+                let write = Arc::clone(&write);
+                move || write.write()
+            });
+            let result = match task.await {
+                Ok(result) => result,
+                Err(err) => Err(err.into()),
+            };
+
+            let expired = {
+                let mut shells = self.shells.write();
+                let Some(shell) = shells.get_mut(&id) else {
+                    continue;
+                };
+                let spill = shell.spill.as_mut().unwrap();
+                let expired = match result {
+                    Ok(()) => spill.commit(&write, disk_bytes),
+                    Err(err) => {
+                        warn!(?err, %id, "failed to spill output to disk");
+                        spill.abort(&write)
+                    }
+                };
+                // Readers now find these chunks on disk, if they were written.
+                let count = write.chunk_count();
+                let bytes = shell.data[..count].iter().map(|x| x.len() as u64).sum();
+                shell.remove_chunks(count, bytes);
+                shell.unspilled -= count;
+                shell.unspilled_bytes -= bytes;
+                expired
+            };
+            if !expired.is_empty() {
+                task::spawn_blocking(move || remove_files(&expired))
+                    .await
+                    .ok();
+            }
+        }
+    }
+
+    /// Take the chunks of a shell that are waiting to be written to disk.
+    ///
+    /// If the disk quota is zero, the chunks are removed from memory instead.
+    fn prepare_spill(&self, id: Sid, disk_bytes: u64) -> Option<SpillWrite> {
+        let mut shells = self.shells.write();
+        let shell = shells.get_mut(&id)?;
+        let count = shell.unspilled;
+        if disk_bytes == 0 {
+            let bytes = shell.unspilled_bytes;
+            shell.remove_chunks(count, bytes);
+            (shell.unspilled, shell.unspilled_bytes) = (0, 0);
+            return None;
+        }
+        let (chunk_offset, byte_offset) = (shell.chunk_offset, shell.byte_offset);
+        let chunks = shell.data[..count].to_vec();
+        let dir = self.spill_dir.get()?.join(id.0.to_string());
+        let spill = shell.spill.get_or_insert_with(|| ShellSpill::new(dir));
+        Some(spill.prepare(chunk_offset, byte_offset, chunks, disk_bytes))
+    }
+}
+
+/// Old output of a shell that has been spilled to disk.
+#[derive(Debug)]
+pub(super) struct ShellSpill {
+    dir: PathBuf,
+    segments: VecDeque<Segment>,
+    bytes: u64,
+}
+
+/// A single append-only file of consecutive chunks.
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    first_chunk: u64,
+    first_byte: u64,
+    lengths: Vec<u64>,
+    size: u64,
+}
+
+impl Segment {
+    fn end_chunk(&self) -> u64 {
+        self.first_chunk + self.lengths.len() as u64
+    }
+}
+
+/// Chunks to append to disk, prepared while holding the lock on a shell.
+#[derive(Debug)]
+pub(super) struct SpillWrite {
+    dir: PathBuf,
+    path: PathBuf,
+    new_segment: bool,
+    first_chunk: u64,
+    first_byte: u64,
+    chunks: Vec<Bytes>,
+    count: usize,
+    stale: Vec<PathBuf>,
+}
+
+/// Chunks to read from disk, located while holding the lock on a shell.
+#[derive(Debug)]
+pub(super) struct SpillRead {
+    /// Sequence number of the first byte read.
+    pub seqnum: u64,
+
+    /// Number of the chunk after the last one read.
+    pub end_chunk: u64,
+
+    /// Ranges of files to read, with the lengths of the chunks in each.
+    ranges: Vec<(PathBuf, u64, Vec<u64>)>,
+}
+
+impl ShellSpill {
+    /// Create an empty store that writes segments into a directory.
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            segments: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Prepare to append chunks pruned from memory, starting at a chunk number
+    /// and sequence number.
+    ///
+    /// The write is done by [`SpillWrite::write`] without holding the lock on
+    /// the shell, and the chunks are only visible to readers once it is passed
+    /// back to [`ShellSpill::commit`]. The oldest chunks are skipped if they
+    /// would not fit in `max_bytes` on disk anyway.
+    pub fn prepare(
+        &mut self,
+        mut chunk_offset: u64,
+        mut byte_offset: u64,
+        mut chunks: Vec<Bytes>,
+        max_bytes: u64,
+    ) -> SpillWrite {
+        let count = chunks.len();
+        let mut total: u64 = chunks.iter().map(|x| x.len() as u64).sum();
+        let mut skip = 0;
+        while skip < chunks.len() && total > max_bytes {
+            total -= chunks[skip].len() as u64;
+            byte_offset += chunks[skip].len() as u64;
+            skip += 1;
+        }
+        chunks.drain(..skip);
+        chunk_offset += skip as u64;
+
+        let mut stale = Vec::new();
+        if self.segments.back().map(|s| s.end_chunk()) != Some(chunk_offset) {
+            // Output is only ever pruned from the front, so this happens only
+            // after all segments were deleted to meet the quota.
+            stale = self.clear();
+        }
+        let segment = self.segments.back().filter(|s| s.size < SEGMENT_BYTES);
+        SpillWrite {
+            dir: self.dir.clone(),
+            path: match segment {
+                Some(segment) => segment.path.clone(),
+                None => self.dir.join(format!("{chunk_offset}.seg")),
+            },
+            new_segment: segment.is_none(),
+            first_chunk: chunk_offset,
+            first_byte: byte_offset,
+            chunks,
+            count,
+            stale,
+        }
+    }
+
+    /// Add chunks that were written to disk, then return the oldest segments
+    /// to delete to stay within `max_bytes` on disk.
+    pub fn commit(&mut self, write: &SpillWrite, max_bytes: u64) -> Vec<PathBuf> {
+        if write.new_segment {
+            self.segments.push_back(Segment {
+                path: write.path.clone(),
+                first_chunk: write.first_chunk,
+                first_byte: write.first_byte,
+                lengths: Vec::new(),
+                size: 0,
+            });
+        }
+        let segment = self.segments.back_mut().unwrap();
+        for chunk in &write.chunks {
+            segment.lengths.push(chunk.len() as u64);
+            segment.size += chunk.len() as u64;
+            self.bytes += chunk.len() as u64;
+        }
+
+        let mut expired = Vec::new();
+        while self.bytes > max_bytes {
+            let Some(segment) = self.segments.pop_front() else {
+                break;
+            };
+            self.bytes -= segment.size;
+            expired.push(segment.path);
+        }
+        expired
+    }
+
+    /// Drop all segments after a write failed, since the last one no longer
+    /// matches its file, and return the files to delete.
+    pub fn abort(&mut self, write: &SpillWrite) -> Vec<PathBuf> {
+        let mut expired = self.clear();
+        if write.new_segment {
+            expired.push(write.path.clone());
+        }
+        expired
+    }
+
+    /// Remove all segments, returning their files.
+    fn clear(&mut self) -> Vec<PathBuf> {
+        self.bytes = 0;
+        self.segments.drain(..).map(|s| s.path).collect()
+    }
+
+    /// Locate the chunks on disk starting at `chunknum`, or the oldest chunk
+    /// still stored if it is earlier.
+    pub fn locate(&self, chunknum: u64) -> Option<SpillRead> {
+        let start = self
+            .segments
+            .iter()
+            .position(|s| chunknum < s.end_chunk())?;
+        let mut read = SpillRead {
+            seqnum: 0,
+            end_chunk: 0,
+            ranges: Vec::new(),
+        };
+        let mut total = 0;
+        for segment in self.segments.iter().skip(start) {
+            if total >= READ_BATCH_BYTES {
+                break;
+            }
+            let skip = chunknum.saturating_sub(segment.first_chunk) as usize;
+            let skipped: u64 = segment.lengths[..skip].iter().sum();
+            if read.ranges.is_empty() {
+                read.seqnum = segment.first_byte + skipped;
+            }
+            let mut lengths = Vec::new();
+            for &len in &segment.lengths[skip..] {
+                if total > 0 && total + len > READ_BATCH_BYTES {
+                    break;
+                }
+                lengths.push(len);
+                total += len;
+            }
+            read.end_chunk = segment.first_chunk + (skip + lengths.len()) as u64;
+            read.ranges.push((segment.path.clone(), skipped, lengths));
+            if read.end_chunk < segment.end_chunk() {
+                break;
+            }
+        }
+        Some(read)
+    }
+}
+
+impl SpillWrite {
+    /// Returns the number of chunks pruned from memory, including any that
+    /// were skipped.
+    pub fn chunk_count(&self) -> usize {
+        self.count
+    }
+
+    /// Write the chunks to the end of their segment file.
+    pub fn write(&self) -> Result<()> {
+        remove_files(&self.stale);
+        if self.new_segment {
+            fs::create_dir_all(&self.dir)?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&self.chunks.concat())?;
+        Ok(())
+    }
+}
+
+/// Delete files of segments that are no longer needed, ignoring errors.
+pub(super) fn remove_files(paths: &[PathBuf]) {
+    for path in paths {
+        fs::remove_file(path).ok();
+    }
+}
+
+impl SpillRead {
+    /// Read the located chunks from disk.
+    ///
+    /// This fails if a segment was deleted to meet the quota after the chunks
+    /// were located.
+    pub fn read(&self) -> Result<Vec<Bytes>> {
+        let mut chunks = Vec::new();
+        for (path, offset, lengths) in &self.ranges {
+            let mut file = File::open(path)?;
+            file.seek(SeekFrom::Start(*offset))?;
+            for &len in lengths {
+                let mut buf = vec![0; len as usize];
+                file.read_exact(&mut buf)?;
+                chunks.push(Bytes::from(buf));
+            }
+        }
+        Ok(chunks)
+    }
+}
@@ -1,33 +1,51 @@
 //! Stateful components of the server, managing multiple sessions.
 
-use std::pin::pin;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use dashmap::DashMap;
 use hmac::{Hmac, Mac as _};
 use sha2::Sha256;
+use sshx_core::proto::{server_update::ServerMessage, Reconnect};
 use sshx_core::rand_alphanumeric;
 use tokio::time;
 use tokio_stream::StreamExt;
-use tracing::error;
+use tracing::{error, info, warn};
 
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock};
 use sysinfo::System;
 
-use self::mesh::StorageMesh;
-use crate::session::Session;
+use self::file::FileStore;
+use self::mesh::{RedisConfig, StorageMesh};
+use self::peer::PeerConfig;
+use self::storage::{LeaseLost, MemoryStore, SessionStore};
+use crate::config::Limits;
+use crate::metrics::Metrics;
+use crate::ratelimit::{Action, ClientAddr, RateLimiter};
+use crate::session::{Metadata, Session};
 use crate::ServerOptions;
 
+pub mod file;
 pub mod mesh;
+pub mod peer;
+pub mod storage;
 
-/// Timeout for a disconnected session to be evicted and closed.
-///
-/// If a session has no backend clients making connections in this interval,
-/// then its updated timestamp will be out-of-date, so we close it and remove it
-/// from the state to reduce memory usage.
-const DISCONNECTED_SESSION_EXPIRY: Duration = Duration::from_secs(300);
+/// Interval for checking the scrollback stored by all sessions against the
+/// budget in the server's limits.
+const SCROLLBACK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait in total for busy sessions to accept reconnect messages
+/// when draining the server.
+const DRAIN_SEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// File extension of session snapshots saved in the state directory.
+const SNAPSHOT_EXTENSION: &str = "snapshot";
+
+/// Extension of the directories of spilled output in the scrollback directory.
+const SCROLLBACK_EXTENSION: &str = "scrollback";
 
 /// Shared state object for global server logic.
 pub struct ServerState {
@@ -38,10 +56,38 @@ pub struct ServerState {
     override_origin: Option<String>,
 
     /// A concurrent map of session IDs to session objects.
-    store: DashMap<String, Arc<Session>>,
+    store: Arc<DashMap<String, Arc<Session>>>,
+
+    /// Storage and distributed communication provider for sessions.
+    storage: Arc<dyn SessionStore>,
+
+    /// Whether sessions are synced to storage, which is skipped for the
+    /// default in-memory store since no other server can read it.
+    sync_sessions: bool,
+
+    /// Settings for proxying connections to other servers in the mesh.
+    peer: PeerConfig,
+
+    /// Directory to write encrypted session recordings to, if enabled.
+    recording_dir: Option<PathBuf>,
 
-    /// Storage and distributed communication provider, if enabled.
-    mesh: Option<StorageMesh>,
+    /// Directory to save sessions to on shutdown and restore them from.
+    state_dir: Option<PathBuf>,
+
+    /// Directory to spill output pruned from memory to, if enabled.
+    scrollback_dir: Option<PathBuf>,
+
+    /// Limits on resources used by sessions, which can be reloaded.
+    limits: Arc<RwLock<Limits>>,
+
+    /// Password for the admin dashboard, which can be reloaded.
+    admin_password: RwLock<Option<String>>,
+
+    /// Metrics about sessions and storage, for monitoring.
+    metrics: Arc<Metrics>,
+
+    /// Requests and failed authentication attempts from each client.
+    rate_limiter: RateLimiter,
 
     /// System monitor for server metrics.
     pub system: Arc<Mutex<System>>,
@@ -50,16 +96,53 @@ pub struct ServerState {
 impl ServerState {
     /// Create an empty server state using the given secret.
     pub fn new(options: ServerOptions) -> Result<Self> {
-        let secret = options.secret.unwrap_or_else(|| rand_alphanumeric(22));
-        let mesh = match options.redis_url {
-            Some(url) => Some(StorageMesh::new(&url, options.host.as_deref())?),
-            None => None,
+        let storage: Arc<dyn SessionStore> = match (&options.redis_url, &options.storage_path) {
+            (Some(_), Some(_)) => bail!("cannot use both Redis and a storage file"),
+            (Some(_), None) => {
+                let config = RedisConfig::from_options(&options).unwrap();
+                Arc::new(StorageMesh::new(&config, options.host.as_deref())?)
+            }
+            (None, Some(path)) => Arc::new(FileStore::open(path)?),
+            (None, None) => return Self::build(options, Arc::new(MemoryStore::new()), false),
         };
+        Self::with_storage(options, storage)
+    }
+
+    /// Create an empty server state with a custom session store.
+    ///
+    /// The storage options in `options` are ignored. This is useful for sharing
+    /// one store between multiple servers in the same process.
+    pub fn with_storage(options: ServerOptions, storage: Arc<dyn SessionStore>) -> Result<Self> {
+        Self::build(options, storage, true)
+    }
+
+    fn build(
+        options: ServerOptions,
+        storage: Arc<dyn SessionStore>,
+        sync_sessions: bool,
+    ) -> Result<Self> {
+        options.limits.validate().context("invalid limits")?;
+        let peer = PeerConfig::from_options(&options)?;
+        let secret = options.secret.unwrap_or_else(|| rand_alphanumeric(22));
+        if let Some(dir) = &options.scrollback_dir {
+            clear_scrollback_dir(dir)?;
+        }
+        let store = Arc::new(DashMap::new());
+        let metrics = Arc::new(Metrics::new(Arc::clone(&store), &*storage));
         Ok(Self {
             mac: Hmac::new_from_slice(secret.as_bytes()).unwrap(),
             override_origin: options.override_origin,
-            store: DashMap::new(),
-            mesh,
+            store,
+            storage,
+            sync_sessions,
+            peer,
+            recording_dir: options.recording_dir,
+            state_dir: options.state_dir,
+            scrollback_dir: options.scrollback_dir,
+            limits: Arc::new(RwLock::new(options.limits)),
+            admin_password: RwLock::new(options.admin_password),
+            metrics,
+            rate_limiter: RateLimiter::new(),
             system: Arc::new(Mutex::new(System::new_all())),
         })
     }
@@ -74,6 +157,86 @@ impl ServerState {
         self.override_origin.clone()
     }
 
+    /// Returns the settings for proxying connections to peers.
+    pub fn peer(&self) -> &PeerConfig {
+        &self.peer
+    }
+
+    /// Returns the metrics for monitoring this server.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the current limits on resources used by sessions.
+    pub fn limits(&self) -> Limits {
+        *self.limits.read()
+    }
+
+    /// Returns the tracker for failed authentication attempts and requests
+    /// from each client.
+    pub fn rate_limiter(&self) -> &RateLimiter {
+        &self.rate_limiter
+    }
+
+    /// Take a token for a request from a client, returning `false` if it has
+    /// made too many requests of this kind recently.
+    pub fn check_rate(&self, client: ClientAddr, action: Action) -> bool {
+        let allowed = self.rate_limiter.check(client, action, &self.limits());
+        if !allowed {
+            warn!(?client, ?action, "rate limited request");
+            self.metrics.rate_limited.inc();
+        }
+        allowed
+    }
+
+    /// Returns whether the server has as many sessions as it allows.
+    pub fn at_session_limit(&self) -> bool {
+        let max = self.limits().max_sessions as usize;
+        max > 0 && self.store.len() >= max
+    }
+
+    /// Returns whether the server has as many shells as it allows, across
+    /// all of its sessions.
+    pub fn at_shell_limit(&self) -> bool {
+        let max = self.limits().max_shells as usize;
+        max > 0 && self.store.iter().map(|s| s.shell_count()).sum::<usize>() >= max
+    }
+
+    /// Returns the password for the admin dashboard, if configured.
+    pub fn admin_password(&self) -> Option<String> {
+        self.admin_password.read().clone()
+    }
+
+    /// Apply the settings in `options` that can change while running.
+    ///
+    /// New limits apply to existing sessions, except for the sync and ping
+    /// intervals, which are only used by new client connections.
+    pub fn reload(&self, options: &ServerOptions) -> Result<()> {
+        options.limits.validate().context("invalid limits")?;
+        *self.limits.write() = options.limits;
+        self.admin_password.write().clone_from(&options.admin_password);
+        Ok(())
+    }
+
+    /// Create a new session that uses the server's limits.
+    pub fn new_session(&self, metadata: Metadata) -> Session {
+        Session::with_limits(metadata, Arc::clone(&self.limits))
+    }
+
+    /// Returns the path of the recording file for a session, if enabled.
+    pub fn recording_path(&self, name: &str) -> Option<PathBuf> {
+        session_file(self.recording_dir.as_ref()?, name, "cbor")
+    }
+
+    /// Returns a new directory to spill a session's output to, if enabled.
+    ///
+    /// This is unique to each session object, since a session may be replaced
+    /// by a restored copy while the old one is still being dropped.
+    pub fn scrollback_path(&self, name: &str) -> Option<PathBuf> {
+        let extension = format!("{}.{SCROLLBACK_EXTENSION}", rand_alphanumeric(8));
+        session_file(self.scrollback_dir.as_ref()?, name, &extension)
+    }
+
     /// Lookup a local session by name.
     pub fn lookup(&self, name: &str) -> Option<Arc<Session>> {
         self.store.get(name).map(|s| s.clone())
@@ -81,14 +244,32 @@ impl ServerState {
 
     /// Insert a session into the local store.
     pub fn insert(&self, name: &str, session: Arc<Session>) {
-        if let Some(mesh) = &self.mesh {
+        if self.sync_sessions {
             let name = name.to_string();
             let session = session.clone();
-            let mesh = mesh.clone();
+            let storage = Arc::clone(&self.storage);
+            let store = Arc::clone(&self.store);
+            let metrics = Arc::clone(&self.metrics);
             tokio::spawn(async move {
-                mesh.background_sync(&name, session).await;
+                let result = storage.background_sync(&name, session.clone(), metrics);
+                if let Err(err) = result.await {
+                    // Another server owns the session now, so stop serving it.
+                    warn!(?err, "lost lease on session {name}");
+                    store.remove_if(&name, |_, s| Arc::ptr_eq(s, &session));
+                    session.shutdown();
+                }
             });
         }
+        if let Some(path) = self.recording_path(name) {
+            if let Err(err) = session.start_recording(&path) {
+                error!(?err, "failed to start recording session {name}");
+            }
+        }
+        if let Some(path) = self.scrollback_path(name) {
+            if let Err(err) = session.start_spilling(&path) {
+                error!(?err, "failed to spill output of session {name}");
+            }
+        }
         if let Some(prev_session) = self.store.insert(name.to_string(), session) {
             prev_session.shutdown();
         }
@@ -107,10 +288,72 @@ impl ServerState {
     /// Close a session permanently on this and other servers.
     pub async fn close_session(&self, name: &str) -> Result<()> {
         self.remove(name);
-        if let Some(mesh) = &self.mesh {
-            mesh.mark_closed(name).await?;
+        self.storage.mark_closed(name).await
+    }
+
+    /// Move every session on this server to a peer, before it is taken down.
+    ///
+    /// Each session is snapshotted to storage, then its client is asked to
+    /// reconnect to `origin`. Once the peer takes over a session, it is removed
+    /// here, and web viewers reconnecting to this server are proxied to the new
+    /// owner. Returns the number of sessions that were asked to move, and the
+    /// names of sessions that could not be moved.
+    pub async fn drain(&self, origin: &str) -> Result<(usize, Vec<String>)> {
+        // Transfers are published to the peer by its hostname, like in the mesh.
+        let peer_host = origin
+            .parse::<http::Uri>()
+            .ok()
+            .and_then(|uri| Some(uri.authority()?.to_string()))
+            .filter(|host| Some(host.as_str()) != self.storage.host());
+        let sessions: Vec<_> = self
+            .store
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        let deadline = time::Instant::now() + DRAIN_SEND_TIMEOUT;
+        let mut count = 0;
+        let mut skipped = Vec::new();
+        for (name, session) in sessions {
+            let snapshot = match session.snapshot() {
+                Ok(snapshot) => snapshot,
+                Err(err) => {
+                    error!(?err, "failed to snapshot session {name} before draining");
+                    skipped.push(name);
+                    continue;
+                }
+            };
+            match self.storage.save(&name, session.epoch(), snapshot).await {
+                Ok(()) => {}
+                Err(err) if err.is::<LeaseLost>() => {
+                    // Another server already owns this session.
+                    self.remove(&name);
+                    continue;
+                }
+                Err(err) => {
+                    error!(?err, "failed to save session {name} before draining");
+                    skipped.push(name);
+                    continue;
+                }
+            }
+            if let Some(host) = &peer_host {
+                // Drop any stale copy on the peer, so it restores this snapshot.
+                if let Err(err) = self.storage.notify_transfer(&name, host).await {
+                    warn!(?err, "failed to publish transfer of session {name}");
+                }
+            }
+            let msg = ServerMessage::Reconnect(Reconnect {
+                origin: origin.into(),
+            });
+            let sent = time::timeout_at(deadline, session.update_tx().send(msg));
+            if matches!(sent.await, Ok(Ok(()))) {
+                count += 1;
+            } else {
+                warn!("client did not accept reconnect, not draining session {name}");
+                skipped.push(name);
+            }
         }
-        Ok(())
+        info!(%origin, skipped = skipped.len(), "asked {count} sessions to reconnect");
+        Ok((count, skipped))
     }
 
     /// Connect to a session by name from the `sshx` client, which provides the
@@ -120,16 +363,14 @@ impl ServerState {
             return Ok(Some(session));
         }
 
-        if let Some(mesh) = &self.mesh {
-            let (owner, snapshot) = mesh.get_owner_snapshot(name).await?;
-            if let Some(snapshot) = snapshot {
-                let session = Arc::new(Session::restore(&snapshot)?);
-                self.insert(name, session.clone());
-                if let Some(owner) = owner {
-                    mesh.notify_transfer(name, &owner).await?;
-                }
-                return Ok(Some(session));
+        let (owner, snapshot) = self.storage.get_owner_snapshot(name).await?;
+        if let Some(snapshot) = snapshot {
+            let session = Arc::new(snapshot.restore_with_limits(Arc::clone(&self.limits))?);
+            self.insert(name, session.clone());
+            if let Some(owner) = owner {
+                self.storage.notify_transfer(name, &owner).await?;
             }
+            return Ok(Some(session));
         }
 
         Ok(None)
@@ -144,36 +385,31 @@ impl ServerState {
             return Ok(Ok(session));
         }
 
-        if let Some(mesh) = &self.mesh {
-            let mut owner = mesh.get_owner(name).await?;
-            if owner.is_some() && owner.as_deref() == mesh.host() {
-                // Do not redirect back to the same server.
-                owner = None;
-            }
-            return Ok(Err(owner));
+        let mut owner = self.storage.get_owner(name).await?;
+        if owner.is_some() && owner.as_deref() == self.storage.host() {
+            // Do not redirect back to the same server.
+            owner = None;
         }
-
-        Ok(Err(None))
+        Ok(Err(owner))
     }
 
     /// Listen for and remove sessions that are transferred away from this host.
     pub async fn listen_for_transfers(&self) {
-        if let Some(mesh) = &self.mesh {
-            let mut transfers = pin!(mesh.listen_for_transfers());
-            while let Some(name) = transfers.next().await {
-                self.remove(&name);
-            }
+        let mut transfers = self.storage.listen_for_transfers();
+        while let Some(name) = transfers.next().await {
+            self.remove(&name);
         }
     }
 
     /// Close all sessions that have been disconnected for too long.
     pub async fn close_old_sessions(&self) {
         loop {
-            time::sleep(DISCONNECTED_SESSION_EXPIRY / 5).await;
+            let expiry = self.limits().disconnected_session_expiry;
+            time::sleep(expiry / 5).await;
             let mut to_close = Vec::new();
-            for entry in &self.store {
+            for entry in self.store.iter() {
                 let session = entry.value();
-                if session.last_accessed().elapsed() > DISCONNECTED_SESSION_EXPIRY {
+                if session.last_accessed().elapsed() > expiry {
                     to_close.push(entry.key().clone());
                 }
             }
@@ -185,7 +421,69 @@ impl ServerState {
         }
     }
 
+    /// Returns the bytes of scrollback stored in memory across all sessions.
+    pub fn scrollback_bytes(&self) -> u64 {
+        self.store
+            .iter()
+            .flat_map(|entry| entry.value().scrollback())
+            .map(|shell| shell.bytes)
+            .sum()
+    }
+
+    /// Prune the least recently viewed shells while the scrollback stored
+    /// across all sessions is over budget, returning the bytes freed.
+    pub fn enforce_scrollback_budget(&self) -> u64 {
+        let limits = self.limits();
+        if limits.scrollback_budget_bytes == 0 {
+            return 0;
+        }
+        let mut shells: Vec<_> = self
+            .store
+            .iter()
+            .flat_map(|entry| {
+                let session = Arc::clone(entry.value());
+                let shells = session.scrollback();
+                shells
+                    .into_iter()
+                    .map(move |shell| (Arc::clone(&session), shell))
+            })
+            .collect();
+        let mut usage: u64 = shells.iter().map(|(_, shell)| shell.bytes).sum();
+        let mut freed = 0;
+        if usage > limits.scrollback_budget_bytes {
+            shells.sort_by_key(|(_, shell)| shell.last_viewed);
+            for (session, shell) in shells {
+                if usage <= limits.scrollback_budget_bytes {
+                    break;
+                }
+                let pruned = session.prune_scrollback(shell.id, limits.shell_floor_bytes);
+                usage = usage.saturating_sub(pruned);
+                freed += pruned;
+            }
+            self.metrics.scrollback_pruned_bytes.inc_by(freed);
+            info!(freed, usage, "pruned scrollback to meet budget");
+        }
+        freed
+    }
+
+    /// Periodically prune scrollback to keep within the server's budget.
+    pub async fn prune_scrollback(&self) {
+        loop {
+            time::sleep(SCROLLBACK_CHECK_INTERVAL).await;
+            self.enforce_scrollback_budget();
+        }
+    }
+
+    /// Periodically forget clients that are no longer rate limited.
+    pub async fn prune_rate_limits(&self) {
+        loop {
+            time::sleep(Duration::from_secs(60)).await;
+            self.rate_limiter.prune(&self.limits());
+        }
+    }
+
     /// List all active session IDs with full system information.
+    #[allow(clippy::type_complexity)]
     pub fn list_sessions(&self) -> Vec<(String, u64, Option<String>, String, String, String, u64, String)> {
         self.store
             .iter()
@@ -206,10 +504,119 @@ impl ServerState {
             .collect()
     }
 
+    /// Save a snapshot of every session to the state directory, if enabled.
+    ///
+    /// This is called on shutdown, so that a new server process can restore
+    /// the sessions with [`ServerState::restore_sessions`].
+    pub fn save_sessions(&self) -> Result<()> {
+        let Some(dir) = &self.state_dir else {
+            return Ok(());
+        };
+        fs::create_dir_all(dir).context("failed to create state directory")?;
+        let mut count = 0;
+        for entry in self.store.iter() {
+            let name = entry.key();
+            let Some(path) = session_file(dir, name, SNAPSHOT_EXTENSION) else {
+                warn!("not saving session with invalid name {name:?}");
+                continue;
+            };
+            let result = entry.value().snapshot().and_then(|snapshot| {
+                // Write to a temporary file first, so snapshots are never partial.
+                let tmp_path = path.with_extension("tmp");
+                fs::write(&tmp_path, snapshot)?;
+                fs::rename(&tmp_path, &path)?;
+                Ok(())
+            });
+            match result {
+                Ok(()) => count += 1,
+                Err(err) => error!(?err, "failed to save session {name}"),
+            }
+        }
+        info!(count, "saved sessions to {}", dir.display());
+        Ok(())
+    }
+
+    /// Restore sessions saved in the state directory by a previous server.
+    ///
+    /// Snapshot files are removed once they are restored, so that sessions
+    /// closed later are not restored again on the next start. Snapshots that
+    /// fail to restore are renamed with a `.failed` suffix instead, to be
+    /// inspected by hand.
+    pub fn restore_sessions(&self) -> Result<()> {
+        let Some(dir) = &self.state_dir else {
+            return Ok(());
+        };
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err).context("failed to read state directory"),
+        };
+        let mut count = 0;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != SNAPSHOT_EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            let result = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| {
+                    Session::restore_with_limits(&data, &[], Arc::clone(&self.limits))
+                });
+            match result {
+                Ok(session) => {
+                    self.insert(name, Arc::new(session));
+                    count += 1;
+                    if let Err(err) = fs::remove_file(&path) {
+                        error!(?err, "failed to remove snapshot of session {name}");
+                    }
+                }
+                Err(err) => {
+                    // Keep the snapshot around, so the session is not lost.
+                    error!(?err, "failed to restore session {name}");
+                    let failed = path.with_extension(format!("{SNAPSHOT_EXTENSION}.failed"));
+                    if let Err(err) = fs::rename(&path, &failed) {
+                        error!(?err, "failed to rename snapshot of session {name}");
+                    }
+                }
+            }
+        }
+        info!(count, "restored sessions from {}", dir.display());
+        Ok(())
+    }
+
     /// Send a graceful shutdown signal to every session.
     pub fn shutdown(&self) {
-        for entry in &self.store {
+        for entry in self.store.iter() {
             entry.value().shutdown();
         }
     }
 }
+
+/// Returns the path of a file for a session in a directory.
+///
+/// Session names are checked here since they can be chosen by clients when
+/// reconnecting, and they should not be able to escape the directory.
+fn session_file(dir: &Path, name: &str, extension: &str) -> Option<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some(dir.join(format!("{name}.{extension}")))
+}
+
+/// Remove output spilled by a previous server process, which can no longer be
+/// read since its index was only kept in memory.
+fn clear_scrollback_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == SCROLLBACK_EXTENSION) {
+            fs::remove_dir_all(&path)
+                .with_context(|| format!("failed to remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,240 @@
+//! Session storage in a local embedded database file.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{ensure, Result};
+use async_trait::async_trait;
+use futures_util::stream::{self, BoxStream};
+use redb::{Database, ReadableTable, Table, TableDefinition};
+use tokio::task;
+
+use super::storage::{LeaseLost, SessionStore, StoredSnapshot, STORAGE_EXPIRY};
+use crate::utils::unix_millis;
+
+/// Table of sessions by name, with the Unix time in ms that they were last
+/// updated, whether they are closed, and the epoch of their latest lease.
+const SESSIONS: TableDefinition<&str, (u64, bool, u64)> = TableDefinition::new("sessions");
+
+/// Table of the latest full snapshot of each session.
+const SNAPSHOTS: TableDefinition<&str, &[u8]> = TableDefinition::new("snapshots");
+
+/// Table of deltas appended after each snapshot, by session name and index.
+const DELTAS: TableDefinition<(&str, u64), &[u8]> = TableDefinition::new("deltas");
+
+/// Session store that persists snapshots to a database file on local disk.
+///
+/// This lets a single server restore its sessions after a restart, without
+/// running a separate Redis instance. The file can only be opened by one
+/// server at a time.
+#[derive(Clone)]
+pub struct FileStore {
+    db: Arc<Database>,
+}
+
+impl FileStore {
+    /// Open or create the database at a path, removing expired sessions.
+    pub fn open(path: &Path) -> Result<Self> {
+        let db = Database::create(path)?;
+        let txn = db.begin_write()?;
+        {
+            let mut sessions = txn.open_table(SESSIONS)?;
+            let mut snapshots = txn.open_table(SNAPSHOTS)?;
+            let mut deltas = txn.open_table(DELTAS)?;
+            let mut expired = Vec::new();
+            for entry in sessions.iter()? {
+                let (name, value) = entry?;
+                if is_expired(value.value().0) {
+                    expired.push(name.value().to_string());
+                }
+            }
+            for name in expired {
+                sessions.remove(name.as_str())?;
+                snapshots.remove(name.as_str())?;
+                clear_deltas(&mut deltas, &name)?;
+            }
+        }
+        txn.commit()?;
+        Ok(Self { db: Arc::new(db) })
+    }
+
+    /// Run a blocking database operation on a separate thread.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Database) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let db = Arc::clone(&self.db);
+        task::spawn_blocking(move || f(&db)).await?
+    }
+}
+
+fn is_expired(updated: u64) -> bool {
+    unix_millis().saturating_sub(updated) > STORAGE_EXPIRY.as_millis() as u64
+}
+
+/// Check if a session is stored and open, given its row in [`SESSIONS`].
+fn is_live(value: Option<(u64, bool, u64)>) -> bool {
+    matches!(value, Some((updated, closed, _)) if !closed && !is_expired(updated))
+}
+
+/// Returns the epoch of the latest lease on a session, given its row.
+///
+/// This is kept after the row expires or the session is closed, so that an
+/// older owner stays fenced off until the row is removed on the next start.
+fn current_epoch(value: Option<(u64, bool, u64)>) -> u64 {
+    value.map_or(0, |(_, _, epoch)| epoch)
+}
+
+/// Remove all of the deltas stored for a session.
+fn clear_deltas(deltas: &mut Table<(&str, u64), &[u8]>, name: &str) -> Result<()> {
+    let mut indices = Vec::new();
+    for entry in deltas.range((name, 0)..=(name, u64::MAX))? {
+        indices.push(entry?.0.value().1);
+    }
+    for index in indices {
+        deltas.remove((name, index))?;
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl SessionStore for FileStore {
+    fn host(&self) -> Option<&str> {
+        None
+    }
+
+    async fn get_owner(&self, _name: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    async fn get_owner_snapshot(
+        &self,
+        name: &str,
+    ) -> Result<(Option<String>, Option<StoredSnapshot>)> {
+        let name = name.to_string();
+        let snapshot = self
+            .blocking(move |db| {
+                let txn = db.begin_read()?;
+                let name = name.as_str();
+                let session = txn.open_table(SESSIONS)?.get(name)?.map(|v| v.value());
+                if !is_live(session) {
+                    return Ok(None);
+                }
+                let Some(snapshot) = txn.open_table(SNAPSHOTS)?.get(name)? else {
+                    return Ok(None);
+                };
+                let mut deltas = Vec::new();
+                for entry in txn
+                    .open_table(DELTAS)?
+                    .range((name, 0)..=(name, u64::MAX))?
+                {
+                    deltas.push(entry?.1.value().to_vec());
+                }
+                Ok(Some(StoredSnapshot {
+                    snapshot: snapshot.value().to_vec(),
+                    deltas,
+                }))
+            })
+            .await?;
+        Ok((None, snapshot))
+    }
+
+    async fn acquire_lease(&self, name: &str, after: u64) -> Result<u64> {
+        let name = name.to_string();
+        self.blocking(move |db| {
+            let txn = db.begin_write()?;
+            let epoch;
+            {
+                let name = name.as_str();
+                let mut sessions = txn.open_table(SESSIONS)?;
+                let session = sessions.get(name)?.map(|v| v.value());
+                epoch = current_epoch(session).max(after) + 1;
+                let closed = matches!(session, Some((_, true, _)));
+                sessions.insert(name, (unix_millis(), closed, epoch))?;
+            }
+            txn.commit()?;
+            Ok(epoch)
+        })
+        .await
+    }
+
+    async fn save(&self, name: &str, epoch: u64, snapshot: Vec<u8>) -> Result<()> {
+        let name = name.to_string();
+        self.blocking(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let name = name.as_str();
+                let mut sessions = txn.open_table(SESSIONS)?;
+                let session = sessions.get(name)?.map(|v| v.value());
+                ensure!(current_epoch(session) <= epoch, LeaseLost);
+                let closed = match session {
+                    Some((updated, closed, _)) => closed && !is_expired(updated),
+                    None => false,
+                };
+                if !closed {
+                    sessions.insert(name, (unix_millis(), false, epoch))?;
+                    txn.open_table(SNAPSHOTS)?.insert(name, &*snapshot)?;
+                    clear_deltas(&mut txn.open_table(DELTAS)?, name)?;
+                }
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn append_delta(&self, name: &str, epoch: u64, delta: Vec<u8>) -> Result<()> {
+        let name = name.to_string();
+        self.blocking(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let name = name.as_str();
+                let mut sessions = txn.open_table(SESSIONS)?;
+                let session = sessions.get(name)?.map(|v| v.value());
+                ensure!(current_epoch(session) <= epoch, LeaseLost);
+                ensure!(
+                    is_live(session),
+                    "cannot append delta to a missing snapshot"
+                );
+                sessions.insert(name, (unix_millis(), false, epoch))?;
+
+                let mut deltas = txn.open_table(DELTAS)?;
+                let index = match deltas.range((name, 0)..=(name, u64::MAX))?.next_back() {
+                    Some(entry) => entry?.0.value().1 + 1,
+                    None => 0,
+                };
+                deltas.insert((name, index), &*delta)?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn mark_closed(&self, name: &str) -> Result<()> {
+        let name = name.to_string();
+        self.blocking(move |db| {
+            let txn = db.begin_write()?;
+            {
+                let name = name.as_str();
+                let mut sessions = txn.open_table(SESSIONS)?;
+                let epoch = current_epoch(sessions.get(name)?.map(|v| v.value()));
+                sessions.insert(name, (unix_millis(), true, epoch))?;
+                txn.open_table(SNAPSHOTS)?.remove(name)?;
+                clear_deltas(&mut txn.open_table(DELTAS)?, name)?;
+            }
+            txn.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn notify_transfer(&self, _name: &str, _host: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn listen_for_transfers(&self) -> BoxStream<'_, String> {
+        // The database file is not shared with other servers.
+        Box::pin(stream::empty())
+    }
+}
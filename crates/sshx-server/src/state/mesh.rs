@@ -1,79 +1,492 @@
 //! Storage and distributed communication.
 
-use std::{pin::pin, sync::Arc, time::Duration};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::{pin::pin, time::Duration};
 
-use anyhow::Result;
-use redis::AsyncCommands;
+use anyhow::{ensure, Context, Result};
+use async_trait::async_trait;
+use deadpool::managed::{self, Metrics, RecycleError, RecycleResult};
+use futures_util::stream::BoxStream;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::registry::Registry;
+use redis::aio::{ConnectionLike, MultiplexedConnection, PubSub};
+use redis::cluster::{ClusterClient, ClusterClientBuilder};
+use redis::cluster_async::ClusterConnection;
+use redis::{
+    AsyncCommands, Client, ClientTlsConfig, Cmd, ConnectionAddr, ConnectionInfo, ErrorKind,
+    IntoConnectionInfo, Pipeline, RedisConnectionInfo, RedisError, RedisFuture, RedisResult,
+    TlsCertificates, Value,
+};
 use tokio::time;
-use tokio_stream::{Stream, StreamExt};
-use tracing::error;
+use tokio_stream::StreamExt;
+use tracing::{error, warn};
 
-use crate::session::Session;
-
-/// Interval for syncing the latest session state into persistent storage.
-const STORAGE_SYNC_INTERVAL: Duration = Duration::from_secs(20);
-
-/// Length of time a key lasts in Redis before it is expired.
-const STORAGE_EXPIRY: Duration = Duration::from_secs(300);
+use super::storage::{LeaseLost, SessionStore, StoredSnapshot, STORAGE_EXPIRY};
+use crate::ServerOptions;
 
 fn set_opts() -> redis::SetOptions {
     redis::SetOptions::default()
         .with_expiration(redis::SetExpiry::PX(STORAGE_EXPIRY.as_millis() as u64))
 }
 
+/// Increment the epoch of a session's lease past `ARGV[1]`, and set its owner.
+///
+/// Keys: epoch, owner. Args: minimum epoch, host, expiry in ms.
+const ACQUIRE_SCRIPT: &str = r#"
+local epoch = math.max(tonumber(redis.call('GET', KEYS[1]) or '0'), tonumber(ARGV[1])) + 1
+redis.call('SET', KEYS[1], epoch, 'PX', ARGV[3])
+if ARGV[2] ~= '' then
+    redis.call('SET', KEYS[2], ARGV[2], 'PX', ARGV[3])
+end
+return epoch
+"#;
+
+/// Replace the snapshot of a session, if no newer lease has been acquired.
+///
+/// Keys: epoch, owner, snapshot, deltas. Args: epoch, host, snapshot, expiry
+/// in ms. Returns 0 if the lease was lost.
+const SAVE_SCRIPT: &str = r#"
+if tonumber(redis.call('GET', KEYS[1]) or '0') > tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[4])
+if ARGV[2] ~= '' then
+    redis.call('SET', KEYS[2], ARGV[2], 'PX', ARGV[4])
+end
+redis.call('SET', KEYS[3], ARGV[3], 'PX', ARGV[4])
+redis.call('DEL', KEYS[4])
+return 1
+"#;
+
+/// Append a delta to the snapshot of a session, if no newer lease has been
+/// acquired.
+///
+/// Keys: epoch, owner, snapshot, deltas. Args: epoch, host, delta, expiry in
+/// ms. Returns 0 if the lease was lost, or -1 if there is no snapshot.
+const APPEND_SCRIPT: &str = r#"
+if tonumber(redis.call('GET', KEYS[1]) or '0') > tonumber(ARGV[1]) then
+    return 0
+end
+if redis.call('PEXPIRE', KEYS[3], ARGV[4]) == 0 then
+    return -1
+end
+redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[4])
+if ARGV[2] ~= '' then
+    redis.call('SET', KEYS[2], ARGV[2], 'PX', ARGV[4])
+end
+redis.call('RPUSH', KEYS[4], ARGV[3])
+redis.call('PEXPIRE', KEYS[4], ARGV[4])
+return 1
+"#;
+
+/// Settings for connecting to the Redis deployment that backs a mesh.
+#[derive(Clone, Debug, Default)]
+pub struct RedisConfig {
+    /// URLs of the Redis server, the seed nodes of a cluster, or the Sentinels
+    /// that monitor the master.
+    pub urls: Vec<String>,
+
+    /// Name of the master to discover through Sentinel, if enabled.
+    pub sentinel_master: Option<String>,
+
+    /// Connect to a Redis Cluster, with `urls` as the seed nodes.
+    pub cluster: bool,
+
+    /// ACL username, overriding any username in the URLs.
+    pub username: Option<String>,
+
+    /// Password, overriding any password in the URLs.
+    pub password: Option<String>,
+
+    /// PEM bundle of CA certificates to verify `rediss://` servers with.
+    pub tls_ca: Option<PathBuf>,
+
+    /// PEM client certificate, for servers that require mutual TLS.
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate.
+    pub tls_key: Option<PathBuf>,
+}
+
+impl RedisConfig {
+    /// Settings for a single Redis server at a URL.
+    pub fn from_url(url: &str) -> Self {
+        Self {
+            urls: vec![url.into()],
+            ..Default::default()
+        }
+    }
+
+    /// Settings from the server options, if Redis is configured.
+    pub fn from_options(options: &ServerOptions) -> Option<Self> {
+        let url = options.redis_url.as_ref()?;
+        Some(Self {
+            urls: url.split(',').map(|url| url.trim().to_string()).collect(),
+            sentinel_master: options.redis_sentinel_master.clone(),
+            cluster: options.redis_cluster,
+            username: options.redis_username.clone(),
+            password: options.redis_password.clone(),
+            tls_ca: options.redis_ca.clone(),
+            tls_cert: options.redis_cert.clone(),
+            tls_key: options.redis_key.clone(),
+        })
+    }
+
+    /// Load the TLS certificates to connect with, if any are configured.
+    fn certificates(&self) -> Result<Option<TlsCertificates>> {
+        let read = |path: &Path| {
+            fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+        };
+        let client_tls = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read(cert)?,
+                client_key: read(key)?,
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("Redis client certificate and key must be used together"),
+        };
+        let root_cert = self.tls_ca.as_deref().map(read).transpose()?;
+        if client_tls.is_none() && root_cert.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(TlsCertificates {
+            client_tls,
+            root_cert,
+        }))
+    }
+
+    /// Parse the URL of a data node, applying the configured credentials.
+    fn node_info(&self, url: &str) -> Result<ConnectionInfo> {
+        let mut info = url.into_connection_info()?;
+        if self.username.is_some() {
+            info.redis.username.clone_from(&self.username);
+        }
+        if self.password.is_some() {
+            info.redis.password.clone_from(&self.password);
+        }
+        Ok(info)
+    }
+}
+
+/// Create a client for one Redis node, with TLS certificates if provided.
+fn client(info: ConnectionInfo, certs: &Option<TlsCertificates>) -> RedisResult<Client> {
+    match certs {
+        Some(certs) => Client::build_with_tls(info, certs.clone()),
+        None => Client::open(info),
+    }
+}
+
+/// Discovery of the current master through a set of Sentinels.
+struct Sentinel {
+    sentinels: Vec<Client>,
+    master: String,
+    tls: bool,
+    redis: RedisConnectionInfo,
+    certs: Option<TlsCertificates>,
+}
+
+impl Sentinel {
+    /// Ask each Sentinel in turn for the address of the master.
+    async fn master(&self) -> RedisResult<Client> {
+        for sentinel in &self.sentinels {
+            let addr: RedisResult<Option<(String, u16)>> = async {
+                let mut conn = sentinel.get_multiplexed_async_connection().await?;
+                redis::cmd("SENTINEL")
+                    .arg("get-master-addr-by-name")
+                    .arg(&self.master)
+                    .query_async(&mut conn)
+                    .await
+            }
+            .await;
+            match addr {
+                Ok(Some((host, port))) => {
+                    let addr = if self.tls {
+                        ConnectionAddr::TcpTls {
+                            host,
+                            port,
+                            insecure: false,
+                            tls_params: None,
+                        }
+                    } else {
+                        ConnectionAddr::Tcp(host, port)
+                    };
+                    let redis = self.redis.clone();
+                    return client(ConnectionInfo { addr, redis }, &self.certs);
+                }
+                Ok(None) => warn!(master = self.master, "sentinel does not know the master"),
+                Err(err) => warn!(?err, "failed to query sentinel"),
+            }
+        }
+        Err(RedisError::from((
+            ErrorKind::MasterNameNotFoundBySentinel,
+            "no sentinel returned the master",
+        )))
+    }
+}
+
+/// Source of connections to Redis, in each of the supported deployments.
+enum Connector {
+    /// A single Redis server.
+    Single(Client),
+
+    /// A master that is discovered through Sentinel, and may fail over.
+    Sentinel(Sentinel),
+
+    /// A Redis Cluster, where keys of a session share the `{name}` hash tag.
+    Cluster {
+        cluster: ClusterClient,
+        nodes: Vec<Client>,
+        next_node: AtomicUsize,
+    },
+}
+
+impl Connector {
+    fn new(config: &RedisConfig) -> Result<Self> {
+        ensure!(!config.urls.is_empty(), "no Redis URLs were provided");
+        let certs = config.certificates()?;
+
+        if let Some(master) = &config.sentinel_master {
+            ensure!(
+                !config.cluster,
+                "cannot use both Redis Sentinel and Cluster"
+            );
+            let sentinels = config
+                .urls
+                .iter()
+                .map(|url| client(url.as_str().into_connection_info()?, &certs))
+                .collect::<RedisResult<Vec<_>>>()?;
+            let tls = matches!(
+                sentinels[0].get_connection_info().addr,
+                ConnectionAddr::TcpTls { .. }
+            );
+            let redis = RedisConnectionInfo {
+                username: config.username.clone(),
+                password: config.password.clone(),
+                ..Default::default()
+            };
+            Ok(Self::Sentinel(Sentinel {
+                sentinels,
+                master: master.clone(),
+                tls,
+                redis,
+                certs,
+            }))
+        } else if config.cluster {
+            let nodes = config
+                .urls
+                .iter()
+                .map(|url| config.node_info(url))
+                .collect::<Result<Vec<_>>>()?;
+            let mut builder = ClusterClientBuilder::new(nodes.clone());
+            if let Some(certs) = &certs {
+                builder = builder.certs(certs.clone());
+            }
+            Ok(Self::Cluster {
+                cluster: builder.build()?,
+                nodes: nodes
+                    .into_iter()
+                    .map(|info| client(info, &certs))
+                    .collect::<RedisResult<_>>()?,
+                next_node: AtomicUsize::new(0),
+            })
+        } else {
+            ensure!(
+                config.urls.len() == 1,
+                "multiple Redis URLs require Sentinel or Cluster"
+            );
+            Ok(Self::Single(client(
+                config.node_info(&config.urls[0])?,
+                &certs,
+            )?))
+        }
+    }
+
+    /// Open a connection for pub/sub, which is not pooled.
+    ///
+    /// Messages published in a cluster are broadcast to every node, so any node
+    /// can be subscribed to. Nodes are tried in turn on each reconnection.
+    async fn pubsub(&self) -> RedisResult<PubSub> {
+        match self {
+            Self::Single(client) => client.get_async_pubsub().await,
+            Self::Sentinel(sentinel) => sentinel.master().await?.get_async_pubsub().await,
+            Self::Cluster {
+                nodes, next_node, ..
+            } => {
+                let index = next_node.fetch_add(1, Ordering::Relaxed) % nodes.len();
+                nodes[index].get_async_pubsub().await
+            }
+        }
+    }
+}
+
+/// Pool manager for Redis connections, which counts errors for metrics.
+struct Manager {
+    connector: Connector,
+    errors: Counter,
+}
+
+impl managed::Manager for Manager {
+    type Type = RedisConnection;
+    type Error = RedisError;
+
+    async fn create(&self) -> RedisResult<RedisConnection> {
+        let conn = async {
+            Ok(match &self.connector {
+                Connector::Single(client) => {
+                    NodeConnection::Single(client.get_multiplexed_async_connection().await?)
+                }
+                Connector::Sentinel(sentinel) => {
+                    let client = sentinel.master().await?;
+                    NodeConnection::Single(client.get_multiplexed_async_connection().await?)
+                }
+                Connector::Cluster { cluster, .. } => {
+                    NodeConnection::Cluster(cluster.get_async_connection().await?)
+                }
+            })
+        };
+        match conn.await {
+            Ok(conn) => Ok(RedisConnection {
+                conn,
+                errors: self.errors.clone(),
+            }),
+            Err(err) => {
+                self.errors.inc();
+                Err(err)
+            }
+        }
+    }
+
+    async fn recycle(&self, conn: &mut RedisConnection, _: &Metrics) -> RecycleResult<RedisError> {
+        if let Connector::Sentinel(_) = self.connector {
+            // After a failover, the old master is demoted and rejects writes.
+            let role: Value = redis::cmd("ROLE").query_async(conn).await?;
+            match role {
+                Value::Array(items)
+                    if items.first() == Some(&Value::BulkString(b"master".into())) =>
+                {
+                    Ok(())
+                }
+                _ => Err(RecycleError::message(
+                    "connection is no longer to the master",
+                )),
+            }
+        } else {
+            let () = redis::cmd("PING").query_async(conn).await?;
+            Ok(())
+        }
+    }
+}
+
+/// Pooled connection to Redis, which counts errors for metrics.
+pub struct RedisConnection {
+    conn: NodeConnection,
+    errors: Counter,
+}
+
+/// Connection to Redis, which is either to one node or to a cluster.
+enum NodeConnection {
+    /// Connection to a single node.
+    Single(MultiplexedConnection),
+
+    /// Connection that routes commands to the nodes of a cluster.
+    Cluster(ClusterConnection),
+}
+
+/// Count the error from a Redis command, if it fails.
+fn count_errors<'a, T: Send + 'a>(errors: Counter, fut: RedisFuture<'a, T>) -> RedisFuture<'a, T> {
+    Box::pin(async move { fut.await.inspect_err(|_| _ = errors.inc()) })
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        let errors = self.errors.clone();
+        let fut = match &mut self.conn {
+            NodeConnection::Single(conn) => conn.req_packed_command(cmd),
+            NodeConnection::Cluster(conn) => conn.req_packed_command(cmd),
+        };
+        count_errors(errors, fut)
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let errors = self.errors.clone();
+        let fut = match &mut self.conn {
+            NodeConnection::Single(conn) => conn.req_packed_commands(cmd, offset, count),
+            NodeConnection::Cluster(conn) => conn.req_packed_commands(cmd, offset, count),
+        };
+        count_errors(errors, fut)
+    }
+
+    fn get_db(&self) -> i64 {
+        match &self.conn {
+            NodeConnection::Single(conn) => conn.get_db(),
+            NodeConnection::Cluster(conn) => conn.get_db(),
+        }
+    }
+}
+
 /// Communication with a distributed mesh of sshx server nodes.
 ///
-/// This uses a Redis instance to persist data across restarts, as well as a
+/// This uses a Redis deployment to persist data across restarts, as well as a
 /// pub/sub channel to keep be notified of when another node becomes the owner
-/// of an active session.
+/// of an active session. Redis may be a single server, a master monitored by
+/// Sentinel, or a cluster.
 ///
 /// All servers must be accessible to each other through TCP mesh networking,
 /// since requests are forwarded to the controller of a given session.
 #[derive(Clone)]
 pub struct StorageMesh {
-    redis: deadpool_redis::Pool,
-    redis_pubsub: redis::Client,
+    redis: managed::Pool<Manager>,
+    errors: Counter,
     host: Option<String>,
+    acquire_script: redis::Script,
+    save_script: redis::Script,
+    append_script: redis::Script,
 }
 
 impl StorageMesh {
-    /// Construct a new storage object from Redis URL.
-    pub fn new(redis_url: &str, host: Option<&str>) -> Result<Self> {
-        let redis = deadpool_redis::Config::from_url(redis_url)
-            .builder()?
+    /// Construct a new storage object from Redis connection settings.
+    pub fn new(config: &RedisConfig, host: Option<&str>) -> Result<Self> {
+        let errors = Counter::default();
+        let manager = Manager {
+            connector: Connector::new(config)?,
+            errors: errors.clone(),
+        };
+        let redis = managed::Pool::builder(manager)
             .max_size(10)
             .wait_timeout(Some(Duration::from_secs(5)))
-            .runtime(deadpool_redis::Runtime::Tokio1)
+            .runtime(deadpool::Runtime::Tokio1)
             .build()?;
 
-        // Separate `redis::Client` just for pub/sub connections.
-        //
-        // At time of writing, deadpool-redis has not been updated to support the new
-        // pub/sub client APIs in Rust. This is a temporary workaround that creates a
-        // new Redis client on the side, bypassing the connection pool.
-        //
-        // Reference: https://github.com/deadpool-rs/deadpool/issues/226
-        let redis_pubsub = redis::Client::open(redis_url)?;
-
         Ok(Self {
             redis,
-            redis_pubsub,
+            errors,
             host: host.map(|s| s.to_string()),
+            acquire_script: redis::Script::new(ACQUIRE_SCRIPT),
+            save_script: redis::Script::new(SAVE_SCRIPT),
+            append_script: redis::Script::new(APPEND_SCRIPT),
         })
     }
+}
 
-    /// Returns the hostname of this server, if running in mesh node.
-    pub fn host(&self) -> Option<&str> {
+#[async_trait]
+impl SessionStore for StorageMesh {
+    fn host(&self) -> Option<&str> {
         self.host.as_deref()
     }
 
-    /// Retrieve the hostname of the owner of a session.
-    pub async fn get_owner(&self, name: &str) -> Result<Option<String>> {
+    async fn get_owner(&self, name: &str) -> Result<Option<String>> {
         let mut conn = self.redis.get().await?;
         let (owner, closed) = redis::pipe()
             .get(format!("session:{{{name}}}:owner"))
             .get(format!("session:{{{name}}}:closed"))
-            .query_async(&mut conn)
+            .query_async(&mut *conn)
             .await?;
         if closed {
             Ok(None)
@@ -82,71 +495,88 @@ impl StorageMesh {
         }
     }
 
-    /// Retrieve the owner and snapshot of a session.
-    pub async fn get_owner_snapshot(
+    async fn get_owner_snapshot(
         &self,
         name: &str,
-    ) -> Result<(Option<String>, Option<Vec<u8>>)> {
+    ) -> Result<(Option<String>, Option<StoredSnapshot>)> {
         let mut conn = self.redis.get().await?;
-        let (owner, snapshot, closed) = redis::pipe()
+        let (owner, snapshot, deltas, closed): (_, Option<Vec<u8>>, _, _) = redis::pipe()
             .get(format!("session:{{{name}}}:owner"))
             .get(format!("session:{{{name}}}:snapshot"))
+            .lrange(format!("session:{{{name}}}:deltas"), 0, -1)
             .get(format!("session:{{{name}}}:closed"))
-            .query_async(&mut conn)
+            .query_async(&mut *conn)
             .await?;
         if closed {
             Ok((None, None))
         } else {
+            let snapshot = snapshot.map(|snapshot| StoredSnapshot { snapshot, deltas });
             Ok((owner, snapshot))
         }
     }
 
-    /// Periodically set the owner and snapshot of a session.
-    pub async fn background_sync(&self, name: &str, session: Arc<Session>) {
-        let mut interval = time::interval(STORAGE_SYNC_INTERVAL);
-        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
-        loop {
-            tokio::select! {
-                _ = interval.tick() => {}
-                _ = session.sync_now_wait() => {}
-                _ = session.terminated() => break,
-            }
-            let mut conn = match self.redis.get().await {
-                Ok(conn) => conn,
-                Err(err) => {
-                    error!(?err, "failed to connect to redis for sync");
-                    continue;
-                }
-            };
-            let snapshot = match session.snapshot() {
-                Ok(snapshot) => snapshot,
-                Err(err) => {
-                    error!(?err, "failed to snapshot session {name}");
-                    continue;
-                }
-            };
-            let mut pipe = redis::pipe();
-            if let Some(host) = &self.host {
-                pipe.set_options(format!("session:{{{name}}}:owner"), host, set_opts());
-            }
-            pipe.set_options(format!("session:{{{name}}}:snapshot"), snapshot, set_opts());
-            match pipe.query_async(&mut conn).await {
-                Ok(()) => {}
-                Err(err) => error!(?err, "failed to sync session {name}"),
-            }
-        }
+    async fn acquire_lease(&self, name: &str, after: u64) -> Result<u64> {
+        let mut conn = self.redis.get().await?;
+        let epoch = self
+            .acquire_script
+            .key(format!("session:{{{name}}}:epoch"))
+            .key(format!("session:{{{name}}}:owner"))
+            .arg(after)
+            .arg(self.host.as_deref().unwrap_or_default())
+            .arg(STORAGE_EXPIRY.as_millis() as u64)
+            .invoke_async(&mut *conn)
+            .await?;
+        Ok(epoch)
     }
 
-    /// Mark a session as closed, so it will expire and never be accessed again.
-    pub async fn mark_closed(&self, name: &str) -> Result<()> {
+    async fn save(&self, name: &str, epoch: u64, snapshot: Vec<u8>) -> Result<()> {
+        let mut conn = self.redis.get().await?;
+        let status: i64 = self
+            .save_script
+            .key(format!("session:{{{name}}}:epoch"))
+            .key(format!("session:{{{name}}}:owner"))
+            .key(format!("session:{{{name}}}:snapshot"))
+            .key(format!("session:{{{name}}}:deltas"))
+            .arg(epoch)
+            .arg(self.host.as_deref().unwrap_or_default())
+            .arg(snapshot)
+            .arg(STORAGE_EXPIRY.as_millis() as u64)
+            .invoke_async(&mut *conn)
+            .await?;
+        ensure!(status != 0, LeaseLost);
+        Ok(())
+    }
+
+    async fn append_delta(&self, name: &str, epoch: u64, delta: Vec<u8>) -> Result<()> {
+        let mut conn = self.redis.get().await?;
+        let status: i64 = self
+            .append_script
+            .key(format!("session:{{{name}}}:epoch"))
+            .key(format!("session:{{{name}}}:owner"))
+            .key(format!("session:{{{name}}}:snapshot"))
+            .key(format!("session:{{{name}}}:deltas"))
+            .arg(epoch)
+            .arg(self.host.as_deref().unwrap_or_default())
+            .arg(delta)
+            .arg(STORAGE_EXPIRY.as_millis() as u64)
+            .invoke_async(&mut *conn)
+            .await?;
+        ensure!(status != 0, LeaseLost);
+        ensure!(status > 0, "cannot append delta to a missing snapshot");
+        Ok(())
+    }
+
+    async fn mark_closed(&self, name: &str) -> Result<()> {
         let mut conn = self.redis.get().await?;
         let (owner,): (Option<String>,) = redis::pipe()
             .get_del(format!("session:{{{name}}}:owner"))
             .del(format!("session:{{{name}}}:snapshot"))
             .ignore()
+            .del(format!("session:{{{name}}}:deltas"))
+            .ignore()
             .set_options(format!("session:{{{name}}}:closed"), true, set_opts())
             .ignore()
-            .query_async(&mut conn)
+            .query_async(&mut *conn)
             .await?;
         if let Some(owner) = owner {
             self.notify_transfer(name, &owner).await?;
@@ -154,16 +584,22 @@ impl StorageMesh {
         Ok(())
     }
 
-    /// Notify a host that a session has been transferred.
-    pub async fn notify_transfer(&self, name: &str, host: &str) -> Result<()> {
+    async fn notify_transfer(&self, name: &str, host: &str) -> Result<()> {
         let mut conn = self.redis.get().await?;
         () = conn.publish(format!("transfers:{host}"), name).await?;
         Ok(())
     }
 
-    /// Listen for sessions that are transferred away from this host.
-    pub fn listen_for_transfers(&self) -> impl Stream<Item = String> + Send + '_ {
-        async_stream::stream! {
+    fn register_metrics(&self, registry: &mut Registry) {
+        registry.register(
+            "redis_errors",
+            "Errors connecting to or querying Redis",
+            self.errors.clone(),
+        );
+    }
+
+    fn listen_for_transfers(&self) -> BoxStream<'_, String> {
+        Box::pin(async_stream::stream! {
             let Some(host) = &self.host else {
                 // If not in a mesh, there are no transfers.
                 return;
@@ -171,15 +607,17 @@ impl StorageMesh {
 
             loop {
                 // Requires an owned, non-pool connection for ownership reasons.
-                let mut pubsub = match self.redis_pubsub.get_async_pubsub().await {
+                let mut pubsub = match self.redis.manager().connector.pubsub().await {
                     Ok(pubsub) => pubsub,
                     Err(err) => {
+                        self.errors.inc();
                         error!(?err, "failed to connect to redis for pub/sub");
                         time::sleep(Duration::from_secs(5)).await;
                         continue;
                     }
                 };
                 if let Err(err) = pubsub.subscribe(format!("transfers:{host}")).await {
+                    self.errors.inc();
                     error!(?err, "failed to subscribe to transfers");
                     time::sleep(Duration::from_secs(1)).await;
                     continue;
@@ -196,6 +634,6 @@ impl StorageMesh {
                     };
                 }
             }
-        }
+        })
     }
 }
@@ -0,0 +1,206 @@
+//! Encryption and authentication of connections between servers in a mesh.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{ensure, Context, Result};
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use hmac::{Hmac, Mac};
+use http::Method;
+use rustls::{ClientConfig, RootCertStore};
+use sha2::Sha256;
+use tokio_tungstenite::Connector;
+use tracing::warn;
+
+use crate::utils::unix_millis;
+use crate::ServerOptions;
+
+/// Header on WebSocket requests proxied from a peer, signed with the mesh
+/// secret over the time, method and path of the request.
+pub const MESH_AUTH_HEADER: &str = "x-sshx-mesh-auth";
+
+/// Maximum age of a mesh authentication header, in milliseconds.
+const MESH_AUTH_MAX_AGE: u64 = 60_000;
+
+/// Settings for proxying connections to other servers in the mesh.
+pub struct PeerConfig {
+    /// TLS settings, if peers are connected to with `wss://`.
+    tls: Option<Arc<ClientConfig>>,
+
+    /// Code for signing and verifying requests between peers, if enabled.
+    mac: Option<Hmac<Sha256>>,
+
+    /// Whether this server's own listener is served over TLS.
+    listener_tls: bool,
+
+    /// Whether signed requests may be sent and accepted over plain HTTP.
+    allow_plaintext: bool,
+}
+
+impl PeerConfig {
+    /// Create a new configuration for connecting to peers.
+    ///
+    /// If TLS is enabled, peer certificates are verified against the CA bundle
+    /// in `ca_file` if provided, or the public web roots otherwise. Requests
+    /// are only signed over TLS, unless `allow_plaintext` is set.
+    pub fn new(
+        tls: bool,
+        ca_file: Option<&Path>,
+        secret: Option<&str>,
+        allow_plaintext: bool,
+    ) -> Result<Self> {
+        ensure!(tls || ca_file.is_none(), "peer CA bundle requires peer TLS");
+        let tls = if tls {
+            let mut roots = RootCertStore::empty();
+            match ca_file {
+                Some(path) => {
+                    let file = File::open(path)
+                        .with_context(|| format!("failed to open {}", path.display()))?;
+                    for cert in rustls_pemfile::certs(&mut BufReader::new(file)) {
+                        roots.add(cert?)?;
+                    }
+                    ensure!(!roots.is_empty(), "no certificates in {}", path.display());
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let config = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            Some(Arc::new(config))
+        } else {
+            None
+        };
+        let mac = secret.map(|secret| Hmac::new_from_slice(secret.as_bytes()).unwrap());
+        Ok(Self {
+            tls,
+            mac,
+            listener_tls: false,
+            allow_plaintext,
+        })
+    }
+
+    /// Create the configuration for connecting to peers from server options.
+    pub fn from_options(options: &ServerOptions) -> Result<Self> {
+        let mut config = Self::new(
+            options.peer_tls,
+            options.peer_ca.as_deref(),
+            options.mesh_secret.as_deref(),
+            options.mesh_allow_plaintext,
+        )?;
+        config.listener_tls = options.tls_cert.is_some() || options.tls_self_signed;
+        if config.mac.is_some() && !config.allow_plaintext {
+            if config.tls.is_none() {
+                warn!("mesh secret is not sent to peers without peer TLS");
+            }
+            if !config.listener_tls {
+                warn!("mesh secret is not accepted from peers without TLS");
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns the WebSocket URL of a path on a peer.
+    pub fn url(&self, host: &str, path: &str) -> String {
+        let scheme = if self.tls.is_some() { "wss" } else { "ws" };
+        format!("{scheme}://{host}{path}")
+    }
+
+    /// Returns the TLS connector for peers, if enabled.
+    pub fn connector(&self) -> Option<Connector> {
+        self.tls.clone().map(Connector::Rustls)
+    }
+
+    /// Sign a request to a peer, returning the value of the header.
+    ///
+    /// Returns `None` if there is no mesh secret, or if the request would be
+    /// sent over plain HTTP without the operator allowing it.
+    pub fn sign(&self, method: &Method, path: &str) -> Option<String> {
+        if self.tls.is_none() && !self.allow_plaintext {
+            return None;
+        }
+        let mac = self.mac.clone()?;
+        let time = unix_millis();
+        let tag = mac
+            .chain_update(auth_message(time, method, path))
+            .finalize();
+        Some(format!(
+            "{time}:{}",
+            BASE64_STANDARD.encode(tag.into_bytes())
+        ))
+    }
+
+    /// Verify the signed header of a request from a peer.
+    ///
+    /// Requests received over plain HTTP are rejected, unless the operator
+    /// allowed it.
+    pub fn verify(&self, method: &Method, path: &str, value: &str) -> bool {
+        if !self.listener_tls && !self.allow_plaintext {
+            return false;
+        }
+        let (Some(mac), Some((time, tag))) = (self.mac.clone(), value.split_once(':')) else {
+            return false;
+        };
+        let (Ok(time_ms), Ok(tag)) = (time.parse::<u64>(), BASE64_STANDARD.decode(tag)) else {
+            return false;
+        };
+        unix_millis().abs_diff(time_ms) <= MESH_AUTH_MAX_AGE
+            && mac
+                .chain_update(auth_message(time_ms, method, path))
+                .verify_slice(&tag)
+                .is_ok()
+    }
+}
+
+/// Message authenticated by the mesh secret for a request.
+fn auth_message(time: u64, method: &Method, path: &str) -> String {
+    format!("{time}:{method}:{path}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let config = PeerConfig::new(false, None, Some("mesh secret"), true).unwrap();
+        let header = config.sign(&Method::GET, "/api/s/abc").unwrap();
+        assert!(config.verify(&Method::GET, "/api/s/abc", &header));
+        assert!(!config.verify(&Method::GET, "/api/s/xyz", &header));
+        assert!(!config.verify(&Method::POST, "/api/s/abc", &header));
+
+        let other = PeerConfig::new(false, None, Some("other secret"), true).unwrap();
+        assert!(!other.verify(&Method::GET, "/api/s/abc", &header));
+
+        let unsigned = PeerConfig::new(false, None, None, true).unwrap();
+        assert!(unsigned.sign(&Method::GET, "/api/s/abc").is_none());
+        assert!(!unsigned.verify(&Method::GET, "/api/s/abc", &header));
+    }
+
+    #[test]
+    fn plaintext_refused() {
+        let config = PeerConfig::new(false, None, Some("mesh secret"), true).unwrap();
+        let header = config.sign(&Method::GET, "/api/s/abc").unwrap();
+
+        let strict = PeerConfig::new(false, None, Some("mesh secret"), false).unwrap();
+        assert!(strict.sign(&Method::GET, "/api/s/abc").is_none());
+        assert!(!strict.verify(&Method::GET, "/api/s/abc", &header));
+    }
+
+    #[test]
+    fn expired_header() {
+        let config = PeerConfig::new(false, None, Some("mesh secret"), true).unwrap();
+        let time = unix_millis() - 2 * MESH_AUTH_MAX_AGE;
+        let tag = config
+            .mac
+            .clone()
+            .unwrap()
+            .chain_update(auth_message(time, &Method::GET, "/api/s/abc"))
+            .finalize();
+        let header = format!("{time}:{}", BASE64_STANDARD.encode(tag.into_bytes()));
+        assert!(!config.verify(&Method::GET, "/api/s/abc", &header));
+    }
+}
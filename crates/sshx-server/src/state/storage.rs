@@ -0,0 +1,331 @@
+//! Pluggable backends for persisting sessions outside of server memory.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{ensure, Context, Result};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures_util::stream::{self, BoxStream};
+use parking_lot::RwLock;
+use prometheus_client::registry::Registry;
+use tokio::time::{self, Instant};
+use tracing::error;
+
+use crate::config::Limits;
+use crate::metrics::Metrics;
+use crate::session::{Session, SyncPosition};
+
+/// Interval for syncing the latest session state into persistent storage.
+pub const STORAGE_SYNC_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Length of time a stored session lasts without syncs before it is expired.
+pub const STORAGE_EXPIRY: Duration = Duration::from_secs(300);
+
+/// Number of deltas appended after a full snapshot before it is compacted.
+const COMPACT_AFTER_DELTAS: usize = 15;
+
+/// Snapshot of a session in storage, with the deltas appended after it.
+#[derive(Debug, Clone, Default)]
+pub struct StoredSnapshot {
+    /// Compressed full snapshot, from [`Session::snapshot`].
+    pub snapshot: Vec<u8>,
+    /// Compressed deltas, from [`Session::snapshot_delta`], in order.
+    pub deltas: Vec<Vec<u8>>,
+}
+
+/// Error returned when another server has taken over a session's lease.
+///
+/// After this, the session must no longer be served by this server, since its
+/// state would diverge from the new owner's.
+#[derive(Debug, Clone, Copy)]
+pub struct LeaseLost;
+
+impl fmt::Display for LeaseLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "session lease was acquired by another server")
+    }
+}
+
+impl std::error::Error for LeaseLost {}
+
+impl StoredSnapshot {
+    /// Restore the session from storage.
+    pub fn restore(&self) -> Result<Session> {
+        Session::restore_with_deltas(&self.snapshot, &self.deltas)
+    }
+
+    /// Restore the session from storage, with limits that may change while it
+    /// runs.
+    pub fn restore_with_limits(&self, limits: Arc<RwLock<Limits>>) -> Result<Session> {
+        Session::restore_with_limits(&self.snapshot, &self.deltas, limits)
+    }
+}
+
+/// Storage for session snapshots and ownership, shared by server nodes.
+///
+/// Sessions are periodically snapshotted into the store while they are active,
+/// so they can be restored after a restart or on a different server.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Returns the hostname of this server, if running in a mesh.
+    fn host(&self) -> Option<&str>;
+
+    /// Retrieve the hostname of the owner of a session.
+    async fn get_owner(&self, name: &str) -> Result<Option<String>>;
+
+    /// Retrieve the owner and snapshot of a session.
+    async fn get_owner_snapshot(
+        &self,
+        name: &str,
+    ) -> Result<(Option<String>, Option<StoredSnapshot>)>;
+
+    /// Acquire the lease on a session for this host, returning its epoch.
+    ///
+    /// The new epoch is greater than both `after` and any epoch previously
+    /// acquired for the session, so writes from older owners are fenced off.
+    async fn acquire_lease(&self, name: &str, after: u64) -> Result<u64>;
+
+    /// Store a full snapshot of a session, replacing any previous snapshot and
+    /// deltas.
+    ///
+    /// Fails with [`LeaseLost`] if a newer lease than `epoch` was acquired.
+    async fn save(&self, name: &str, epoch: u64, snapshot: Vec<u8>) -> Result<()>;
+
+    /// Append a delta to the stored snapshot of a session.
+    ///
+    /// Fails with [`LeaseLost`] if a newer lease than `epoch` was acquired.
+    async fn append_delta(&self, name: &str, epoch: u64, delta: Vec<u8>) -> Result<()>;
+
+    /// Mark a session as closed, so it will expire and never be accessed again.
+    async fn mark_closed(&self, name: &str) -> Result<()>;
+
+    /// Notify a host that a session has been transferred.
+    async fn notify_transfer(&self, name: &str, host: &str) -> Result<()>;
+
+    /// Listen for sessions that are transferred away from this host.
+    fn listen_for_transfers(&self) -> BoxStream<'_, String>;
+
+    /// Register metrics specific to this store, if it has any.
+    fn register_metrics(&self, _registry: &mut Registry) {}
+
+    /// Periodically sync the state of a session until it is terminated.
+    ///
+    /// Most syncs only append a delta of new data, with a full snapshot taken
+    /// every so often to compact them. Returns [`LeaseLost`] if another server
+    /// takes over the session, after which it should be shut down here.
+    async fn background_sync(
+        &self,
+        name: &str,
+        session: Arc<Session>,
+        metrics: Arc<Metrics>,
+    ) -> Result<()> {
+        let mut interval = time::interval(STORAGE_SYNC_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        let mut leased = false;
+        let mut position: Option<SyncPosition> = None;
+        let mut deltas = 0;
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = session.sync_now_wait() => {}
+                _ = session.terminated() => return Ok(()),
+            }
+            if !leased {
+                match self.acquire_lease(name, session.epoch()).await {
+                    Ok(epoch) => {
+                        session.set_epoch(epoch);
+                        leased = true;
+                    }
+                    Err(err) => {
+                        error!(?err, "failed to acquire lease on session {name}");
+                        continue;
+                    }
+                }
+            }
+            let epoch = session.epoch();
+            let start = Instant::now();
+            let result = match &position {
+                Some(since) if deltas < COMPACT_AFTER_DELTAS => {
+                    match session.snapshot_delta(since) {
+                        Ok((delta, next)) => {
+                            let size = delta.len();
+                            let result = self.append_delta(name, epoch, delta).await;
+                            result.map(|_| (next, size))
+                        }
+                        Err(err) => Err(err),
+                    }
+                }
+                _ => match session.snapshot_position() {
+                    Ok((snapshot, next)) => {
+                        let size = snapshot.len();
+                        self.save(name, epoch, snapshot).await.map(|_| (next, size))
+                    }
+                    Err(err) => Err(err),
+                },
+            };
+            match result {
+                Ok((next, size)) => {
+                    metrics.snapshot_bytes.observe(size as f64);
+                    metrics
+                        .snapshot_seconds
+                        .observe(start.elapsed().as_secs_f64());
+                    deltas = if position.is_some() { deltas + 1 } else { 0 };
+                    position = Some(next);
+                }
+                Err(err) if err.is::<LeaseLost>() => return Err(err),
+                Err(err) => {
+                    error!(?err, "failed to sync session {name}");
+                    // Take a full snapshot next time, since a delta may be lost.
+                    position = None;
+                }
+            }
+        }
+    }
+}
+
+/// Stored state of a session in memory.
+struct MemoryEntry {
+    snapshot: Option<StoredSnapshot>,
+    closed: bool,
+    epoch: u64,
+    owner: Option<String>,
+    updated: Instant,
+}
+
+impl MemoryEntry {
+    fn new() -> Self {
+        Self {
+            snapshot: None,
+            closed: false,
+            epoch: 0,
+            owner: None,
+            updated: Instant::now(),
+        }
+    }
+}
+
+/// Session store that is kept in memory, so it is lost on restart.
+///
+/// This is the default for a single server, where no other nodes need to read
+/// the snapshots.
+#[derive(Default)]
+pub struct MemoryStore {
+    entries: Arc<DashMap<String, MemoryEntry>>,
+    host: Option<String>,
+}
+
+impl MemoryStore {
+    /// Construct a new, empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a view of the same store for a server with the given hostname.
+    ///
+    /// This lets servers in one process act as a mesh, redirecting to the
+    /// owners of sessions, which is mostly useful for testing.
+    pub fn with_host(&self, host: &str) -> Self {
+        Self {
+            entries: Arc::clone(&self.entries),
+            host: Some(host.into()),
+        }
+    }
+}
+
+#[async_trait]
+impl SessionStore for MemoryStore {
+    fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    async fn get_owner(&self, name: &str) -> Result<Option<String>> {
+        Ok(match self.entries.get(name) {
+            Some(entry) if !entry.closed => entry.owner.clone(),
+            _ => None,
+        })
+    }
+
+    async fn get_owner_snapshot(
+        &self,
+        name: &str,
+    ) -> Result<(Option<String>, Option<StoredSnapshot>)> {
+        self.entries
+            .remove_if(name, |_, entry| entry.updated.elapsed() > STORAGE_EXPIRY);
+        Ok(match self.entries.get(name) {
+            Some(entry) if !entry.closed => (entry.owner.clone(), entry.snapshot.clone()),
+            _ => (None, None),
+        })
+    }
+
+    async fn acquire_lease(&self, name: &str, after: u64) -> Result<u64> {
+        let mut entry = self
+            .entries
+            .entry(name.to_string())
+            .or_insert_with(MemoryEntry::new);
+        entry.epoch = entry.epoch.max(after) + 1;
+        entry.owner.clone_from(&self.host);
+        entry.updated = Instant::now();
+        Ok(entry.epoch)
+    }
+
+    async fn save(&self, name: &str, epoch: u64, snapshot: Vec<u8>) -> Result<()> {
+        let mut entry = self
+            .entries
+            .entry(name.to_string())
+            .or_insert_with(MemoryEntry::new);
+        ensure!(entry.epoch <= epoch, LeaseLost);
+        if !entry.closed {
+            entry.snapshot = Some(StoredSnapshot {
+                snapshot,
+                deltas: Vec::new(),
+            });
+            entry.epoch = epoch;
+            entry.updated = Instant::now();
+        }
+        Ok(())
+    }
+
+    async fn append_delta(&self, name: &str, epoch: u64, delta: Vec<u8>) -> Result<()> {
+        let mut entry = self
+            .entries
+            .get_mut(name)
+            .context("cannot append delta to a missing snapshot")?;
+        ensure!(entry.epoch <= epoch, LeaseLost);
+        if !entry.closed {
+            let snapshot = entry
+                .snapshot
+                .as_mut()
+                .context("cannot append delta to a missing snapshot")?;
+            snapshot.deltas.push(delta);
+            entry.updated = Instant::now();
+        }
+        Ok(())
+    }
+
+    async fn mark_closed(&self, name: &str) -> Result<()> {
+        self.entries
+            .retain(|_, entry| entry.updated.elapsed() <= STORAGE_EXPIRY);
+        let mut entry = self
+            .entries
+            .entry(name.to_string())
+            .or_insert_with(MemoryEntry::new);
+        // Keep the epoch, so that older owners are still fenced off.
+        *entry = MemoryEntry {
+            closed: true,
+            epoch: entry.epoch,
+            ..MemoryEntry::new()
+        };
+        Ok(())
+    }
+
+    async fn notify_transfer(&self, _name: &str, _host: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn listen_for_transfers(&self) -> BoxStream<'_, String> {
+        // There are no other servers to transfer sessions to.
+        Box::pin(stream::empty())
+    }
+}
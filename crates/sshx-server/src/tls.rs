@@ -0,0 +1,250 @@
+//! TLS termination for the server, with certificates that reload while running.
+
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use axum::serve::Listener;
+use parking_lot::{Mutex, RwLock};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_rustls::{server::TlsStream, TlsAcceptor};
+use tracing::{debug, error, info, warn};
+
+use crate::ServerOptions;
+
+/// Interval between checks for changes to the certificate files.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time allowed for a client to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the server's certificate comes from.
+enum CertSource {
+    /// PEM files for the certificate chain and private key.
+    Files { cert: PathBuf, key: PathBuf },
+
+    /// A certificate generated at startup, for development.
+    SelfSigned,
+}
+
+/// Certificate resolver where the certificate can be swapped while serving.
+#[derive(Debug)]
+struct Resolver(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.0.read()))
+    }
+}
+
+/// TLS settings for serving both HTTP and gRPC on a single port.
+pub struct ServerTls {
+    source: CertSource,
+    provider: Arc<CryptoProvider>,
+    resolver: Arc<Resolver>,
+    config: Arc<ServerConfig>,
+
+    /// Modification times of the certificate files when they were last loaded.
+    modified: Mutex<Option<(SystemTime, SystemTime)>>,
+}
+
+impl ServerTls {
+    /// Create the TLS settings requested in server options, if any.
+    pub fn from_options(options: &ServerOptions) -> Result<Option<Self>> {
+        match (&options.tls_cert, &options.tls_key, options.tls_self_signed) {
+            (Some(cert), Some(key), false) => Ok(Some(Self::from_files(cert, key)?)),
+            (None, None, true) => {
+                let mut names = vec!["localhost".to_string()];
+                if let Some(host) = &options.host {
+                    let hostname = host.rsplit_once(':').map_or(host.as_str(), |(h, _)| h);
+                    names.push(hostname.to_string());
+                }
+                Ok(Some(Self::self_signed(names)?))
+            }
+            (None, None, false) => Ok(None),
+            (_, _, true) => bail!("cannot use a self-signed certificate with certificate files"),
+            _ => bail!("TLS certificate and private key must be provided together"),
+        }
+    }
+
+    /// Serve the certificate chain and private key in PEM files.
+    pub fn from_files(cert: &Path, key: &Path) -> Result<Self> {
+        let source = CertSource::Files {
+            cert: cert.into(),
+            key: key.into(),
+        };
+        let tls = Self::new(source, |provider| load_files(provider, cert, key))?;
+        *tls.modified.lock() = modified_times(cert, key).ok();
+        Ok(tls)
+    }
+
+    /// Serve a certificate that is generated for the given names.
+    ///
+    /// Clients will not trust this certificate, so it is only for development.
+    pub fn self_signed(names: Vec<String>) -> Result<Self> {
+        warn!(
+            ?names,
+            "serving a self-signed TLS certificate, for development only"
+        );
+        Self::new(CertSource::SelfSigned, |provider| {
+            let generated = rcgen::generate_simple_self_signed(names)?;
+            let key = PrivatePkcs8KeyDer::from(generated.key_pair.serialize_der());
+            let key = provider.key_provider.load_private_key(key.into())?;
+            Ok(CertifiedKey::new(vec![generated.cert.der().clone()], key))
+        })
+    }
+
+    fn new(
+        source: CertSource,
+        load: impl FnOnce(&CryptoProvider) -> Result<CertifiedKey>,
+    ) -> Result<Self> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let resolver = Arc::new(Resolver(RwLock::new(Arc::new(load(&provider)?))));
+        let mut config = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        // gRPC requires HTTP/2, which clients must negotiate with ALPN.
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Self {
+            source,
+            provider,
+            resolver,
+            config: Arc::new(config),
+            modified: Mutex::new(None),
+        })
+    }
+
+    /// Load the certificate files again, serving them to new connections.
+    ///
+    /// If the files are invalid, the previous certificate is kept.
+    pub fn reload(&self) -> Result<()> {
+        let CertSource::Files { cert, key } = &self.source else {
+            return Ok(());
+        };
+        let modified = modified_times(cert, key).ok();
+        let certified_key = load_files(&self.provider, cert, key)?;
+        *self.resolver.0.write() = Arc::new(certified_key);
+        *self.modified.lock() = modified;
+        info!(cert = %cert.display(), "reloaded TLS certificate");
+        Ok(())
+    }
+
+    /// Reload the certificate files whenever they change, running forever.
+    pub async fn watch(&self) {
+        let CertSource::Files { cert, key } = &self.source else {
+            return;
+        };
+        let mut interval = time::interval(RELOAD_INTERVAL);
+        interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Ok(modified) = modified_times(cert, key) else {
+                continue;
+            };
+            if *self.modified.lock() != Some(modified) {
+                if let Err(err) = self.reload() {
+                    error!(?err, "failed to reload TLS certificate");
+                    // Avoid logging the same error on every check.
+                    *self.modified.lock() = Some(modified);
+                }
+            }
+        }
+    }
+
+    /// Accept TLS connections on a listener.
+    ///
+    /// Handshakes are completed in the background, so slow clients do not
+    /// hold up other connections.
+    pub fn listen<L>(&self, mut listener: L) -> io::Result<TlsListener<L>>
+    where
+        L: Listener,
+        L::Addr: Clone + Debug,
+    {
+        let local_addr = listener.local_addr()?;
+        let acceptor = TlsAcceptor::from(Arc::clone(&self.config));
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (stream, addr) = tokio::select! {
+                    conn = listener.accept() => conn,
+                    _ = tx.closed() => break,
+                };
+                let (acceptor, tx) = (acceptor.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => _ = tx.send((stream, addr)).await,
+                        Ok(Err(err)) => debug!(?addr, "TLS handshake failed: {err}"),
+                        Err(_) => debug!(?addr, "TLS handshake timed out"),
+                    }
+                });
+            }
+        });
+        Ok(TlsListener { rx, local_addr })
+    }
+}
+
+/// Listener for connections that have completed a TLS handshake.
+pub struct TlsListener<L: Listener> {
+    rx: mpsc::Receiver<(TlsStream<L::Io>, L::Addr)>,
+    local_addr: L::Addr,
+}
+
+impl<L> Listener for TlsListener<L>
+where
+    L: Listener,
+    L::Addr: Clone,
+{
+    type Io = TlsStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.rx.recv().await {
+            Some(conn) => conn,
+            // The background task only stops when this listener is dropped.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr.clone())
+    }
+}
+
+/// Load a certificate chain and private key from PEM files.
+fn load_files(provider: &CryptoProvider, cert: &Path, key: &Path) -> Result<CertifiedKey> {
+    let open = |path: &Path| {
+        File::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("failed to open {}", path.display()))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .collect::<Result<Vec<CertificateDer>, _>>()
+        .with_context(|| format!("failed to parse {}", cert.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates in {}", cert.display());
+    }
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut open(key)?)
+        .with_context(|| format!("failed to parse {}", key.display()))?
+        .with_context(|| format!("no private key in {}", key.display()))?;
+    let key = provider.key_provider.load_private_key(key)?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Returns the modification times of the certificate and key files.
+fn modified_times(cert: &Path, key: &Path) -> io::Result<(SystemTime, SystemTime)> {
+    Ok((
+        fs::metadata(cert)?.modified()?,
+        fs::metadata(key)?.modified()?,
+    ))
+}
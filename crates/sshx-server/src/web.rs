@@ -2,15 +2,20 @@
 
 use std::sync::Arc;
 
-use axum::routing::{any, get_service};
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::{any, get, get_service};
 use axum::Router;
 use tower_http::services::{ServeDir, ServeFile};
 
 use crate::ServerState;
 
-pub mod protocol;
-mod socket;
+pub use sshx_core::web as protocol;
 mod admin;
+mod cli;
+mod replay;
+mod socket;
 
 /// Returns the web application server, routed with Axum.
 pub fn app() -> Router<Arc<ServerState>> {
@@ -29,9 +34,22 @@ pub fn app() -> Router<Arc<ServerState>> {
         .fallback_service(get_service(static_files))
 }
 
+/// Returns the server for Prometheus metrics, which is bound separately.
+pub fn metrics_app() -> Router<Arc<ServerState>> {
+    Router::new().route("/metrics", get(get_metrics))
+}
+
+/// Encode the server's metrics in the OpenMetrics text format.
+async fn get_metrics(State(state): State<Arc<ServerState>>) -> impl IntoResponse {
+    let content_type = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+    ([(CONTENT_TYPE, content_type)], state.metrics().encode())
+}
+
 /// Routes for the backend web API server.
 fn backend() -> Router<Arc<ServerState>> {
     Router::new()
         .merge(admin::routes())
         .route("/s/{name}", any(socket::get_session_ws))
+        .route("/cli/{method}", any(cli::get_cli_ws))
+        .route("/replay/{name}", any(replay::get_replay_ws))
 }
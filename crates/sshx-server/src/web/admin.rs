@@ -12,7 +12,7 @@ use sha2::{Sha256, Digest};
 
 use crate::ServerState;
 
-// Default password: titeo123
+// Default password: titeo123, unless configured in the `[admin]` section.
 const DEFAULT_PASSWORD: &str = "titeo123";
 
 #[derive(Deserialize)]
@@ -47,6 +47,8 @@ struct ServerStats {
     total_disk: u64,
     used_disk: u64,
     uptime: u64,
+    scrollback_bytes: u64,
+    scrollback_budget_bytes: u64,
 }
 
 #[derive(Serialize)]
@@ -69,9 +71,13 @@ fn generate_token(password: &str) -> String {
     format!("{:x}", hasher.finalize())
 }
 
-async fn login(Json(payload): Json<LoginRequest>) -> Response {
+async fn login(
+    State(state): State<Arc<ServerState>>,
+    Json(payload): Json<LoginRequest>,
+) -> Response {
     // Verify password
-    if payload.password == DEFAULT_PASSWORD {
+    let password = state.admin_password();
+    if payload.password == password.as_deref().unwrap_or(DEFAULT_PASSWORD) {
         let token = generate_token(&payload.password);
         let response = LoginResponse {
             success: true,
@@ -133,6 +139,8 @@ async fn list_devices(State(state): State<Arc<ServerState>>) -> Json<DashboardDa
             total_disk,
             used_disk,
             uptime,
+            scrollback_bytes: state.scrollback_bytes(),
+            scrollback_budget_bytes: state.limits().scrollback_budget_bytes,
         }
     };
 
@@ -160,8 +168,6 @@ async fn execute_all(
     
     // Send command to all sessions
     for (id, _, _, _, _, _, _, _) in sessions {
-        // Convert command string to bytes and send to session
-        let command_bytes = format!("{}\n", payload.command).into_bytes();
         // TODO: Actually send the command to the session
         // This requires access to the session's input channel
         // For now, we'll just log it
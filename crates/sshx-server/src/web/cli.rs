@@ -0,0 +1,135 @@
+//! WebSocket transport for the command-line client, when gRPC over HTTP/2 is
+//! blocked by a proxy. See [`sshx_core::tunnel`] for the protocol.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::extract::{
+    ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    Extension, Path, State,
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use futures_util::SinkExt;
+use sshx_core::proto::{sshx_service_server::SshxService, ClientUpdate};
+use sshx_core::tunnel;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::{Request, Status};
+use tracing::{info_span, warn, Instrument};
+
+use crate::grpc::GrpcServer;
+use crate::ratelimit::ClientAddr;
+use crate::ServerState;
+
+pub async fn get_cli_ws(
+    Path(method): Path<String>,
+    ws: WebSocketUpgrade,
+    client: Option<Extension<ClientAddr>>,
+    State(state): State<Arc<ServerState>>,
+) -> Response {
+    if !matches!(method.as_str(), "open" | "channel" | "close") {
+        return (StatusCode::NOT_FOUND, "unknown method").into_response();
+    }
+    let client = client.map(|Extension(client)| client).unwrap_or_default();
+    let server = GrpcServer::new(state);
+
+    ws.on_upgrade(move |mut socket| {
+        let span = info_span!("cli", %method);
+        async move {
+            let result = match method.as_str() {
+                "open" => unary(&mut socket, client, |req| server.open(req)).await,
+                "close" => unary(&mut socket, client, |req| server.close(req)).await,
+                _ => channel(&mut socket, client, &server).await,
+            };
+            if let Err(err) = result {
+                warn!(?err, "websocket call exiting early");
+            } else {
+                socket.close().await.ok();
+            }
+        }
+        .instrument(span)
+    })
+}
+
+/// Close the connection with a status, so the client can report it.
+async fn close_with_status(socket: &mut WebSocket, status: Status) -> Result<()> {
+    let (code, reason) = tunnel::status_to_close(&status);
+    let frame = CloseFrame {
+        code,
+        reason: reason.into(),
+    };
+    socket.send(Message::Close(Some(frame))).await?;
+    Ok(())
+}
+
+/// Receive the next protobuf message from the client.
+async fn recv<T: prost::Message + Default>(socket: &mut WebSocket) -> Result<Option<T>> {
+    Ok(loop {
+        match socket.recv().await.transpose()? {
+            Some(Message::Binary(msg)) => break Some(T::decode(msg)?),
+            Some(Message::Close(_)) | None => break None,
+            Some(_) => (), // ignore other message types, keep looping
+        }
+    })
+}
+
+/// Send a protobuf message to the client.
+async fn send(socket: &mut WebSocket, msg: &impl prost::Message) -> Result<()> {
+    let msg = Bytes::from(msg.encode_to_vec());
+    socket.send(Message::Binary(msg)).await?;
+    Ok(())
+}
+
+/// Handle a call with a single request and response.
+async fn unary<T, U, F>(
+    socket: &mut WebSocket,
+    client: ClientAddr,
+    call: impl FnOnce(Request<T>) -> F,
+) -> Result<()>
+where
+    T: prost::Message + Default,
+    U: prost::Message,
+    F: Future<Output = Result<tonic::Response<U>, Status>>,
+{
+    let Some(req) = recv(socket).await? else {
+        return Ok(());
+    };
+    let mut req = Request::new(req);
+    req.extensions_mut().insert(client);
+    match call(req).await {
+        Ok(resp) => send(socket, resp.get_ref()).await,
+        Err(status) => close_with_status(socket, status).await,
+    }
+}
+
+/// Handle a streaming channel of updates, until either side hangs up.
+async fn channel(socket: &mut WebSocket, client: ClientAddr, server: &GrpcServer) -> Result<()> {
+    // The first update has to arrive before the channel can start.
+    let Some(hello) = recv::<ClientUpdate>(socket).await? else {
+        return Ok(());
+    };
+    let (updates_tx, updates_rx) = mpsc::channel(16);
+    updates_tx.send(Ok(hello)).await?;
+    let stream = ReceiverStream::new(updates_rx);
+    let mut server_updates = match server.start_channel(client, stream).await {
+        Ok(server_updates) => server_updates,
+        Err(status) => return close_with_status(socket, status).await,
+    };
+
+    loop {
+        tokio::select! {
+            msg = recv(socket) => match msg? {
+                Some(update) => updates_tx.send(Ok(update)).await?,
+                None => return Ok(()), // The client has hung up on their end.
+            },
+            update = server_updates.next() => match update {
+                Some(Ok(update)) => send(socket, &update).await?,
+                Some(Err(status)) => return close_with_status(socket, status).await,
+                None => return Ok(()),
+            },
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Playback of encrypted session recordings over WebSocket.
+//!
+//! Replays use the same protocol as live sessions, so viewers can decrypt them
+//! with the key from the original session URL. Output is only sent for shells
+//! that the viewer has subscribed to, as in a live session. Recordings are read
+//! from disk as they are played back.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::ErrorKind;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use axum::extract::{
+    ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+    Extension, Path, Query, State,
+};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use futures_util::SinkExt;
+use serde::Deserialize;
+use sshx_core::{Sid, Uid, PROTOCOL_VERSION};
+use subtle::ConstantTimeEq;
+use tokio::time::{self, Instant};
+use tracing::{error, info_span, warn, Instrument};
+
+use super::socket::{close_outdated, recv, send};
+use crate::compat;
+use crate::config::Limits;
+use crate::ratelimit::{Action, ClientAddr};
+use crate::session::{Record, RecordReader};
+use crate::web::protocol::{WsClient, WsServer};
+use crate::ServerState;
+
+/// Idle periods in a recording are shortened to at most this long.
+const MAX_REPLAY_IDLE: Duration = Duration::from_secs(2);
+
+/// Slowest and fastest playback speeds, other than instant playback.
+const REPLAY_SPEEDS: RangeInclusive<f64> = 0.1..=100.0;
+
+/// Query parameters for a replay.
+#[derive(Deserialize, Debug)]
+pub struct ReplayParams {
+    /// Playback speed, relative to the original. Zero plays back instantly.
+    speed: Option<f64>,
+}
+
+pub async fn get_replay_ws(
+    Path(name): Path<String>,
+    Query(params): Query<ReplayParams>,
+    ws: WebSocketUpgrade,
+    client: Option<Extension<ClientAddr>>,
+    State(state): State<Arc<ServerState>>,
+) -> Response {
+    let speed = match params.speed {
+        None => 1.0,
+        Some(speed) if !speed.is_finite() || speed < 0.0 => {
+            return (StatusCode::BAD_REQUEST, "invalid replay speed").into_response();
+        }
+        Some(0.0) => 0.0,
+        Some(speed) => speed.clamp(*REPLAY_SPEEDS.start(), *REPLAY_SPEEDS.end()),
+    };
+    let client = client.map(|Extension(client)| client).unwrap_or_default();
+    let allowed = state.check_rate(client, Action::WebSocket);
+
+    ws.on_upgrade(move |mut socket| {
+        let span = info_span!("replay", %name);
+        async move {
+            if !allowed {
+                let frame = CloseFrame {
+                    code: 4429,
+                    reason: "too many connections, try again later".into(),
+                };
+                socket.send(Message::Close(Some(frame))).await.ok();
+                return;
+            }
+            match open_recording(&state, &name).await {
+                Ok(Some(records)) => {
+                    let limits = state.limits();
+                    let replay = handle_replay(&mut socket, records, speed, &limits);
+                    if let Err(err) = replay.await {
+                        warn!(?err, "replay exiting early");
+                    } else {
+                        socket.close().await.ok();
+                    }
+                }
+                Ok(None) => {
+                    let frame = CloseFrame {
+                        code: 4404,
+                        reason: "could not find the requested recording".into(),
+                    };
+                    socket.send(Message::Close(Some(frame))).await.ok();
+                }
+                Err(err) => {
+                    error!(?err, "failed to load recording");
+                    let frame = CloseFrame {
+                        code: 4500,
+                        reason: format!("load recording: {err}").into(),
+                    };
+                    socket.send(Message::Close(Some(frame))).await.ok();
+                }
+            }
+        }
+        .instrument(span)
+    })
+    .into_response()
+}
+
+/// Open a session's recording for reading, if it exists.
+async fn open_recording(state: &ServerState, name: &str) -> Result<Option<RecordReader>> {
+    let Some(path) = state.recording_path(name) else {
+        return Ok(None);
+    };
+    match RecordReader::open(&path).await {
+        Ok(reader) => Ok(Some(reader)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Output of a shell played back so far, for viewers that subscribe late.
+///
+/// Like in a live session, only the most recent output is kept in memory.
+#[derive(Default)]
+struct ReplayShell {
+    /// Number of chunks pruned before the first one stored.
+    chunk_offset: u64,
+
+    /// Chunks of output with their sequence numbers.
+    chunks: VecDeque<(u64, Bytes)>,
+
+    /// Total bytes of the stored chunks.
+    bytes: u64,
+}
+
+impl ReplayShell {
+    /// Add a chunk, then prune the oldest ones to at most `max_bytes`.
+    fn push(&mut self, seqnum: u64, data: Bytes, max_bytes: u64) {
+        self.bytes += data.len() as u64;
+        self.chunks.push_back((seqnum, data));
+        while self.bytes > max_bytes {
+            let Some((_, data)) = self.chunks.pop_front() else {
+                break;
+            };
+            self.bytes -= data.len() as u64;
+            self.chunk_offset += 1;
+        }
+    }
+
+    /// Returns the chunks from an index on, split into runs of consecutive
+    /// output that each start at their own sequence number.
+    ///
+    /// Records may be missing from a recording that fell behind, and output
+    /// after a gap can only be decrypted at its own sequence number.
+    fn runs(&self, start: usize) -> Vec<(u64, Vec<Bytes>)> {
+        let mut runs: Vec<(u64, Vec<Bytes>)> = Vec::new();
+        let mut end = 0;
+        for (seqnum, data) in self.chunks.range(start.min(self.chunks.len())..) {
+            match runs.last_mut() {
+                Some((_, chunks)) if *seqnum == end => chunks.push(data.clone()),
+                _ => runs.push((*seqnum, vec![data.clone()])),
+            }
+            end = seqnum + data.len() as u64;
+        }
+        runs
+    }
+}
+
+/// Play back a recording to a viewer, then wait for the viewer to leave.
+async fn handle_replay(
+    socket: &mut WebSocket,
+    mut records: RecordReader,
+    speed: f64,
+    limits: &Limits,
+) -> Result<()> {
+    let (name, encrypted_zeros, compressed) = match records.next().await? {
+        Some(Record::Header {
+            name,
+            encrypted_zeros,
+            compressed,
+        }) => (name, encrypted_zeros, compressed),
+        _ => bail!("recording is missing its header"),
+    };
+
+    // There is only one user in a replay, so the ID is arbitrary.
+    let hello = WsServer::Hello(Uid(1), name, compressed, PROTOCOL_VERSION);
+    send(socket, hello, false).await?;
+    let deflate = match recv(socket).await? {
+        Some(WsClient::Authenticate(bytes, _, version))
+            if bool::from(bytes.ct_eq(&encrypted_zeros)) =>
+        {
+            let min_version = limits.min_protocol_version;
+            if let Err(reason) = compat::check_viewer(version, min_version, compressed) {
+                return close_outdated(socket, reason).await;
+            }
+            compat::can_deflate(version)
+        }
+        _ => {
+            send(socket, WsServer::InvalidAuth(), false).await?;
+            return Ok(());
+        }
+    };
+    send(socket, WsServer::Users(Vec::new()), deflate).await?;
+
+    let mut history: HashMap<Sid, ReplayShell> = HashMap::new();
+    let mut subscribed = HashSet::new();
+    let mut deadline = Instant::now();
+    let mut last_time = None;
+    let mut next_record = records.next().await?;
+
+    loop {
+        let next_deadline = next_record.as_ref().map(|record| {
+            let time = record.time().unwrap_or_default();
+            let elapsed = time.saturating_sub(last_time.unwrap_or(time));
+            let delay = Duration::from_millis(elapsed).min(MAX_REPLAY_IDLE);
+            if speed > 0.0 {
+                deadline
+                    .checked_add(delay.div_f64(speed))
+                    .unwrap_or(deadline)
+            } else {
+                deadline
+            }
+        });
+
+        tokio::select! {
+            _ = time::sleep_until(next_deadline.unwrap_or(deadline)), if next_deadline.is_some() => {
+                deadline = next_deadline.unwrap();
+                let record = next_record.take().unwrap();
+                last_time = record.time().or(last_time);
+                match record {
+                    Record::Header { .. } => {}
+                    Record::Shells(_, shells) => {
+                        send(socket, WsServer::Shells(shells), deflate).await?;
+                    }
+                    Record::Data(_, id, seqnum, data) => {
+                        let shell = history.entry(id).or_default();
+                        shell.push(seqnum, data.clone(), limits.shell_stored_bytes);
+                        if subscribed.contains(&id) {
+                            send(socket, WsServer::Chunks(id, seqnum, vec![data]), deflate).await?;
+                        }
+                    }
+                }
+                next_record = records.next().await?;
+            }
+            result = recv(socket) => match result? {
+                Some(WsClient::Subscribe(id, chunknum)) => {
+                    if !subscribed.insert(id) {
+                        continue;
+                    }
+                    let Some(shell) = history.get(&id) else {
+                        continue;
+                    };
+                    let start = chunknum.saturating_sub(shell.chunk_offset) as usize;
+                    for (seqnum, chunks) in shell.runs(start) {
+                        send(socket, WsServer::Chunks(id, seqnum, chunks), deflate).await?;
+                    }
+                }
+                Some(WsClient::Ping(ts)) => send(socket, WsServer::Pong(ts), deflate).await?,
+                Some(
+                    WsClient::Create(..)
+                    | WsClient::Close(_)
+                    | WsClient::Move(..)
+                    | WsClient::Data(..),
+                ) => {
+                    let msg = String::from("cannot modify a recorded session");
+                    send(socket, WsServer::Error(msg), deflate).await?;
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+    Ok(())
+}
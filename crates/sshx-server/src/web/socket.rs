@@ -1,43 +1,94 @@
 use std::collections::HashSet;
 use std::sync::Arc;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use axum::extract::{
     ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
-    Path, State,
+    Extension, OriginalUri, Path, State,
 };
-use axum::response::IntoResponse;
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
 use bytes::Bytes;
 use futures_util::SinkExt;
-use sshx_core::proto::{server_update::ServerMessage, NewShell, TerminalInput, TerminalSize};
-use sshx_core::Sid;
+use sshx_core::compress;
+use sshx_core::proto::{server_update::ServerMessage, Compression, NewShell, TerminalInput};
+use sshx_core::{Sid, PROTOCOL_VERSION};
 use subtle::ConstantTimeEq;
 use tokio::sync::mpsc;
-use tokio_stream::StreamExt;
-use tracing::{error, info_span, warn, Instrument};
+use tokio::time::{self, Duration, Instant};
+use tokio_stream::{wrappers::errors::BroadcastStreamRecvError, StreamExt};
+use tracing::{debug, error, info_span, warn, Instrument};
 
+use crate::compat;
+use crate::config::Limits;
+use crate::ratelimit::{Action, ClientAddr};
 use crate::session::Session;
+use crate::state::peer::MESH_AUTH_HEADER;
 use crate::web::protocol::{WsClient, WsServer};
 use crate::ServerState;
 
+/// Smallest encoded message that is compressed for viewers that support it.
+const DEFLATE_MIN_BYTES: usize = 512;
+
 pub async fn get_session_ws(
     Path(name): Path<String>,
+    method: Method,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
     ws: WebSocketUpgrade,
+    client: Option<Extension<ClientAddr>>,
     State(state): State<Arc<ServerState>>,
-) -> impl IntoResponse {
+) -> Response {
+    // Connections proxied from a peer are signed, and never proxied again.
+    let from_peer = match headers.get(MESH_AUTH_HEADER) {
+        Some(value) => {
+            let value = value.to_str().unwrap_or_default();
+            if !state.peer().verify(&method, uri.path(), value) {
+                warn!(%name, "rejecting peer connection with invalid signature");
+                return (StatusCode::FORBIDDEN, "invalid mesh authentication").into_response();
+            }
+            true
+        }
+        None => false,
+    };
+
+    // Peers have already limited the clients whose connections they proxy.
+    let client = match client {
+        Some(Extension(client)) if !from_peer => client,
+        _ => ClientAddr::default(),
+    };
+    let rejection = if !state.check_rate(client, Action::WebSocket) {
+        Some("too many connections, try again later".to_string())
+    } else {
+        let backoff = state.rate_limiter().auth_backoff(client, &state.limits());
+        backoff.map(|wait| {
+            let secs = wait.as_secs_f64().ceil();
+            format!("too many failed attempts, try again in {secs} seconds")
+        })
+    };
+
     ws.on_upgrade(move |mut socket| {
         let span = info_span!("ws", %name);
         async move {
+            if let Some(reason) = rejection {
+                let frame = CloseFrame {
+                    code: 4429,
+                    reason: reason.into(),
+                };
+                socket.send(Message::Close(Some(frame))).await.ok();
+                return;
+            }
             match state.frontend_connect(&name).await {
                 Ok(Ok(session)) => {
-                    if let Err(err) = handle_socket(&mut socket, session).await {
+                    if let Err(err) = handle_socket(&mut socket, session, &state, client).await {
                         warn!(?err, "websocket exiting early");
                     } else {
                         socket.close().await.ok();
                     }
                 }
-                Ok(Err(Some(host))) => {
-                    if let Err(err) = proxy_redirect(&mut socket, &host, &name).await {
+                Ok(Err(Some(host))) if !from_peer => {
+                    state.metrics().proxy_redirects.inc();
+                    if let Err(err) = proxy_redirect(&mut socket, &state, &host, &name).await {
                         error!(?err, "failed to proxy websocket");
                         let frame = CloseFrame {
                             code: 4500,
@@ -48,7 +99,7 @@ pub async fn get_session_ws(
                         socket.close().await.ok();
                     }
                 }
-                Ok(Err(None)) => {
+                Ok(Err(_)) => {
                     let frame = CloseFrame {
                         code: 4404,
                         reason: "could not find the requested session".into(),
@@ -69,44 +120,82 @@ pub async fn get_session_ws(
     })
 }
 
-/// Handle an incoming live WebSocket connection to a given session.
-async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<()> {
-    /// Send a message to the client over WebSocket.
-    async fn send(socket: &mut WebSocket, msg: WsServer) -> Result<()> {
-        // Optimization: Pre-allocate buffer to avoid frequent re-allocations
-        // 4KB is enough for most terminal updates
-        let mut buf = Vec::with_capacity(4096);
+/// Send a message to the client over WebSocket.
+///
+/// If `deflate` is set, messages larger than [`DEFLATE_MIN_BYTES`] are wrapped
+/// in [`WsServer::Deflated`]. This stands in for the permessage-deflate
+/// extension, since tungstenite, which axum's WebSocket is built on, does not
+/// support it. Instead of negotiating an extension in the handshake, callers
+/// only set `deflate` for viewers whose protocol version passes
+/// [`compat::can_deflate`]. Terminal output is encrypted, so it is never
+/// compressed here.
+pub(super) async fn send(socket: &mut WebSocket, msg: WsServer, deflate: bool) -> Result<()> {
+    // Optimization: Pre-allocate buffer to avoid frequent re-allocations
+    // 4KB is enough for most terminal updates
+    let mut buf = Vec::with_capacity(4096);
+    ciborium::ser::into_writer(&msg, &mut buf)?;
+    if deflate && buf.len() >= DEFLATE_MIN_BYTES && !matches!(msg, WsServer::Chunks(..)) {
+        let msg = WsServer::Deflated(compress::deflate(&buf).into());
+        buf.clear();
         ciborium::ser::into_writer(&msg, &mut buf)?;
-        socket.send(Message::Binary(Bytes::from(buf))).await?;
-        Ok(())
     }
+    socket.send(Message::Binary(Bytes::from(buf))).await?;
+    Ok(())
+}
 
-    /// Receive a message from the client over WebSocket.
-    async fn recv(socket: &mut WebSocket) -> Result<Option<WsClient>> {
-        Ok(loop {
-            match socket.recv().await.transpose()? {
-                Some(Message::Text(_)) => warn!("ignoring text message over WebSocket"),
-                Some(Message::Binary(msg)) => break Some(ciborium::de::from_reader(&*msg)?),
-                Some(_) => (), // ignore other message types, keep looping
-                None => break None,
-            }
-        })
-    }
+/// Receive a message from the client over WebSocket.
+pub(super) async fn recv(socket: &mut WebSocket) -> Result<Option<WsClient>> {
+    Ok(loop {
+        match socket.recv().await.transpose()? {
+            Some(Message::Text(_)) => warn!("ignoring text message over WebSocket"),
+            Some(Message::Binary(msg)) => break Some(compat::decode_ws_client(&msg)?),
+            Some(_) => (), // ignore other message types, keep looping
+            None => break None,
+        }
+    })
+}
 
+/// Close the connection of a client that is too old, with a reason to show
+/// the user.
+pub(super) async fn close_outdated(socket: &mut WebSocket, reason: String) -> Result<()> {
+    let frame = CloseFrame {
+        code: 4426,
+        reason: reason.into(),
+    };
+    socket.send(Message::Close(Some(frame))).await?;
+    Ok(())
+}
+
+/// Handle an incoming live WebSocket connection to a given session.
+async fn handle_socket(
+    socket: &mut WebSocket,
+    session: Arc<Session>,
+    state: &ServerState,
+    client: ClientAddr,
+) -> Result<()> {
     let metadata = session.metadata();
     let user_id = session.counter().next_uid();
     session.sync_now();
-    send(socket, WsServer::Hello(user_id, metadata.name.clone())).await?;
+    let compressed = metadata.compression != Compression::None;
+    let hello = WsServer::Hello(user_id, metadata.name.clone(), compressed, PROTOCOL_VERSION);
+    send(socket, hello, false).await?;
+
+    let rate_limiter = state.rate_limiter();
+    let (can_write, deflate) = match recv(socket).await? {
+        Some(WsClient::Authenticate(bytes, write_password_bytes, version)) => {
+            let min_version = state.limits().min_protocol_version;
+            if let Err(reason) = compat::check_viewer(version, min_version, compressed) {
+                return close_outdated(socket, reason).await;
+            }
 
-    let can_write = match recv(socket).await? {
-        Some(WsClient::Authenticate(bytes, write_password_bytes)) => {
             // Constant-time comparison of bytes, converting Choice to bool
             if !bool::from(bytes.ct_eq(metadata.encrypted_zeros.as_ref())) {
-                send(socket, WsServer::InvalidAuth()).await?;
+                rate_limiter.auth_failed(client);
+                send(socket, WsServer::InvalidAuth(), false).await?;
                 return Ok(());
             }
 
-            match (write_password_bytes, &metadata.write_password_hash) {
+            let can_write = match (write_password_bytes, &metadata.write_password_hash) {
                 // No password needed, so all users can write (default).
                 (_, None) => true,
 
@@ -116,24 +205,27 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
                 // Password stored and provided, compare them.
                 (Some(provided), Some(stored)) => {
                     if !bool::from(provided.ct_eq(stored)) {
-                        send(socket, WsServer::InvalidAuth()).await?;
+                        rate_limiter.auth_failed(client);
+                        send(socket, WsServer::InvalidAuth(), false).await?;
                         return Ok(());
                     }
                     true
                 }
-            }
+            };
+            (can_write, compat::can_deflate(version))
         }
         _ => {
-            send(socket, WsServer::InvalidAuth()).await?;
+            send(socket, WsServer::InvalidAuth(), false).await?;
             return Ok(());
         }
     };
+    rate_limiter.auth_succeeded(client);
 
     let _user_guard = session.user_scope(user_id, can_write)?;
 
     let update_tx = session.update_tx(); // start listening for updates before any state reads
     let mut broadcast_stream = session.subscribe_broadcast();
-    send(socket, WsServer::Users(session.list_users())).await?;
+    send(socket, WsServer::Users(session.list_users()), deflate).await?;
 
     let mut subscribed = HashSet::new(); // prevent duplicate subscriptions
     let (chunks_tx, mut chunks_rx) = mpsc::channel::<(Sid, u64, Vec<Bytes>)>(1);
@@ -143,16 +235,25 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
         let msg = tokio::select! {
             _ = session.terminated() => break,
             Some(result) = broadcast_stream.next() => {
-                let msg = result.context("client fell behind on broadcast stream")?;
-                send(socket, msg).await?;
+                match result {
+                    Ok(msg) => send(socket, msg, deflate).await?,
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        // Missed updates are replaced by fresh snapshots.
+                        debug!(skipped, "resynchronizing client that fell behind");
+                        state.metrics().lagged_resyncs.inc();
+                        send(socket, WsServer::Users(session.list_users()), deflate).await?;
+                        send(socket, WsServer::Shells(session.shells()), deflate).await?;
+                    }
+                }
                 continue;
             }
             Some(shells) = shells_stream.next() => {
-                send(socket, WsServer::Shells(shells)).await?;
+                send(socket, WsServer::Shells(shells), deflate).await?;
                 continue;
             }
             Some((id, seqnum, chunks)) = chunks_rx.recv() => {
-                send(socket, WsServer::Chunks(id, seqnum, chunks)).await?;
+                state.metrics().chunk_messages.inc();
+                send(socket, WsServer::Chunks(id, seqnum, chunks), deflate).await?;
                 continue;
             }
             result = recv(socket) => {
@@ -164,7 +265,7 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
         };
 
         match msg {
-            WsClient::Authenticate(_, _) => {}
+            WsClient::Authenticate(..) => {}
             WsClient::SetName(name) => {
                 if !name.is_empty() {
                     session.update_user(user_id, |user| user.name = name)?;
@@ -178,7 +279,12 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
             }
             WsClient::Create(x, y) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
+                    send(socket, WsServer::Error(e.to_string()), deflate).await?;
+                    continue;
+                }
+                if state.at_shell_limit() {
+                    let msg = "server has too many open shells".to_string();
+                    send(socket, WsServer::Error(msg), deflate).await?;
                     continue;
                 }
                 let id = session.counter().next_sid();
@@ -190,32 +296,27 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
             }
             WsClient::Close(id) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
+                    send(socket, WsServer::Error(e.to_string()), deflate).await?;
                     continue;
                 }
                 update_tx.send(ServerMessage::CloseShell(id.0)).await?;
             }
             WsClient::Move(id, winsize) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
+                    send(socket, WsServer::Error(e.to_string()), deflate).await?;
                     continue;
                 }
                 if let Err(err) = session.move_shell(id, winsize) {
-                    send(socket, WsServer::Error(err.to_string())).await?;
+                    send(socket, WsServer::Error(err.to_string()), deflate).await?;
                     continue;
                 }
                 if let Some(winsize) = winsize {
-                    let msg = ServerMessage::Resize(TerminalSize {
-                        id: id.0,
-                        rows: winsize.rows as u32,
-                        cols: winsize.cols as u32,
-                    });
-                    session.update_tx().send(msg).await?;
+                    session.send_resize(id, winsize).await?;
                 }
             }
             WsClient::Data(id, data, offset) => {
                 if let Err(e) = session.check_write_permission(user_id) {
-                    send(socket, WsServer::Error(e.to_string())).await?;
+                    send(socket, WsServer::Error(e.to_string()), deflate).await?;
                     continue;
                 }
                 let input = TerminalInput {
@@ -232,35 +333,118 @@ async fn handle_socket(socket: &mut WebSocket, session: Arc<Session>) -> Result<
                 subscribed.insert(id);
                 let session = Arc::clone(&session);
                 let chunks_tx = chunks_tx.clone();
+                let limits = state.limits();
                 tokio::spawn(async move {
-                    let stream = session.subscribe_chunks(id, chunknum);
-                    tokio::pin!(stream);
-                    while let Some((seqnum, chunks)) = stream.next().await {
-                        if chunks_tx.send((id, seqnum, chunks)).await.is_err() {
-                            break;
-                        }
-                    }
+                    forward_chunks(&session, id, chunknum, chunks_tx, limits).await;
                 });
             }
             WsClient::Chat(msg) => {
                 session.send_chat(user_id, &msg)?;
             }
             WsClient::Ping(ts) => {
-                send(socket, WsServer::Pong(ts)).await?;
+                send(socket, WsServer::Pong(ts), deflate).await?;
             }
         }
     }
     Ok(())
 }
 
+/// Forward chunks of output from a shell to a WebSocket connection.
+///
+/// Output is held for up to `chunk_batch_delay`, or until `chunk_batch_bytes`
+/// are held, so that a noisy shell sends fewer and larger messages. Chunks are
+/// also merged while the connection is busy, so a viewer on a slow link does
+/// not hold up the shell's subscription. At most `shell_stored_bytes` of output
+/// are held for the viewer; older output is skipped, and the viewer
+/// resynchronizes from the sequence number of the next message.
+async fn forward_chunks(
+    session: &Session,
+    id: Sid,
+    chunknum: u64,
+    chunks_tx: mpsc::Sender<(Sid, u64, Vec<Bytes>)>,
+    limits: Limits,
+) {
+    let stream = session.subscribe_chunks(id, chunknum);
+    tokio::pin!(stream);
+    let batch_timer = time::sleep(Duration::ZERO);
+    tokio::pin!(batch_timer);
+    let mut batching = false; // waiting for the batch timer
+    let mut ready = false; // pending output can be sent
+    let mut pending: Option<(u64, Vec<Bytes>)> = None;
+    loop {
+        tokio::select! {
+            item = stream.next() => {
+                let Some((seqnum, chunks)) = item else { break };
+                pending = Some(match pending.take() {
+                    Some((start, mut prev)) if start + total_len(&prev) == seqnum => {
+                        prev.extend(chunks);
+                        (start, prev)
+                    }
+                    _ => (seqnum, chunks),
+                });
+                let (start, chunks) = pending.as_mut().unwrap();
+                let mut skip = 0;
+                let mut held = total_len(chunks);
+                while held > limits.shell_stored_bytes && skip + 1 < chunks.len() {
+                    held -= chunks[skip].len() as u64;
+                    *start += chunks[skip].len() as u64;
+                    skip += 1;
+                }
+                chunks.drain(..skip);
+
+                if held >= limits.chunk_batch_bytes || limits.chunk_batch_delay.is_zero() {
+                    ready = true;
+                } else if !ready && !batching {
+                    batch_timer.as_mut().reset(Instant::now() + limits.chunk_batch_delay);
+                    batching = true;
+                }
+            }
+            _ = &mut batch_timer, if batching => {
+                batching = false;
+                ready = true;
+            }
+            permit = chunks_tx.reserve(), if ready => {
+                let Ok(permit) = permit else { return };
+                let (seqnum, chunks) = pending.take().unwrap();
+                permit.send((id, seqnum, chunks));
+                batching = false;
+                ready = false;
+            }
+        }
+    }
+    // Deliver the last output of a shell after it is closed.
+    if let Some((seqnum, chunks)) = pending {
+        chunks_tx.send((id, seqnum, chunks)).await.ok();
+    }
+}
+
+fn total_len(chunks: &[Bytes]) -> u64 {
+    chunks.iter().map(|x| x.len() as u64).sum()
+}
+
 /// Transparently reverse-proxy a WebSocket connection to a different host.
-async fn proxy_redirect(socket: &mut WebSocket, host: &str, name: &str) -> Result<()> {
+async fn proxy_redirect(
+    socket: &mut WebSocket,
+    state: &ServerState,
+    host: &str,
+    name: &str,
+) -> Result<()> {
     use tokio_tungstenite::{
-        connect_async,
+        connect_async_tls_with_config,
+        tungstenite::client::IntoClientRequest,
         tungstenite::protocol::{CloseFrame as TCloseFrame, Message as TMessage},
     };
 
-    let (mut upstream, _) = connect_async(format!("ws://{host}/api/s/{name}")).await?;
+    let peer = state.peer();
+    let path = format!("/api/s/{name}");
+    let mut request = peer.url(host, &path).into_client_request()?;
+    if let Some(value) = peer.sign(&Method::GET, &path) {
+        request
+            .headers_mut()
+            .insert(MESH_AUTH_HEADER, value.parse()?);
+    }
+    let (mut upstream, _) =
+        connect_async_tls_with_config(request, None, false, peer.connector()).await?;
     loop {
         // Due to axum having its own WebSocket API types, we need to manually translate
         // between it and tungstenite's message type.
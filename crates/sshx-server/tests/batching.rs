@@ -0,0 +1,71 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::Result;
+use bytes::Bytes;
+use sshx_core::{proto::Compression, Sid};
+use sshx_server::ServerOptions;
+use sshx_web_client::SessionClient;
+use tokio::time::{self, Duration};
+use tokio_stream::StreamExt;
+
+use crate::common::*;
+
+pub mod common;
+
+#[tokio::test(start_paused = true)]
+async fn test_chunk_batching() {
+    // With time paused, the shell writes exactly once per millisecond.
+    let mut options = ServerOptions::default();
+    options.limits.chunk_batch_delay = Duration::ZERO;
+    assert_eq!(count_chunk_messages(options.clone(), 100).await, 100);
+
+    options.limits.chunk_batch_delay = Duration::from_millis(10);
+    assert_eq!(count_chunk_messages(options, 100).await, 10);
+}
+
+/// Benchmark of the messages sent to viewers of a noisy shell, with different
+/// batch delays. Run with `cargo test -p sshx-server --test batching --
+/// --ignored --nocapture`.
+#[tokio::test]
+#[ignore]
+async fn bench_chunk_batching() {
+    const WRITES: u64 = 1000;
+    println!("{WRITES} single-byte writes, one every millisecond");
+    for delay in [0, 1, 5, 10, 50] {
+        let mut options = ServerOptions::default();
+        options.limits.chunk_batch_delay = Duration::from_millis(delay);
+        let start = Instant::now();
+        let messages = count_chunk_messages(options, WRITES).await;
+        let elapsed = start.elapsed();
+        println!("batch delay {delay:>2} ms: {messages:>4} messages in {elapsed:.2?}");
+    }
+}
+
+#[tokio::test]
+async fn test_chunk_batch_bytes() -> Result<()> {
+    // Large output is sent right away, without waiting for the batch delay.
+    let mut options = ServerOptions::default();
+    options.limits.chunk_batch_delay = Duration::from_secs(60);
+    options.limits.chunk_batch_bytes = 100;
+    let server = TestServer::with_options(options).await;
+    let state = server.state();
+
+    let session = Arc::new(state.new_session(metadata("key", Compression::None)));
+    state.insert("test", Arc::clone(&session));
+    session.add_shell(Sid(1), (0, 0))?;
+
+    let s = SessionClient::connect(&server.ws_endpoint("test"), "key", None).await?;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+    session.add_data(Sid(1), Bytes::from(vec![b'a'; 50]), 0)?;
+    session.add_data(Sid(1), Bytes::from(vec![b'b'; 50]), 50)?;
+
+    let mut received = 0;
+    let read = async {
+        while received < 100 {
+            received += output.next().await.unwrap().len();
+        }
+    };
+    time::timeout(Duration::from_secs(5), read).await?;
+    Ok(())
+}
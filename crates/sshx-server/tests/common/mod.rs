@@ -1,23 +1,23 @@
-use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::{ensure, Result};
-use axum::serve::ListenerExt;
-use futures_util::{SinkExt, StreamExt};
-use http::StatusCode;
-use sshx::encrypt::Encrypt;
-use sshx_core::proto::sshx_service_client::SshxServiceClient;
-use sshx_core::{Sid, Uid};
+use bytes::Bytes;
+use sshx_core::crypto::Encrypt;
+use sshx_core::proto::{sshx_service_client::SshxServiceClient, Compression, OpenRequest};
+use sshx_core::Sid;
 use sshx_server::{
-    state::ServerState,
-    web::protocol::{WsClient, WsServer, WsUser, WsWinsize},
-    Server,
+    session::Metadata,
+    state::{storage::SessionStore, ServerState},
+    Server, ServerOptions,
 };
+use sshx_web_client::{Event, SessionClient, ShellOutput};
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
 use tokio::time;
-use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+use tokio_stream::StreamExt;
 use tonic::transport::Channel;
 
 /// An ephemeral, isolated server that is created for each test.
@@ -32,23 +32,39 @@ impl TestServer {
     /// Returns an object with the local address, as well as a custom [`Drop`]
     /// implementation that gracefully shuts down the server.
     pub async fn new() -> Self {
-        let listener = TcpListener::bind("[::1]:0").await.unwrap();
-        let local_addr = listener.local_addr().unwrap();
+        Self::with_options(Default::default()).await
+    }
+
+    /// Create a fresh server for testing, with custom options.
+    pub async fn with_options(options: ServerOptions) -> Self {
+        Self::start(Server::new(options).unwrap()).await
+    }
 
-        let server = Arc::new(Server::new(Default::default()).unwrap());
+    /// Create a fresh server for testing, with a shared session store.
+    pub async fn with_storage(options: ServerOptions, storage: Arc<dyn SessionStore>) -> Self {
+        Self::start(Server::with_storage(options, storage).unwrap()).await
+    }
+
+    /// Create a fresh server for testing on a TCP listener, which serves TLS
+    /// if it is configured in the server's options.
+    pub async fn with_listener(server: Server, listener: TcpListener) -> Self {
+        let local_addr = listener.local_addr().unwrap();
+        let server = Arc::new(server);
         {
             let server = Arc::clone(&server);
-            let listener = listener.tap_io(|tcp_stream| {
-                _ = tcp_stream.set_nodelay(true);
-            });
             tokio::spawn(async move {
-                server.listen(listener).await.unwrap();
+                server.listen_tcp(listener).await.unwrap();
             });
         }
 
         TestServer { local_addr, server }
     }
 
+    async fn start(server: Server) -> Self {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        Self::with_listener(server, listener).await
+    }
+
     /// Returns the local TCP address of this server.
     pub fn local_addr(&self) -> SocketAddr {
         self.local_addr
@@ -64,6 +80,34 @@ impl TestServer {
         format!("ws://{}/api/s/{}", self.local_addr, name)
     }
 
+    /// Returns the WebSocket endpoint for replaying a session's recording.
+    pub fn replay_endpoint(&self, name: &str) -> String {
+        format!("ws://{}/api/replay/{}?speed=0", self.local_addr, name)
+    }
+
+    /// Start a proxy to this server that drops HTTP/2 connections, like some
+    /// corporate proxies, and return its base endpoint URI.
+    pub async fn http1_proxy(&self) -> String {
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let upstream = self.local_addr;
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    // Every HTTP/2 connection starts with "PRI * HTTP/2.0".
+                    let mut start = [0; 3];
+                    if stream.read_exact(&mut start).await.is_err() || &start == b"PRI" {
+                        return;
+                    }
+                    let mut server = TcpStream::connect(upstream).await.unwrap();
+                    server.write_all(&start).await.unwrap();
+                    io::copy_bidirectional(&mut stream, &mut server).await.ok();
+                });
+            }
+        });
+        endpoint
+    }
+
     /// Creates a gRPC client connected to this server.
     pub async fn grpc_client(&self) -> SshxServiceClient<Channel> {
         SshxServiceClient::connect(self.endpoint()).await.unwrap()
@@ -81,122 +125,105 @@ impl Drop for TestServer {
     }
 }
 
-/// A WebSocket client that interacts with the server, used for testing.
-pub struct ClientSocket {
-    inner: WebSocketStream<MaybeTlsStream<TcpStream>>,
-    encrypt: Encrypt,
-    write_encrypt: Option<Encrypt>,
-
-    pub user_id: Uid,
-    pub users: BTreeMap<Uid, WsUser>,
-    pub shells: BTreeMap<Sid, WsWinsize>,
-    pub data: HashMap<Sid, String>,
-    pub messages: Vec<(Uid, String, String)>,
-    pub errors: Vec<String>,
+/// Returns a request to open a session, from a client of the given protocol
+/// version.
+pub fn open_request(protocol_version: u32) -> OpenRequest {
+    OpenRequest {
+        origin: "sshx.io".into(),
+        encrypted_zeros: Encrypt::new("").zeros().into(),
+        name: String::new(),
+        write_password_hash: Vec::new().into(),
+        compression: Vec::new(),
+        protocol_version,
+    }
+}
+
+/// Returns the metadata of a session named "test", for creating it directly in
+/// the server state.
+pub fn metadata(key: &str, compression: Compression) -> Metadata {
+    Metadata {
+        encrypted_zeros: Encrypt::new(key).zeros().into(),
+        name: "test".into(),
+        write_password_hash: None,
+        encryption_key: None,
+        hostname: "localhost".into(),
+        cpu: "Unknown".into(),
+        memory_mb: 0,
+        os_info: "Unknown OS".into(),
+        compression,
+    }
 }
 
-impl ClientSocket {
-    /// Connect to a WebSocket endpoint.
-    pub async fn connect(uri: &str, key: &str, write_password: Option<&str>) -> Result<Self> {
-        let (stream, resp) = tokio_tungstenite::connect_async(uri).await?;
-        ensure!(resp.status() == StatusCode::SWITCHING_PROTOCOLS);
-
-        let mut this = Self {
-            inner: stream,
-            encrypt: Encrypt::new(key),
-            write_encrypt: write_password.map(Encrypt::new),
-            user_id: Uid(0),
-            users: BTreeMap::new(),
-            shells: BTreeMap::new(),
-            data: HashMap::new(),
-            messages: Vec::new(),
-            errors: Vec::new(),
-        };
-        this.authenticate().await;
-        Ok(this)
-    }
-
-    async fn authenticate(&mut self) {
-        let encrypted_zeros = self.encrypt.zeros().into();
-        let write_zeros = self.write_encrypt.as_ref().map(|e| e.zeros().into());
-
-        self.send(WsClient::Authenticate(encrypted_zeros, write_zeros))
-            .await;
-    }
-
-    pub async fn send(&mut self, msg: WsClient) {
-        let mut buf = Vec::new();
-        ciborium::ser::into_writer(&msg, &mut buf).unwrap();
-        self.inner.send(Message::Binary(buf.into())).await.unwrap();
-    }
-
-    pub async fn send_input(&mut self, id: Sid, data: &[u8]) {
-        let offset = 42; // arbitrary, don't reuse the offset in real code though
-        let data = self.encrypt.segment(0x200000000, offset, data);
-        self.send(WsClient::Data(id, data.into(), offset)).await;
-    }
-
-    async fn recv(&mut self) -> Option<WsServer> {
-        loop {
-            match self.inner.next().await.transpose().unwrap() {
-                Some(Message::Text(_)) => panic!("unexpected text message over WebSocket"),
-                Some(Message::Binary(msg)) => {
-                    break Some(ciborium::de::from_reader(&*msg).unwrap())
-                }
-                Some(_) => (), // ignore other message types, keep looping
-                None => break None,
-            }
-        }
+/// Create an empty temporary directory for a test, named after it.
+pub fn temp_dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("sshx-{test}-{}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Time to wait for in-flight messages between the server and clients.
+const FLUSH_DURATION: Duration = Duration::from_millis(50);
+
+/// Wait for in-flight messages to be delivered and processed.
+pub async fn flush() {
+    time::sleep(FLUSH_DURATION).await;
+}
+
+/// Returns the number of output messages sent to a viewer while a shell makes
+/// `writes` single-byte writes, one every millisecond.
+pub async fn count_chunk_messages(options: ServerOptions, writes: u64) -> u64 {
+    let server = TestServer::with_options(options).await;
+    let state = server.state();
+    let session = Arc::new(state.new_session(metadata("key", Compression::None)));
+    state.insert("test", Arc::clone(&session));
+    session.add_shell(Sid(1), (0, 0)).unwrap();
+
+    let s = SessionClient::connect(&server.ws_endpoint("test"), "key", None)
+        .await
+        .unwrap();
+    let mut output = s.subscribe(Sid(1), 0).await.unwrap();
+    flush().await;
+    let before = state.metrics().chunk_messages.get();
+
+    for seqnum in 0..writes {
+        let data = Bytes::from_static(b"x");
+        session.add_data(Sid(1), data, seqnum).unwrap();
+        time::sleep(Duration::from_millis(1)).await;
     }
+    let mut received = 0;
+    while received < writes {
+        received += output.next().await.unwrap().len() as u64;
+    }
+    state.metrics().chunk_messages.get() - before
+}
 
-    pub async fn expect_close(&mut self, code: u16) {
-        let msg = self.inner.next().await.unwrap().unwrap();
-        match msg {
-            Message::Close(Some(frame)) => assert!(frame.code == code.into()),
-            _ => panic!("unexpected non-close message over WebSocket: {:?}", msg),
+/// Append the output of a subscribed shell to `text`, until it goes quiet.
+pub async fn read_output(output: &mut ShellOutput, text: &mut String) {
+    let read_task = async {
+        while let Some(data) = output.next().await {
+            text.push_str(std::str::from_utf8(&data).unwrap());
         }
-    }
+    };
+    time::timeout(FLUSH_DURATION, read_task).await.ok();
+}
 
-    pub async fn flush(&mut self) {
-        const FLUSH_DURATION: Duration = Duration::from_millis(50);
-        let flush_task = async {
-            while let Some(msg) = self.recv().await {
-                match msg {
-                    WsServer::Hello(user_id, _) => self.user_id = user_id,
-                    WsServer::InvalidAuth() => panic!("invalid authentication"),
-                    WsServer::Users(users) => self.users = BTreeMap::from_iter(users),
-                    WsServer::UserDiff(id, maybe_user) => {
-                        self.users.remove(&id);
-                        if let Some(user) = maybe_user {
-                            self.users.insert(id, user);
-                        }
-                    }
-                    WsServer::Shells(shells) => self.shells = BTreeMap::from_iter(shells),
-                    WsServer::Chunks(id, seqnum, chunks) => {
-                        let value = self.data.entry(id).or_default();
-                        assert_eq!(seqnum, value.len() as u64);
-                        for buf in chunks {
-                            let plaintext = self.encrypt.segment(
-                                0x100000000 | id.0 as u64,
-                                value.len() as u64,
-                                &buf,
-                            );
-                            value.push_str(std::str::from_utf8(&plaintext).unwrap());
-                        }
-                    }
-                    WsServer::Hear(id, name, msg) => {
-                        self.messages.push((id, name, msg));
-                    }
-                    WsServer::ShellLatency(_) => {}
-                    WsServer::Pong(_) => {}
-                    WsServer::Error(err) => self.errors.push(err),
-                }
-            }
-        };
-        time::timeout(FLUSH_DURATION, flush_task).await.ok();
+/// Take all events received so far, without waiting.
+pub fn take_events(events: &mut broadcast::Receiver<Event>) -> Vec<Event> {
+    let mut received = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        received.push(event);
     }
+    received
+}
 
-    pub fn read(&self, id: Sid) -> &str {
-        self.data.get(&id).map(|s| &**s).unwrap_or("")
-    }
+/// Take the error messages among all events received so far.
+pub fn take_errors(events: &mut broadcast::Receiver<Event>) -> Vec<String> {
+    take_events(events)
+        .into_iter()
+        .filter_map(|event| match event {
+            Event::Error(err) => Some(err),
+            _ => None,
+        })
+        .collect()
 }
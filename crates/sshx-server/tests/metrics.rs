@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use sshx::{controller::Controller, runner::Runner};
+use sshx_core::Sid;
+use sshx_server::{web::protocol::WsClient, Server, ServerOptions};
+use sshx_web_client::SessionClient;
+use tokio::net::TcpListener;
+
+use crate::common::*;
+
+pub mod common;
+
+/// Returns the value of a metric in the text format, if it is present.
+fn metric(text: &str, name: &str) -> Option<f64> {
+    text.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .and_then(|value| value.parse().ok())
+}
+
+#[tokio::test]
+async fn test_metrics() -> Result<()> {
+    // Sessions are only snapshotted when there is a persistent store.
+    let mut options = ServerOptions::default();
+    options.storage_path = Some(temp_dir("metrics").join("sessions.redb"));
+    let server = Arc::new(Server::new(options)?);
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await?;
+    let metrics_url = format!("http://{}/metrics", metrics_listener.local_addr()?);
+    tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.listen_tcp(listener).await }
+    });
+    tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.listen_metrics(metrics_listener).await }
+    });
+
+    let text = reqwest::get(&metrics_url).await?.text().await?;
+    assert_eq!(metric(&text, "sshx_sessions"), Some(0.0));
+    assert_eq!(metric(&text, "sshx_grpc_streams"), Some(0.0));
+
+    let mut controller = Controller::new(&endpoint, "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let ws_endpoint = format!("{}/api/s/{name}", endpoint.replace("http", "ws"));
+    let s = SessionClient::connect(&ws_endpoint, &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+    s.send_input(Sid(1), b"hello!").await?;
+    read_output(&mut output, &mut String::new()).await;
+
+    let resp = reqwest::get(&metrics_url).await?;
+    assert!(resp.status().is_success());
+    let text = resp.text().await?;
+    assert_eq!(metric(&text, "sshx_sessions"), Some(1.0));
+    assert_eq!(metric(&text, "sshx_web_users"), Some(1.0));
+    assert_eq!(metric(&text, "sshx_grpc_streams"), Some(1.0));
+    assert_eq!(metric(&text, "sshx_session_shells_count"), Some(1.0));
+    assert_eq!(metric(&text, "sshx_session_shells_sum"), Some(1.0));
+    assert!(metric(&text, "sshx_data_bytes_total").unwrap() > 0.0);
+    assert!(metric(&text, "sshx_snapshot_bytes_count").unwrap() >= 1.0);
+    assert_eq!(metric(&text, "sshx_proxy_redirects_total"), Some(0.0));
+
+    // Metrics are not served on the public listener.
+    let text = reqwest::get(format!("{endpoint}/metrics"))
+        .await?
+        .text()
+        .await?;
+    assert!(!text.contains("sshx_sessions"));
+
+    server.shutdown();
+    Ok(())
+}
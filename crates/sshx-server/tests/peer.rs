@@ -0,0 +1,101 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::Result;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use sshx::{controller::Controller, runner::Runner};
+use sshx_server::{state::storage::MemoryStore, Server, ServerOptions};
+use sshx_web_client::{ConnectionClosed, SessionClient};
+use tokio::net::TcpListener;
+
+use crate::common::*;
+
+pub mod common;
+
+/// Write a CA certificate, and a certificate for `localhost` signed by it.
+///
+/// Returns the paths to the CA bundle, the certificate and its private key.
+fn write_certs(test: &str) -> Result<(PathBuf, PathBuf, PathBuf)> {
+    let ca_key = KeyPair::generate()?;
+    let mut ca_params = CertificateParams::new(Vec::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key)?;
+
+    let key = KeyPair::generate()?;
+    let cert = CertificateParams::new(vec!["localhost".into()])?.signed_by(&key, &ca, &ca_key)?;
+
+    let dir = temp_dir(test);
+    std::fs::write(dir.join("ca.pem"), ca.pem())?;
+    std::fs::write(dir.join("cert.pem"), cert.pem())?;
+    std::fs::write(dir.join("key.pem"), key.serialize_pem())?;
+    Ok((
+        dir.join("ca.pem"),
+        dir.join("cert.pem"),
+        dir.join("key.pem"),
+    ))
+}
+
+#[tokio::test]
+async fn test_tls_proxy() -> Result<()> {
+    let (ca_path, cert_path, key_path) = write_certs("tls-proxy")?;
+    let storage = MemoryStore::new();
+
+    // The session is owned by a server that only accepts TLS connections.
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let owner_host = format!("localhost:{}", listener.local_addr()?.port());
+    let mut options = ServerOptions::default();
+    options.mesh_secret = Some("mesh secret".into());
+    let origin_options = options.clone();
+    options.tls_cert = Some(cert_path);
+    options.tls_key = Some(key_path);
+    let owner = Server::with_storage(options, Arc::new(storage.with_host(&owner_host)))?;
+    let owner = TestServer::with_listener(owner, listener).await;
+
+    let origin_store = Arc::new(storage.with_host("origin"));
+    let origin = TestServer::with_storage(origin_options, origin_store).await;
+    let controller = Controller::new(&origin.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name();
+    let key = controller.encryption_key();
+    flush().await;
+    owner.state().backend_connect(name).await?.unwrap();
+    flush().await;
+    origin.state().lookup(name).unwrap().sync_now();
+    flush().await;
+    assert!(origin.state().lookup(name).is_none());
+
+    // Proxy with TLS verified by the CA bundle, and the correct mesh secret.
+    let mut options = ServerOptions::default();
+    options.peer_tls = true;
+    options.peer_ca = Some(ca_path.clone());
+    options.mesh_secret = Some("mesh secret".into());
+    let proxy = TestServer::with_storage(options, Arc::new(storage.with_host("proxy"))).await;
+    let s = SessionClient::connect(&proxy.ws_endpoint(name), key, None).await?;
+    flush().await;
+    assert!(s.can_write());
+
+    // Proxy with the wrong mesh secret, which is rejected by the owner.
+    let mut options = ServerOptions::default();
+    options.peer_tls = true;
+    options.peer_ca = Some(ca_path.clone());
+    options.mesh_secret = Some("wrong secret".into());
+    let proxy = TestServer::with_storage(options, Arc::new(storage.with_host("proxy"))).await;
+    let err = SessionClient::connect(&proxy.ws_endpoint(name), key, None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.downcast_ref::<ConnectionClosed>().unwrap().code, 4500);
+
+    // Proxy that does not trust the self-signed certificate authority.
+    let mut options = ServerOptions::default();
+    options.peer_tls = true;
+    options.mesh_secret = Some("mesh secret".into());
+    let proxy = TestServer::with_storage(options, Arc::new(storage.with_host("proxy"))).await;
+    let err = SessionClient::connect(&proxy.ws_endpoint(name), key, None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(err.downcast_ref::<ConnectionClosed>().unwrap().code, 4500);
+
+    std::fs::remove_dir_all(ca_path.parent().unwrap())?;
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use anyhow::{Context, Result};
+use sshx::{controller::Controller, runner::Runner};
+use sshx_core::PROTOCOL_VERSION;
+use sshx_server::{web::protocol::WsClient, ServerOptions};
+use sshx_web_client::{ConnectionClosed, SessionClient};
+use tokio::time::{self, Duration};
+use tonic::Code;
+
+use crate::common::*;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_open_rate_limit() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.open_per_minute = 2;
+    let server = TestServer::with_options(options).await;
+    let mut client = server.grpc_client().await;
+    let req = open_request(PROTOCOL_VERSION);
+
+    client.open(req.clone()).await?;
+    client.open(req.clone()).await?;
+    let status = client.open(req).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_limit() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.max_sessions = 1;
+    let server = TestServer::with_options(options).await;
+    let mut client = server.grpc_client().await;
+    let req = open_request(PROTOCOL_VERSION);
+
+    let name = client.open(req.clone()).await?.into_inner().name;
+    let status = client.open(req.clone()).await.unwrap_err();
+    assert_eq!(status.code(), Code::ResourceExhausted);
+
+    // Closing a session makes room for another.
+    server.state().close_session(&name).await?;
+    client.open(req).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket_rate_limit() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.websocket_per_minute = 1;
+    let server = TestServer::with_options(options).await;
+
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let err = SessionClient::connect(&server.ws_endpoint(&name), &key, None)
+        .await
+        .err()
+        .context("connected despite the rate limit")?;
+    let closed = err.downcast_ref::<ConnectionClosed>().unwrap();
+    assert_eq!(closed.code, 4429);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auth_backoff() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let err = SessionClient::connect(&server.ws_endpoint(&name), "wrong key", None)
+        .await
+        .err()
+        .context("connected with the wrong key")?;
+    assert!(err.downcast_ref::<ConnectionClosed>().is_none());
+
+    // Even the right key is rejected until the backoff has passed.
+    let err = SessionClient::connect(&server.ws_endpoint(&name), &key, None)
+        .await
+        .err()
+        .context("connected during the backoff")?;
+    let closed = err.downcast_ref::<ConnectionClosed>().unwrap();
+    assert_eq!(closed.code, 4429);
+
+    // The first failure only has to wait for one second.
+    time::sleep(Duration::from_millis(1100)).await;
+    SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shell_limit() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.max_shells = 1;
+    let server = TestServer::with_options(options).await;
+
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let mut events = s.events();
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    assert_eq!(s.shells().len(), 1);
+    assert!(take_errors(&mut events).is_empty());
+
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    assert_eq!(s.shells().len(), 1);
+    assert_eq!(take_errors(&mut events).len(), 1);
+    Ok(())
+}
@@ -0,0 +1,279 @@
+//! Tests against a locally started `redis-server`, which are skipped if it is
+//! not installed.
+
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use sshx_server::state::mesh::{RedisConfig, StorageMesh};
+use sshx_server::state::storage::SessionStore;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::{Child, Command};
+use tokio::time;
+use tokio_stream::StreamExt;
+
+/// A `redis-server` process for testing, which is killed when dropped.
+struct RedisProcess {
+    port: u16,
+    dir: PathBuf,
+    _child: Child,
+}
+
+impl RedisProcess {
+    /// Start `redis-server` on an unused port, in a fresh directory.
+    ///
+    /// The arguments are built from the port and directory. Returns `None` if
+    /// Redis is not installed, or exits because it does not support them, such
+    /// as TLS options when it was built without TLS.
+    async fn start(
+        name: &str,
+        args: impl FnOnce(u16, &Path) -> Result<Vec<String>>,
+    ) -> Result<Option<Self>> {
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let dir = std::env::temp_dir().join(format!("sshx-redis-{name}-{port}"));
+        std::fs::create_dir_all(&dir)?;
+
+        let child = Command::new("redis-server")
+            .args(args(port, &dir)?)
+            .args(["--bind", "127.0.0.1", "--save", "", "--appendonly", "no"])
+            .arg("--dir")
+            .arg(&dir)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn();
+        let Ok(mut child) = child else {
+            eprintln!("skipping test, redis-server is not installed");
+            return Ok(None);
+        };
+
+        for _ in 0..100 {
+            if child.try_wait()?.is_some() {
+                eprintln!("skipping test, redis-server exited on startup");
+                return Ok(None);
+            }
+            if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+                return Ok(Some(Self {
+                    port,
+                    dir,
+                    _child: child,
+                }));
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        bail!("redis-server did not start listening on port {port}");
+    }
+
+    /// Returns the URL of this server, with the given scheme.
+    fn url(&self, scheme: &str) -> String {
+        format!("{scheme}://127.0.0.1:{}", self.port)
+    }
+}
+
+impl Drop for RedisProcess {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Check that a store holds leases and snapshots, and delivers transfers.
+async fn check_store(store: &StorageMesh) -> Result<()> {
+    let epoch = store.acquire_lease("abc", 0).await?;
+    store.save("abc", epoch, b"snapshot".to_vec()).await?;
+    store.append_delta("abc", epoch, b"delta".to_vec()).await?;
+
+    let (owner, snapshot) = store.get_owner_snapshot("abc").await?;
+    assert_eq!(owner.as_deref(), store.host());
+    let snapshot = snapshot.unwrap();
+    assert_eq!(snapshot.snapshot, b"snapshot");
+    assert_eq!(snapshot.deltas, [b"delta"]);
+
+    let mut transfers = store.listen_for_transfers();
+    let notify = async {
+        // Wait for the subscription to be established before publishing.
+        time::sleep(Duration::from_millis(500)).await;
+        store.notify_transfer("abc", store.host().unwrap()).await
+    };
+    let (transfer, notified) = tokio::join!(
+        time::timeout(Duration::from_secs(5), transfers.next()),
+        notify
+    );
+    notified?;
+    assert_eq!(transfer?.as_deref(), Some("abc"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_acl_user() -> Result<()> {
+    let redis = RedisProcess::start("acl", |port, _| {
+        Ok([
+            "--port",
+            &port.to_string(),
+            "--user",
+            "default",
+            "off",
+            "--user",
+            "sshx",
+            "on",
+            ">hunter2",
+            "~*",
+            "&*",
+            "+@all",
+        ]
+        .map(String::from)
+        .into())
+    })
+    .await?;
+    let Some(redis) = redis else { return Ok(()) };
+
+    let mut config = RedisConfig::from_url(&redis.url("redis"));
+    config.username = Some("sshx".into());
+    config.password = Some("hunter2".into());
+    check_store(&StorageMesh::new(&config, Some("host"))?).await?;
+
+    config.password = Some("wrong".into());
+    let store = StorageMesh::new(&config, Some("host"))?;
+    assert!(store.acquire_lease("abc", 0).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sentinel() -> Result<()> {
+    let Some(master) = RedisProcess::start("master", |port, _| {
+        Ok(vec!["--port".into(), port.to_string()])
+    })
+    .await?
+    else {
+        return Ok(());
+    };
+
+    let master_port = master.port;
+    let sentinel = RedisProcess::start("sentinel", |port, dir| {
+        // Sentinel rewrites its configuration file, so it must be writable.
+        let conf = dir.join("sentinel.conf");
+        std::fs::write(
+            &conf,
+            format!("sentinel monitor primary 127.0.0.1 {master_port} 1\n"),
+        )?;
+        Ok(vec![
+            conf.to_string_lossy().into(),
+            "--sentinel".into(),
+            "--port".into(),
+            port.to_string(),
+        ])
+    })
+    .await?;
+    let Some(sentinel) = sentinel else {
+        return Ok(());
+    };
+
+    let mut config = RedisConfig::from_url(&sentinel.url("redis"));
+    config.sentinel_master = Some("primary".into());
+    check_store(&StorageMesh::new(&config, Some("host"))?).await?;
+
+    config.sentinel_master = Some("missing".into());
+    let store = StorageMesh::new(&config, Some("host"))?;
+    assert!(store.acquire_lease("abc", 0).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cluster() -> Result<()> {
+    let redis = RedisProcess::start("cluster", |port, _| {
+        Ok([
+            "--port",
+            &port.to_string(),
+            "--cluster-enabled",
+            "yes",
+            "--cluster-config-file",
+            "nodes.conf",
+            "--cluster-announce-ip",
+            "127.0.0.1",
+        ]
+        .map(String::from)
+        .into())
+    })
+    .await?;
+    let Some(redis) = redis else { return Ok(()) };
+
+    // Make a cluster of one node, which serves every hash slot.
+    let client = redis::Client::open(redis.url("redis"))?;
+    let mut conn = client.get_multiplexed_async_connection().await?;
+    let () = redis::cmd("CLUSTER")
+        .arg("ADDSLOTSRANGE")
+        .arg(0)
+        .arg(16383)
+        .query_async(&mut conn)
+        .await?;
+    for _ in 0..100 {
+        let info: String = redis::cmd("CLUSTER")
+            .arg("INFO")
+            .query_async(&mut conn)
+            .await?;
+        if info.contains("cluster_state:ok") {
+            break;
+        }
+        time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let mut config = RedisConfig::from_url(&redis.url("redis"));
+    config.cluster = true;
+    check_store(&StorageMesh::new(&config, Some("host"))?).await?;
+    Ok(())
+}
+
+/// Write a CA, and server and client certificates signed by it, to `dir`.
+fn write_certs(dir: &Path) -> Result<()> {
+    let ca_key = KeyPair::generate()?;
+    let mut ca_params = CertificateParams::new(Vec::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key)?;
+    std::fs::write(dir.join("ca.pem"), ca.pem())?;
+
+    for (name, sans) in [("server", vec!["127.0.0.1".into()]), ("client", vec![])] {
+        let key = KeyPair::generate()?;
+        let cert = CertificateParams::new(sans)?.signed_by(&key, &ca, &ca_key)?;
+        std::fs::write(dir.join(format!("{name}.pem")), cert.pem())?;
+        std::fs::write(dir.join(format!("{name}.key")), key.serialize_pem())?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_client_cert() -> Result<()> {
+    let redis = RedisProcess::start("tls", |port, dir| {
+        write_certs(dir)?;
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        Ok(vec![
+            "--port".into(),
+            "0".into(),
+            "--tls-port".into(),
+            port.to_string(),
+            "--tls-cert-file".into(),
+            path("server.pem"),
+            "--tls-key-file".into(),
+            path("server.key"),
+            "--tls-ca-cert-file".into(),
+            path("ca.pem"),
+            "--tls-auth-clients".into(),
+            "yes".into(),
+        ])
+    })
+    .await?;
+    let Some(redis) = redis else { return Ok(()) };
+
+    let mut config = RedisConfig::from_url(&redis.url("rediss"));
+    config.tls_ca = Some(redis.dir.join("ca.pem"));
+    config.tls_cert = Some(redis.dir.join("client.pem"));
+    config.tls_key = Some(redis.dir.join("client.key"));
+    check_store(&StorageMesh::new(&config, Some("host"))?).await?;
+
+    // The server rejects clients without a certificate.
+    config.tls_cert = None;
+    config.tls_key = None;
+    let store = StorageMesh::new(&config, Some("host"))?;
+    assert!(store.acquire_lease("abc", 0).await.is_err());
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use anyhow::{Context, Result};
+use sshx::{controller::Controller, runner::Runner};
+use sshx_core::crypto::{output_stream, Encrypt};
+use sshx_core::web::{WsClient, WsWinsize};
+use sshx_core::Sid;
+use sshx_server::{session::Record, ServerOptions};
+use sshx_web_client::{ConnectionClosed, SessionClient};
+use tokio::time::{self, Duration};
+
+use crate::common::*;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_replay() -> Result<()> {
+    let dir = temp_dir("replay");
+    let mut options = ServerOptions::default();
+    options.recording_dir = Some(dir.clone());
+    let server = TestServer::with_options(options).await;
+
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    s.send_input(Sid(1), b"hello").await?;
+    flush().await;
+    s.send_input(Sid(1), b" world").await?;
+    flush().await;
+
+    let r = SessionClient::connect(&server.replay_endpoint(&name), &key, None).await?;
+    flush().await;
+    assert!(!r.can_write());
+    assert_eq!(r.shells().len(), 1);
+
+    let mut output = r.subscribe(Sid(1), 0).await?;
+    let mut text = String::new();
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "hello world");
+
+    let mut events = r.events();
+    r.send_input(Sid(1), b"ignored").await?;
+    flush().await;
+    assert_eq!(take_errors(&mut events).len(), 1);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_auth() -> Result<()> {
+    let dir = temp_dir("replay-auth");
+    let mut options = ServerOptions::default();
+    options.recording_dir = Some(dir.clone());
+    let server = TestServer::with_options(options).await;
+
+    let controller = Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name();
+    flush().await;
+
+    let result = SessionClient::connect(&server.replay_endpoint(name), "wrong key", None).await;
+    assert!(result.is_err());
+
+    let err = SessionClient::connect(&server.replay_endpoint("foobar"), "", None)
+        .await
+        .err()
+        .context("connected to a missing recording")?;
+    let closed = err.downcast_ref::<ConnectionClosed>().unwrap();
+    assert_eq!(closed.code, 4404);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_rate_limit() -> Result<()> {
+    let dir = temp_dir("replay-rate-limit");
+    let mut options = ServerOptions::default();
+    options.recording_dir = Some(dir.clone());
+    options.limits.websocket_per_minute = 1;
+    let server = TestServer::with_options(options).await;
+
+    for code in [4404, 4429] {
+        let err = SessionClient::connect(&server.replay_endpoint("foobar"), "", None)
+            .await
+            .err()
+            .context("connected to a missing recording")?;
+        let closed = err.downcast_ref::<ConnectionClosed>().unwrap();
+        assert_eq!(closed.code, code);
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_gaps() -> Result<()> {
+    let dir = temp_dir("replay-gaps");
+    let mut options = ServerOptions::default();
+    options.recording_dir = Some(dir.clone());
+    let server = TestServer::with_options(options).await;
+
+    // A recording that fell behind, and dropped the output at seqnum 5.
+    let encrypt = Encrypt::new("key");
+    let data = |seqnum, text: &[u8]| {
+        let data = encrypt.segment(output_stream(Sid(1)), seqnum, text);
+        Record::Data(0, Sid(1), seqnum, data.into())
+    };
+    let records = [
+        Record::Header {
+            name: "gaps".into(),
+            encrypted_zeros: encrypt.zeros().into(),
+            compressed: false,
+        },
+        Record::Shells(0, vec![(Sid(1), WsWinsize::default())]),
+        data(0, b"hello"),
+        data(11, b"world"),
+        Record::Shells(1, vec![(Sid(1), WsWinsize::default())]),
+    ];
+    let mut buf = Vec::new();
+    for record in &records {
+        ciborium::ser::into_writer(record, &mut buf)?;
+    }
+    std::fs::write(dir.join("gaps.cbor"), buf)?;
+
+    // Tiny speeds are clamped, instead of overflowing the playback deadline.
+    let endpoint = server
+        .replay_endpoint("gaps")
+        .replace("speed=0", "speed=1e-20");
+    let r = SessionClient::connect(&endpoint, "key", None).await?;
+    flush().await;
+    let mut output = r.subscribe(Sid(1), 0).await?;
+    let mut text = String::new();
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "helloworld");
+    let closed = time::timeout(Duration::from_millis(100), r.closed()).await;
+    assert!(closed.is_err(), "replay should still be running");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_speed() -> Result<()> {
+    let dir = temp_dir("replay-speed");
+    let mut options = ServerOptions::default();
+    options.recording_dir = Some(dir.clone());
+    let server = TestServer::with_options(options).await;
+    let endpoint = server.replay_endpoint("foobar");
+
+    // Invalid speeds are rejected before the WebSocket upgrade.
+    for speed in ["-1", "NaN", "inf"] {
+        let url = endpoint.replace("speed=0", &format!("speed={speed}"));
+        let err = SessionClient::connect(&url, "", None).await.err().unwrap();
+        assert!(err.downcast_ref::<ConnectionClosed>().is_none());
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
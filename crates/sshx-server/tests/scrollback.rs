@@ -0,0 +1,177 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use bytes::Bytes;
+use sshx_core::{proto::Compression, Sid};
+use sshx_server::{session::Session, state::ServerState, ServerOptions};
+use tokio_stream::StreamExt;
+
+use crate::common::*;
+
+pub mod common;
+
+/// Add a shell with `count` chunks of 100 bytes each.
+fn add_shell(session: &Session, id: Sid, count: u64) -> Result<()> {
+    session.add_shell(id, (0, 0))?;
+    for i in 0..count {
+        session.add_data(id, Bytes::from(vec![b'a'; 100]), i * 100)?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scrollback_budget() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.scrollback_budget_bytes = 3000;
+    options.limits.shell_floor_bytes = 250;
+    let state = ServerState::new(options)?;
+
+    let session = Arc::new(state.new_session(metadata("key", Compression::None)));
+    state.insert("test", Arc::clone(&session));
+    add_shell(&session, Sid(1), 20)?;
+    add_shell(&session, Sid(2), 20)?;
+    add_shell(&session, Sid(3), 5)?;
+    assert_eq!(state.scrollback_bytes(), 4500);
+
+    // Shell 2 is being viewed, so shell 1 is the least recently viewed.
+    let mut viewer = Box::pin(session.subscribe_chunks(Sid(2), 0));
+    viewer.next().await;
+
+    assert_eq!(state.enforce_scrollback_budget(), 1800);
+    assert_eq!(state.scrollback_bytes(), 2700);
+    assert_eq!(state.enforce_scrollback_budget(), 0);
+
+    // New subscribers to the pruned shell only receive the remaining output.
+    let mut chunks = Box::pin(session.subscribe_chunks(Sid(1), 0));
+    let (seqnum, chunks) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 1800);
+    assert_eq!(chunks.len(), 2);
+
+    // Output continues from the same sequence number after pruning.
+    session.add_data(Sid(1), Bytes::from(vec![b'b'; 100]), 2000)?;
+    let mut chunks = Box::pin(session.subscribe_chunks(Sid(1), 20));
+    let (seqnum, chunks) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 2000);
+    assert_eq!(chunks, [Bytes::from(vec![b'b'; 100])]);
+
+    let shells = session.scrollback();
+    let bytes = |id| shells.iter().find(|s| s.id == id).unwrap().bytes;
+    assert_eq!(
+        (bytes(Sid(1)), bytes(Sid(2)), bytes(Sid(3))),
+        (300, 2000, 500)
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scrollback_unlimited() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.scrollback_budget_bytes = 0;
+    let state = ServerState::new(options)?;
+
+    let session = Arc::new(state.new_session(metadata("key", Compression::None)));
+    state.insert("test", Arc::clone(&session));
+    add_shell(&session, Sid(1), 50)?;
+    assert_eq!(state.enforce_scrollback_budget(), 0);
+    assert_eq!(state.scrollback_bytes(), 5000);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spill_to_disk() -> Result<()> {
+    let dir = temp_dir("scrollback");
+    let stale = dir.join("old.abc.scrollback");
+    std::fs::create_dir_all(&stale)?;
+
+    let mut options = ServerOptions::default();
+    options.scrollback_dir = Some(dir.clone());
+    options.limits.shell_stored_bytes = 500;
+    options.limits.shell_floor_bytes = 250;
+    let state = ServerState::new(options)?;
+    assert!(!stale.exists(), "stale output should be removed on start");
+
+    let session = Arc::new(state.new_session(metadata("key", Compression::None)));
+    state.insert("test", Arc::clone(&session));
+    session.add_shell(Sid(1), (0, 0))?;
+    for i in 0..20u8 {
+        let data = Bytes::from(vec![b'a' + i; 100]);
+        session.add_data(Sid(1), data, i as u64 * 100)?;
+    }
+    assert_eq!(state.scrollback_bytes(), 500);
+
+    // Pruned output stays readable in memory until it has been written.
+    let mut chunks = Box::pin(session.subscribe_chunks(Sid(1), 0));
+    let (seqnum, pending) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 0);
+    assert_eq!(pending.len(), 20);
+    drop(chunks);
+    flush().await;
+
+    // Older output is read back from disk, followed by output in memory.
+    let mut chunks = Box::pin(session.subscribe_chunks(Sid(1), 0));
+    let (seqnum, spilled) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 0);
+    assert_eq!(spilled.len(), 15);
+    assert_eq!(spilled[3], Bytes::from(vec![b'd'; 100]));
+    let (seqnum, stored) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 1500);
+    assert_eq!(stored.len(), 5);
+    drop(chunks);
+
+    // Viewers can also start partway through the spilled output.
+    let mut chunks = Box::pin(session.subscribe_chunks(Sid(1), 12));
+    let (seqnum, spilled) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 1200);
+    assert_eq!(spilled[0], Bytes::from(vec![b'm'; 100]));
+    drop(chunks);
+
+    // Spilled output is deleted along with the session.
+    assert!(state.remove("test"));
+    drop(session);
+    flush().await;
+    assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spill_quota() -> Result<()> {
+    let dir = temp_dir("spill-quota");
+
+    let mut options = ServerOptions::default();
+    options.scrollback_dir = Some(dir.clone());
+    options.limits.shell_stored_bytes = 500;
+    options.limits.shell_floor_bytes = 250;
+    options.limits.shell_disk_bytes = 1000;
+    let state = ServerState::new(options)?;
+
+    let session = Arc::new(state.new_session(metadata("key", Compression::None)));
+    state.insert("test", Arc::clone(&session));
+    add_shell(&session, Sid(1), 20)?;
+    flush().await;
+
+    // Only the most recently spilled output that fits in the quota is kept.
+    let mut chunks = Box::pin(session.subscribe_chunks(Sid(1), 0));
+    let (seqnum, spilled) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 500);
+    assert_eq!(spilled.len(), 10);
+    let (seqnum, stored) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 1500);
+    assert_eq!(stored.len(), 5);
+    drop(chunks);
+
+    // Later writes go over the quota, so the whole segment is deleted, and
+    // only output in memory is left.
+    for i in 20..25 {
+        session.add_data(Sid(1), Bytes::from(vec![b'a'; 100]), i * 100)?;
+    }
+    flush().await;
+    let mut chunks = Box::pin(session.subscribe_chunks(Sid(1), 0));
+    let (seqnum, stored) = chunks.next().await.unwrap();
+    assert_eq!(seqnum, 2000);
+    assert_eq!(stored.len(), 5);
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use anyhow::Result;
-use sshx::encrypt::Encrypt;
-use sshx_core::proto::*;
+use sshx_core::proto::{client_update::ClientMessage, *};
+use sshx_core::{Sid, PROTOCOL_VERSION};
+use sshx_server::web::protocol::WsWinsize;
 
 use crate::common::*;
 
@@ -11,14 +12,48 @@ async fn test_rpc() -> Result<()> {
     let server = TestServer::new().await;
     let mut client = server.grpc_client().await;
 
-    let req = OpenRequest {
-        origin: "sshx.io".into(),
-        encrypted_zeros: Encrypt::new("").zeros().into(),
-        name: String::new(),
-        write_password_hash: Vec::new().into(),
+    let req = open_request(PROTOCOL_VERSION);
+    let resp = client.open(req).await?.into_inner();
+    assert!(!resp.name.is_empty());
+    assert_eq!(resp.compression(), Compression::None);
+    assert_eq!(resp.protocol_version, PROTOCOL_VERSION);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_channel_recovers_shells() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let req = open_request(PROTOCOL_VERSION);
+    let resp = client.open(req).await?.into_inner();
+
+    let shell = LiveShell {
+        id: 1,
+        x: 10,
+        y: -20,
+        rows: 30,
+        cols: 100,
+    };
+    let hello = ClientUpdate {
+        client_message: Some(ClientMessage::Hello(Hello {
+            name: resp.name.clone(),
+            token: resp.token,
+            shells: vec![shell],
+        })),
+    };
+    client.channel(tokio_stream::iter([hello])).await?;
+
+    // The shell is restored with the window it had before reconnecting.
+    let session = server.state().lookup(&resp.name).unwrap();
+    let winsize = WsWinsize {
+        x: 10,
+        y: -20,
+        rows: 30,
+        cols: 100,
     };
-    let resp = client.open(req).await?;
-    assert!(!resp.into_inner().name.is_empty());
+    assert_eq!(session.shells(), [(Sid(1), winsize)]);
 
     Ok(())
 }
@@ -5,8 +5,12 @@ use sshx::{controller::Controller, runner::Runner};
 use sshx_core::{Sid, Uid};
 use sshx_server::{
     session::Session,
+    state::file::FileStore,
+    state::storage::{LeaseLost, MemoryStore, SessionStore},
     web::protocol::{WsClient, WsWinsize},
+    ServerOptions,
 };
+use sshx_web_client::SessionClient;
 
 use crate::common::*;
 
@@ -16,17 +20,17 @@ pub mod common;
 async fn test_basic_restore() -> Result<()> {
     let server = TestServer::new().await;
 
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
     let name = controller.name().to_owned();
     let key = controller.encryption_key().to_owned();
     tokio::spawn(async move { controller.run().await });
 
-    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
-    s.flush().await;
-    assert_eq!(s.user_id, Uid(1));
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    assert_eq!(s.user_id(), Uid(1));
 
-    s.send(WsClient::Create(0, 0)).await;
-    s.flush().await;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
 
     let new_size = WsWinsize {
         x: 42,
@@ -35,11 +39,11 @@ async fn test_basic_restore() -> Result<()> {
         cols: 20,
     };
 
-    s.send_input(Sid(1), b"hello there!").await;
-    s.send_input(Sid(1), b" - another message").await;
-    s.send(WsClient::Move(Sid(1), Some(new_size))).await;
-    s.flush().await;
-    assert!(s.shells.contains_key(&Sid(1)));
+    s.send_input(Sid(1), b"hello there!").await?;
+    s.send_input(Sid(1), b" - another message").await?;
+    s.send(WsClient::Move(Sid(1), Some(new_size))).await?;
+    flush().await;
+    assert!(s.shells().iter().any(|&(id, _)| id == Sid(1)));
 
     // Replace the shell with its snapshot.
     let data = server.state().lookup(&name).unwrap().snapshot()?;
@@ -47,12 +51,206 @@ async fn test_basic_restore() -> Result<()> {
         .state()
         .insert(&name, Arc::new(Session::restore(&data)?));
 
-    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
-    s.send(WsClient::Subscribe(Sid(1), 0)).await;
-    s.flush().await;
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+    let mut text = String::new();
+    read_output(&mut output, &mut text).await;
 
-    assert_eq!(s.read(Sid(1)), "hello there! - another message");
-    assert_eq!(s.shells.get(&Sid(1)).unwrap(), &new_size);
+    assert_eq!(text, "hello there! - another message");
+    assert_eq!(s.shells(), [(Sid(1), new_size)]);
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_delta_restore() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    s.send_input(Sid(1), b"first").await?;
+    flush().await;
+
+    let session = server.state().lookup(&name).unwrap();
+    let (base, position) = session.snapshot_position()?;
+
+    s.send_input(Sid(1), b" second").await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    let (delta1, position) = session.snapshot_delta(&position)?;
+
+    s.send_input(Sid(2), b"other").await?;
+    s.send_input(Sid(1), b" third").await?;
+    flush().await;
+    let (delta2, _) = session.snapshot_delta(&position)?;
+
+    // Each delta only contains data since the previous sync.
+    assert!(delta1.len() < session.snapshot()?.len());
+
+    let restored = Session::restore_with_deltas(&base, &[delta1, delta2])?;
+    server.state().insert(&name, Arc::new(restored));
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    flush().await;
+    assert_eq!(s.shells().len(), 2);
+    for (id, expected) in [(Sid(1), "first second third"), (Sid(2), "other")] {
+        let mut output = s.subscribe(id, 0).await?;
+        let mut text = String::new();
+        read_output(&mut output, &mut text).await;
+        assert_eq!(text, expected);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_storage_restore() -> Result<()> {
+    let dir = temp_dir("storage");
+    let mut options = ServerOptions::default();
+    options.storage_path = Some(dir.join("sessions.redb"));
+    let server = TestServer::with_options(options).await;
+
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    s.send_input(Sid(1), b"hello from disk").await?;
+    flush().await;
+
+    // New connections trigger a sync of the session into storage.
+    let _s2 = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    flush().await;
+
+    // Drop the session from memory, so it must be restored from the file.
+    assert!(server.state().remove(&name));
+    let session = server.state().backend_connect(&name).await?;
+    assert!(session.is_some());
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+    let mut text = String::new();
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "hello from disk");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_closed_session_fence() -> Result<()> {
+    let dir = temp_dir("fence");
+    let stores: [Arc<dyn SessionStore>; 2] = [
+        Arc::new(MemoryStore::new()),
+        Arc::new(FileStore::open(&dir.join("sessions.redb"))?),
+    ];
+
+    for storage in stores {
+        let stale = storage.acquire_lease("fenced", 0).await?;
+        storage.save("fenced", stale, b"old".to_vec()).await?;
+        let owner = storage.acquire_lease("fenced", stale).await?;
+        storage.mark_closed("fenced").await?;
+
+        // The closed marker keeps the newer epoch, so the old owner is fenced.
+        let err = storage.save("fenced", stale, b"old".to_vec()).await;
+        assert!(err.unwrap_err().is::<LeaseLost>());
+        storage.save("fenced", owner, b"new".to_vec()).await?;
+    }
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_state_dir_restart() -> Result<()> {
+    let dir = temp_dir("state");
+    let mut options = ServerOptions::default();
+    options.state_dir = Some(dir.clone());
+
+    let server = TestServer::with_options(options.clone()).await;
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    let handle = tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    s.send_input(Sid(1), b"survives restarts").await?;
+    flush().await;
+    handle.abort();
+    drop(server); // Saves the session on shutdown.
+
+    let server = TestServer::with_options(options).await;
+    flush().await;
+    assert!(server.state().lookup(&name).is_some());
+    assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+    let mut text = String::new();
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "survives restarts");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lease_takeover() -> Result<()> {
+    let storage: Arc<dyn SessionStore> = Arc::new(MemoryStore::new());
+    let server1 = TestServer::with_storage(Default::default(), Arc::clone(&storage)).await;
+    let server2 = TestServer::with_storage(Default::default(), Arc::clone(&storage)).await;
+
+    let controller = Controller::new(&server1.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name();
+    flush().await;
+    let session1 = server1.state().lookup(name).unwrap();
+    assert_eq!(session1.epoch(), 1);
+
+    // Take over the session on the second server.
+    let session2 = server2.state().backend_connect(name).await?.unwrap();
+    flush().await;
+    assert_eq!(session2.epoch(), 2);
+
+    // The first server is fenced off on its next sync, and stops serving it.
+    session1.sync_now();
+    flush().await;
+    assert!(server1.state().lookup(name).is_none());
+    assert!(server2.state().lookup(name).is_some());
+
+    let (_, stored) = storage.get_owner_snapshot(name).await?;
+    assert_eq!(stored.unwrap().restore()?.epoch(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_state_dir_bad_snapshot() -> Result<()> {
+    let dir = temp_dir("bad-state");
+    std::fs::write(dir.join("broken.snapshot"), b"not a snapshot")?;
+    let mut options = ServerOptions::default();
+    options.state_dir = Some(dir.clone());
+
+    // The server still starts, and keeps the snapshot that failed to restore.
+    let server = TestServer::with_options(options).await;
+    flush().await;
+    assert!(server.state().lookup("broken").is_none());
+    assert!(!dir.join("broken.snapshot").exists());
+    assert!(dir.join("broken.snapshot.failed").exists());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,106 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Result;
+use sshx_core::proto::sshx_service_client::SshxServiceClient;
+use sshx_core::PROTOCOL_VERSION;
+use sshx_server::{Server, ServerOptions};
+use tokio::net::TcpListener;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig};
+
+use crate::common::*;
+
+pub mod common;
+
+/// Write a self-signed certificate for `localhost` to files in `dir`.
+///
+/// Returns the PEM certificate, which clients can trust directly.
+fn write_cert(dir: &Path) -> Result<String> {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".into()])?;
+    std::fs::write(dir.join("cert.pem"), generated.cert.pem())?;
+    std::fs::write(dir.join("key.pem"), generated.key_pair.serialize_pem())?;
+    Ok(generated.cert.pem())
+}
+
+fn tls_options(dir: &Path) -> ServerOptions {
+    let mut options = ServerOptions::default();
+    options.tls_cert = Some(dir.join("cert.pem"));
+    options.tls_key = Some(dir.join("key.pem"));
+    options
+}
+
+/// Send an Open() RPC over TLS, trusting only the given certificate.
+async fn open_session(port: u16, cert: &str) -> Result<String> {
+    let tls = ClientTlsConfig::new()
+        .ca_certificate(Certificate::from_pem(cert))
+        .domain_name("localhost");
+    let channel = Channel::from_shared(format!("https://localhost:{port}"))?
+        .tls_config(tls)?
+        .connect()
+        .await?;
+    let req = open_request(PROTOCOL_VERSION);
+    let resp = SshxServiceClient::new(channel).open(req).await?;
+    Ok(resp.into_inner().name)
+}
+
+#[tokio::test]
+async fn test_tls_grpc_and_http() -> Result<()> {
+    let dir = temp_dir("tls-serve");
+    let cert = write_cert(&dir)?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let server = TestServer::with_listener(Server::new(tls_options(&dir))?, listener).await;
+
+    let name = open_session(port, &cert).await?;
+    assert!(server.state().lookup(&name).is_some());
+
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(cert.as_bytes())?)
+        .build()?;
+    let resp = client
+        .get(format!("https://localhost:{port}"))
+        .send()
+        .await?;
+    assert!(!resp.status().is_server_error());
+
+    // Plain HTTP is not accepted on a TLS port.
+    assert!(reqwest::get(format!("http://localhost:{port}"))
+        .await
+        .is_err());
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tls_reload() -> Result<()> {
+    let dir = temp_dir("tls-reload");
+    let old_cert = write_cert(&dir)?;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let port = listener.local_addr()?.port();
+    let server = Arc::new(Server::new(tls_options(&dir))?);
+    tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.listen_tcp(listener).await }
+    });
+
+    open_session(port, &old_cert).await?;
+
+    // The new certificate is only served after reloading.
+    let new_cert = write_cert(&dir)?;
+    assert!(open_session(port, &new_cert).await.is_err());
+    server.reload_tls()?;
+    open_session(port, &new_cert).await?;
+    assert!(open_session(port, &old_cert).await.is_err());
+
+    // Invalid files are rejected, and the previous certificate is kept.
+    std::fs::write(dir.join("key.pem"), "not a key")?;
+    assert!(server.reload_tls().is_err());
+    open_session(port, &new_cert).await?;
+
+    server.shutdown();
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use anyhow::{Context, Result};
+use sshx::{controller::Controller, runner::Runner, transport::Transport};
+use sshx_core::proto::{client_update::ClientMessage, ClientUpdate, Hello};
+use sshx_core::{Sid, PROTOCOL_VERSION};
+use sshx_server::{web::protocol::WsClient, ServerOptions};
+use sshx_web_client::SessionClient;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use tonic::Code;
+
+use crate::common::*;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_websocket_fallback() -> Result<()> {
+    let server = TestServer::new().await;
+    let proxy = server.http1_proxy().await;
+
+    let mut controller = Controller::new(&proxy, "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+
+    s.send_input(Sid(1), b"hello over websocket").await?;
+    let mut text = String::new();
+    while text.len() < 20 {
+        let data = time::timeout(Duration::from_secs(5), output.next()).await?;
+        text.push_str(std::str::from_utf8(&data.context("output ended")?)?);
+    }
+    assert_eq!(text, "hello over websocket");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_websocket_status() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.min_protocol_version = 1;
+    let server = TestServer::with_options(options).await;
+    let mut client = Transport::WebSocket(server.endpoint());
+
+    // Errors from the server keep their status code and message.
+    let status = client.open(open_request(0)).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert!(status.message().contains("please upgrade sshx"));
+
+    let resp = client.open(open_request(PROTOCOL_VERSION)).await?;
+    let hello = ClientUpdate {
+        client_message: Some(ClientMessage::Hello(Hello {
+            name: resp.name,
+            token: "invalid".into(),
+            shells: Vec::new(),
+        })),
+    };
+    let (tx, rx) = mpsc::channel(1);
+    tx.send(hello).await?;
+    let mut updates = client.channel(ReceiverStream::new(rx)).await?;
+    let status = updates.next().await.context("missing status")?.unwrap_err();
+    assert_eq!(status.code(), Code::Unauthenticated);
+    Ok(())
+}
@@ -0,0 +1,38 @@
+#![cfg(unix)]
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use sshx_server::{Server, ServerOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::common::*;
+
+pub mod common;
+
+#[tokio::test]
+async fn test_unix_socket() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("sshx-unix-{}.sock", std::process::id()));
+    _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+
+    let server = Arc::new(Server::new(ServerOptions::default())?);
+    tokio::spawn({
+        let server = Arc::clone(&server);
+        async move { server.listen_unix(listener).await }
+    });
+    flush().await;
+
+    let mut stream = UnixStream::connect(&path).await?;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut resp = Vec::new();
+    stream.read_to_end(&mut resp).await?;
+    assert!(resp.starts_with(b"HTTP/1.1 "));
+
+    server.shutdown();
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
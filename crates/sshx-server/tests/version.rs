@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures_util::SinkExt;
+use serde::Serialize;
+use sshx::{controller::Controller, runner::Runner};
+use sshx_core::proto::{client_update::ClientMessage, ClientUpdate, Compression};
+use sshx_core::{compress::inflate, crypto::Encrypt, Sid, PROTOCOL_VERSION};
+use sshx_server::web::protocol::{WsClient, WsServer, WsWinsize};
+use sshx_server::ServerOptions;
+use sshx_web_client::{Event, SessionClient};
+use tokio_stream::StreamExt;
+use tokio_tungstenite::tungstenite::Message;
+use tonic::Code;
+
+use crate::common::*;
+
+pub mod common;
+
+/// Authentication message sent by clients before protocol version 1.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+enum LegacyWsClient {
+    Authenticate(Bytes, Option<Bytes>),
+}
+
+/// Authenticate as a client from before protocol version 1, returning the
+/// server's reply after its hello message.
+async fn legacy_connect(endpoint: &str, key: &str) -> Result<Message> {
+    let (mut socket, _) = tokio_tungstenite::connect_async(endpoint).await?;
+    let auth = LegacyWsClient::Authenticate(Encrypt::new(key).zeros().into(), None);
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(&auth, &mut buf)?;
+    socket.send(Message::Binary(buf.into())).await?;
+
+    let hello = socket.next().await.context("missing hello")??.into_data();
+    match ciborium::de::from_reader(&*hello)? {
+        WsServer::Hello(_, _, _, version) => assert_eq!(version, PROTOCOL_VERSION),
+        msg => panic!("expected hello message, got {msg:?}"),
+    }
+    Ok(socket.next().await.context("connection ended")??)
+}
+
+fn close_code(msg: &Message) -> Option<u16> {
+    match msg {
+        Message::Close(Some(frame)) => Some(frame.code.into()),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn test_open_outdated() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.min_protocol_version = 1;
+    let server = TestServer::with_options(options).await;
+    let mut client = server.grpc_client().await;
+
+    let status = client.open(open_request(0)).await.unwrap_err();
+    assert_eq!(status.code(), Code::FailedPrecondition);
+    assert!(status.message().contains("please upgrade sshx"));
+
+    // Current clients are still accepted.
+    Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_legacy_viewer() -> Result<()> {
+    let server = TestServer::new().await;
+    let state = server.state();
+    let plain = state.new_session(metadata("key", Compression::None));
+    state.insert("plain", Arc::new(plain));
+    let compressed = state.new_session(metadata("key", Compression::Deflate));
+    state.insert("compressed", Arc::new(compressed));
+
+    // Old viewers can still authenticate, unless they need to decompress.
+    let msg = legacy_connect(&server.ws_endpoint("plain"), "key").await?;
+    let msg = ciborium::de::from_reader(&*msg.into_data())?;
+    assert!(matches!(msg, WsServer::Users(_)), "got {msg:?}");
+
+    let msg = legacy_connect(&server.ws_endpoint("compressed"), "key").await?;
+    assert_eq!(close_code(&msg), Some(4426));
+    SessionClient::connect(&server.ws_endpoint("compressed"), "key", None).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_min_version_viewer() -> Result<()> {
+    let mut options = ServerOptions::default();
+    options.limits.min_protocol_version = 1;
+    let server = TestServer::with_options(options).await;
+    let state = server.state();
+    let session = state.new_session(metadata("key", Compression::None));
+    state.insert("test", Arc::new(session));
+
+    let msg = legacy_connect(&server.ws_endpoint("test"), "key").await?;
+    let Message::Close(Some(frame)) = msg else {
+        panic!("expected close frame, got {msg:?}");
+    };
+    assert_eq!(u16::from(frame.code), 4426);
+    assert!(frame.reason.contains("please upgrade sshx"));
+
+    SessionClient::connect(&server.ws_endpoint("test"), "key", None).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_legacy_channel_hello() -> Result<()> {
+    let server = TestServer::new().await;
+    let mut client = server.grpc_client().await;
+
+    let resp = client.open(open_request(0)).await?.into_inner();
+
+    // Old clients only send the IDs of their shells.
+    let hello = format!("{},{};2,5", resp.name, resp.token);
+    let hello = ClientUpdate {
+        client_message: Some(ClientMessage::LegacyHello(hello)),
+    };
+    client.channel(tokio_stream::iter([hello])).await?;
+
+    let session = server.state().lookup(&resp.name).unwrap();
+    let winsize = WsWinsize::default();
+    assert_eq!(session.shells(), [(Sid(2), winsize), (Sid(5), winsize)]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_deflated_messages() -> Result<()> {
+    let server = TestServer::new().await;
+    let state = server.state();
+    let session = state.new_session(metadata("key", Compression::None));
+    state.insert("test", Arc::new(session));
+    let text = "a long chat message, ".repeat(100);
+
+    // Large messages are only compressed for viewers that accept them.
+    for version in [1, PROTOCOL_VERSION] {
+        let (mut socket, _) = tokio_tungstenite::connect_async(server.ws_endpoint("test")).await?;
+        let auth = WsClient::Authenticate(Encrypt::new("key").zeros().into(), None, version);
+        let chat = WsClient::Chat(text.clone());
+        for msg in [auth, chat] {
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(&msg, &mut buf)?;
+            socket.send(Message::Binary(buf.into())).await?;
+        }
+        let data = loop {
+            let msg = socket.next().await.context("connection ended")??;
+            match ciborium::de::from_reader(&*msg.into_data())? {
+                WsServer::Hear(_, _, msg) => break msg,
+                WsServer::Deflated(data) => {
+                    assert!(data.len() < text.len() / 10);
+                    match ciborium::de::from_reader(&*inflate(&data)?)? {
+                        WsServer::Hear(_, _, msg) if version > 1 => break msg,
+                        msg => panic!("unexpected compressed message {msg:?}"),
+                    }
+                }
+                _ => {}
+            }
+        };
+        assert_eq!(data, text);
+    }
+
+    let s = SessionClient::connect(&server.ws_endpoint("test"), "key", None).await?;
+    let mut events = s.events();
+    s.chat(&text).await?;
+    match events.recv().await? {
+        Event::Chat(id, _, msg) => assert_eq!((id, msg), (s.user_id(), text)),
+        event => panic!("expected chat message, got {event:?}"),
+    }
+    Ok(())
+}
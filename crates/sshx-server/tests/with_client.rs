@@ -1,11 +1,22 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
 use anyhow::{Context, Result};
-use sshx::{controller::Controller, encrypt::Encrypt, runner::Runner};
+use sshx::{controller::Controller, runner::Runner};
 use sshx_core::{
-    proto::{server_update::ServerMessage, NewShell, TerminalInput},
+    crypto::{Encrypt, INPUT_STREAM},
+    proto::{server_update::ServerMessage, Compression, DrainRequest, NewShell, TerminalInput},
     Sid, Uid,
 };
-use sshx_server::web::protocol::{WsClient, WsWinsize};
+use sshx_server::{
+    grpc::drain_token,
+    state::storage::{MemoryStore, SessionStore},
+    web::protocol::{WsClient, WsWinsize},
+    ServerOptions,
+};
+use sshx_web_client::{ConnectionClosed, Event, SessionClient};
 use tokio::time::{self, Duration};
+use tokio_stream::StreamExt;
 
 use crate::common::*;
 
@@ -14,7 +25,11 @@ pub mod common;
 #[tokio::test]
 async fn test_handshake() -> Result<()> {
     let server = TestServer::new().await;
-    let controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let controller = Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+
+    // Output is only compressed when the client asks for it.
+    let session = server.state().lookup(controller.name()).unwrap();
+    assert_eq!(session.metadata().compression, Compression::None);
     controller.close().await?;
     Ok(())
 }
@@ -23,7 +38,7 @@ async fn test_handshake() -> Result<()> {
 async fn test_command() -> Result<()> {
     let server = TestServer::new().await;
     let runner = Runner::Shell("/bin/bash".into());
-    let mut controller = Controller::new(&server.endpoint(), "", runner, false).await?;
+    let mut controller = Controller::new(&server.endpoint(), "", runner, false, false).await?;
 
     let session = server
         .state()
@@ -39,7 +54,7 @@ async fn test_command() -> Result<()> {
     let offset = 4242;
     let data = TerminalInput {
         id: 1,
-        data: encrypt.segment(0x200000000, offset, b"ls\r\n").into(),
+        data: encrypt.segment(INPUT_STREAM, offset, b"ls\r\n").into(),
         offset,
     };
     updates.send(ServerMessage::Input(data)).await?;
@@ -57,12 +72,16 @@ async fn test_ws_missing() -> Result<()> {
     let server = TestServer::new().await;
 
     let bad_endpoint = format!("ws://{}/not/an/endpoint", server.local_addr());
-    assert!(ClientSocket::connect(&bad_endpoint, "", None)
+    assert!(SessionClient::connect(&bad_endpoint, "", None)
         .await
         .is_err());
 
-    let mut s = ClientSocket::connect(&server.ws_endpoint("foobar"), "", None).await?;
-    s.expect_close(4404).await;
+    let err = SessionClient::connect(&server.ws_endpoint("foobar"), "", None)
+        .await
+        .err()
+        .context("connected to a missing session")?;
+    let closed = err.downcast_ref::<ConnectionClosed>().unwrap();
+    assert_eq!(closed.code, 4404);
 
     Ok(())
 }
@@ -71,31 +90,68 @@ async fn test_ws_missing() -> Result<()> {
 async fn test_ws_basic() -> Result<()> {
     let server = TestServer::new().await;
 
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
     let name = controller.name().to_owned();
     let key = controller.encryption_key().to_owned();
     tokio::spawn(async move { controller.run().await });
 
-    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
-    s.flush().await;
-    assert_eq!(s.user_id, Uid(1));
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    assert_eq!(s.user_id(), Uid(1));
+
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    let shells = s.shells();
+    assert_eq!(shells.len(), 1);
+    assert_eq!(shells[0].0, Sid(1));
+
+    let mut output = s.subscribe(Sid(1), 0).await?;
+    let mut text = String::new();
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "");
 
-    s.send(WsClient::Create(0, 0)).await;
-    s.flush().await;
-    assert_eq!(s.shells.len(), 1);
-    assert!(s.shells.contains_key(&Sid(1)));
+    s.send_input(Sid(1), b"hello!").await?;
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "hello!");
 
-    s.send(WsClient::Subscribe(Sid(1), 0)).await;
-    assert_eq!(s.read(Sid(1)), "");
+    s.send_input(Sid(1), b" 123").await?;
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "hello! 123");
 
-    s.send_input(Sid(1), b"hello!").await;
-    s.flush().await;
-    assert_eq!(s.read(Sid(1)), "hello!");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compressed_output() -> Result<()> {
+    let server = TestServer::new().await;
 
-    s.send_input(Sid(1), b" 123").await;
-    s.flush().await;
-    assert_eq!(s.read(Sid(1)), "hello! 123");
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false, true).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
 
+    let session = server.state().lookup(&name).unwrap();
+    assert_eq!(session.metadata().compression, Compression::Deflate);
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+
+    let input = "compiling sshx v0.4.1\n".repeat(100);
+    s.send_input(Sid(1), input.as_bytes()).await?;
+    s.send_input(Sid(1), b"done").await?;
+    let expected = input + "done";
+    let mut text = String::new();
+    while text.len() < expected.len() {
+        let data = time::timeout(Duration::from_secs(5), output.next()).await?;
+        text.push_str(std::str::from_utf8(&data.context("output ended")?)?);
+    }
+    assert_eq!(text, expected);
+
+    // The server only stores the compressed output.
+    let stored = session.scrollback()[0].bytes;
+    assert!(stored < 200, "stored {stored} bytes of output");
     Ok(())
 }
 
@@ -103,21 +159,24 @@ async fn test_ws_basic() -> Result<()> {
 async fn test_ws_resize() -> Result<()> {
     let server = TestServer::new().await;
 
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
     let name = controller.name().to_owned();
     let key = controller.encryption_key().to_owned();
     tokio::spawn(async move { controller.run().await });
 
-    let mut s = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let mut events = s.events();
 
-    s.send(WsClient::Move(Sid(1), None)).await; // error: does not exist yet!
-    s.flush().await;
-    assert_eq!(s.errors.len(), 1);
+    s.send(WsClient::Move(Sid(1), None)).await?; // error: does not exist yet!
+    flush().await;
+    assert_eq!(take_errors(&mut events).len(), 1);
 
-    s.send(WsClient::Create(0, 0)).await;
-    s.flush().await;
-    assert_eq!(s.shells.len(), 1);
-    assert_eq!(*s.shells.get(&Sid(1)).unwrap(), WsWinsize::default());
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    let shells = BTreeMap::from_iter(s.shells());
+    assert_eq!(shells.len(), 1);
+    assert_eq!(*shells.get(&Sid(1)).unwrap(), WsWinsize::default());
 
     let new_size = WsWinsize {
         x: 42,
@@ -125,20 +184,21 @@ async fn test_ws_resize() -> Result<()> {
         rows: 200,
         cols: 20,
     };
-    s.send(WsClient::Move(Sid(1), Some(new_size))).await;
-    s.send(WsClient::Move(Sid(2), Some(new_size))).await; // error: does not exist
-    s.flush().await;
-    assert_eq!(s.shells.len(), 1);
-    assert_eq!(*s.shells.get(&Sid(1)).unwrap(), new_size);
-    assert_eq!(s.errors.len(), 2);
-
-    s.send(WsClient::Close(Sid(1))).await;
-    s.flush().await;
-    assert_eq!(s.shells.len(), 0);
-
-    s.send(WsClient::Move(Sid(1), None)).await; // error: shell was closed
-    s.flush().await;
-    assert_eq!(s.errors.len(), 3);
+    s.send(WsClient::Move(Sid(1), Some(new_size))).await?;
+    s.send(WsClient::Move(Sid(2), Some(new_size))).await?; // error: does not exist
+    flush().await;
+    let shells = BTreeMap::from_iter(s.shells());
+    assert_eq!(shells.len(), 1);
+    assert_eq!(*shells.get(&Sid(1)).unwrap(), new_size);
+    assert_eq!(take_errors(&mut events).len(), 1);
+
+    s.send(WsClient::Close(Sid(1))).await?;
+    flush().await;
+    assert_eq!(s.shells().len(), 0);
+
+    s.send(WsClient::Move(Sid(1), None)).await?; // error: shell was closed
+    flush().await;
+    assert_eq!(take_errors(&mut events).len(), 1);
 
     Ok(())
 }
@@ -147,27 +207,25 @@ async fn test_ws_resize() -> Result<()> {
 async fn test_users_join() -> Result<()> {
     let server = TestServer::new().await;
 
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
     let name = controller.name().to_owned();
     let key = controller.encryption_key().to_owned();
     tokio::spawn(async move { controller.run().await });
 
     let endpoint = server.ws_endpoint(&name);
-    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s1.flush().await;
-    assert_eq!(s1.users.len(), 1);
+    let s1 = SessionClient::connect(&endpoint, &key, None).await?;
+    assert_eq!(s1.users().len(), 1);
 
-    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s2.flush().await;
-    assert_eq!(s2.users.len(), 2);
+    let s2 = SessionClient::connect(&endpoint, &key, None).await?;
+    assert_eq!(s2.users().len(), 2);
 
     drop(s2);
-    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s3.flush().await;
-    assert_eq!(s3.users.len(), 2);
+    let s3 = SessionClient::connect(&endpoint, &key, None).await?;
+    assert_eq!(s3.users().len(), 2);
 
-    s1.flush().await;
-    assert_eq!(s1.users.len(), 2);
+    flush().await;
+    assert_eq!(s1.users().len(), 2);
 
     Ok(())
 }
@@ -176,21 +234,21 @@ async fn test_users_join() -> Result<()> {
 async fn test_users_metadata() -> Result<()> {
     let server = TestServer::new().await;
 
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
     let name = controller.name().to_owned();
     let key = controller.encryption_key().to_owned();
     tokio::spawn(async move { controller.run().await });
 
     let endpoint = server.ws_endpoint(&name);
-    let mut s = ClientSocket::connect(&endpoint, &key, None).await?;
-    s.flush().await;
-    assert_eq!(s.users.len(), 1);
-    assert_eq!(s.users.get(&s.user_id).unwrap().cursor, None);
-
-    s.send(WsClient::SetName("mr. foo".into())).await;
-    s.send(WsClient::SetCursor(Some((40, 524)))).await;
-    s.flush().await;
-    let user = s.users.get(&s.user_id).unwrap();
+    let s = SessionClient::connect(&endpoint, &key, None).await?;
+    assert_eq!(s.users().len(), 1);
+    assert_eq!(s.users().get(&s.user_id()).unwrap().cursor, None);
+
+    s.set_name("mr. foo").await?;
+    s.send(WsClient::SetCursor(Some((40, 524)))).await?;
+    flush().await;
+    let user = s.users().remove(&s.user_id()).unwrap();
     assert_eq!(user.name, "mr. foo");
     assert_eq!(user.cursor, Some((40, 524)));
 
@@ -201,30 +259,78 @@ async fn test_users_metadata() -> Result<()> {
 async fn test_chat_messages() -> Result<()> {
     let server = TestServer::new().await;
 
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, false).await?;
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
     let name = controller.name().to_owned();
     let key = controller.encryption_key().to_owned();
     tokio::spawn(async move { controller.run().await });
 
     let endpoint = server.ws_endpoint(&name);
-    let mut s1 = ClientSocket::connect(&endpoint, &key, None).await?;
-    let mut s2 = ClientSocket::connect(&endpoint, &key, None).await?;
+    let s1 = SessionClient::connect(&endpoint, &key, None).await?;
+    let s2 = SessionClient::connect(&endpoint, &key, None).await?;
+    let mut events1 = s1.events();
+    let mut events2 = s2.events();
 
-    s1.send(WsClient::SetName("billy".into())).await;
-    s1.send(WsClient::Chat("hello there!".into())).await;
-    s1.flush().await;
+    s1.set_name("billy").await?;
+    s1.chat("hello there!").await?;
+    flush().await;
 
-    s2.flush().await;
-    assert_eq!(s2.messages.len(), 1);
     assert_eq!(
-        s2.messages[0],
-        (s1.user_id, "billy".into(), "hello there!".into())
+        take_events(&mut events2),
+        [Event::Chat(
+            s1.user_id(),
+            "billy".into(),
+            "hello there!".into()
+        )],
     );
 
-    let mut s3 = ClientSocket::connect(&endpoint, &key, None).await?;
-    s3.flush().await;
-    assert_eq!(s1.messages.len(), 1);
-    assert_eq!(s3.messages.len(), 0);
+    let s3 = SessionClient::connect(&endpoint, &key, None).await?;
+    let mut events3 = s3.events();
+    flush().await;
+    assert_eq!(take_events(&mut events1).len(), 1);
+    assert_eq!(take_events(&mut events3).len(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_lagged_resync() -> Result<()> {
+    let server = TestServer::new().await;
+
+    let mut controller =
+        Controller::new(&server.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    let mut events = s.events();
+
+    // Overflow the broadcast channel before the connection can keep up.
+    let session = server.state().lookup(&name).unwrap();
+    for i in 0..100 {
+        session.send_chat(s.user_id(), &format!("message {i}"))?;
+    }
+    flush().await;
+
+    // The viewer is resynchronized instead of being disconnected.
+    assert_eq!(server.state().metrics().lagged_resyncs.get(), 1);
+    assert!(take_events(&mut events).len() < 100);
+    assert_eq!(s.shells().len(), 1);
+    assert_eq!(s.users().len(), 1);
+
+    s.chat("still connected").await?;
+    flush().await;
+    assert_eq!(
+        take_events(&mut events),
+        [Event::Chat(
+            s.user_id(),
+            "User 1".into(),
+            "still connected".into()
+        )],
+    );
 
     Ok(())
 }
@@ -234,7 +340,7 @@ async fn test_read_write_permissions() -> Result<()> {
     let server = TestServer::new().await;
 
     // create controller with read-only mode enabled
-    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, true).await?;
+    let mut controller = Controller::new(&server.endpoint(), "", Runner::Echo, true, false).await?;
     let name = controller.name().to_owned();
     let key = controller.encryption_key().to_owned();
     let write_url = controller
@@ -250,36 +356,99 @@ async fn test_read_write_permissions() -> Result<()> {
         .expect("Write URL should contain password");
 
     // connect with write access
-    let mut writer =
-        ClientSocket::connect(&server.ws_endpoint(&name), &key, Some(write_password)).await?;
-    writer.flush().await;
+    let writer =
+        SessionClient::connect(&server.ws_endpoint(&name), &key, Some(write_password)).await?;
+    let mut writer_events = writer.events();
+    assert!(writer.can_write());
 
     // test write permissions
-    writer.send(WsClient::Create(0, 0)).await;
-    writer.flush().await;
+    writer.send(WsClient::Create(0, 0)).await?;
+    flush().await;
     assert_eq!(
-        writer.shells.len(),
+        writer.shells().len(),
         1,
         "Writer should be able to create a shell"
     );
-    assert!(writer.errors.is_empty(), "Writer should not receive errors");
+    assert!(
+        take_errors(&mut writer_events).is_empty(),
+        "Writer should not receive errors"
+    );
 
     // connect with read-only access
-    let mut reader = ClientSocket::connect(&server.ws_endpoint(&name), &key, None).await?;
-    reader.flush().await;
+    let reader = SessionClient::connect(&server.ws_endpoint(&name), &key, None).await?;
+    let mut reader_events = reader.events();
+    assert!(!reader.can_write());
 
     // test read-only restrictions
-    reader.send(WsClient::Create(0, 0)).await;
-    reader.flush().await;
+    reader.send(WsClient::Create(0, 0)).await?;
+    flush().await;
     assert!(
-        !reader.errors.is_empty(),
+        !take_errors(&mut reader_events).is_empty(),
         "Reader should receive an error when attempting to create shell"
     );
     assert_eq!(
-        reader.shells.len(),
+        reader.shells().len(),
         1,
         "Reader should still see the existing shell"
     );
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_drain() -> Result<()> {
+    let storage: Arc<dyn SessionStore> = Arc::new(MemoryStore::new());
+    let mut options = ServerOptions::default();
+    options.secret = Some("drain secret".into());
+    let server1 = TestServer::with_storage(options.clone(), Arc::clone(&storage)).await;
+    let server2 = TestServer::with_storage(options, Arc::clone(&storage)).await;
+
+    let mut controller =
+        Controller::new(&server1.endpoint(), "", Runner::Echo, false, false).await?;
+    let name = controller.name().to_owned();
+    let key = controller.encryption_key().to_owned();
+    tokio::spawn(async move { controller.run().await });
+
+    let s = SessionClient::connect(&server1.ws_endpoint(&name), &key, None).await?;
+    s.send(WsClient::Create(0, 0)).await?;
+    flush().await;
+    s.send_input(Sid(1), b"hello").await?;
+    flush().await;
+
+    let mut client = server1.grpc_client().await;
+    let req = DrainRequest {
+        origin: server2.endpoint(),
+        token: drain_token("wrong secret", &server2.endpoint()),
+    };
+    assert!(client.drain(req).await.is_err());
+
+    let req = DrainRequest {
+        origin: server2.endpoint(),
+        token: drain_token("drain secret", &server2.endpoint()),
+    };
+    let resp = client.drain(req).await?.into_inner();
+    assert_eq!(resp.sessions, 1);
+    assert!(resp.skipped.is_empty());
+
+    // The client moves to the second server, which takes over the session.
+    for _ in 0..20 {
+        if server2.state().lookup(&name).is_some() {
+            break;
+        }
+        flush().await;
+    }
+    assert!(server2.state().lookup(&name).is_some());
+    flush().await;
+    server1.state().lookup(&name).unwrap().sync_now();
+    flush().await;
+    assert!(server1.state().lookup(&name).is_none());
+
+    let s = SessionClient::connect(&server2.ws_endpoint(&name), &key, None).await?;
+    let mut output = s.subscribe(Sid(1), 0).await?;
+    s.send_input(Sid(1), b" world").await?;
+    let mut text = String::new();
+    read_output(&mut output, &mut text).await;
+    assert_eq!(text, "hello world");
+
+    Ok(())
+}
@@ -0,0 +1,447 @@
+//! Client library for joining sshx sessions over the web protocol.
+//!
+//! This connects to a session over WebSocket in the same way as the browser
+//! frontend, speaking the CBOR messages in [`sshx_core::web`] and handling
+//! end-to-end encryption with [`sshx_core::crypto`]. It is meant for
+//! automation like bots and recorders, as well as the `sshx join` command.
+//!
+//! Messages from the server are processed by a background task, which keeps
+//! track of users and shells, and forwards decrypted terminal output to any
+//! subscribed streams.
+
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures_util::{SinkExt, StreamExt};
+use sshx_core::compress::inflate;
+use sshx_core::crypto::{output_stream, Encrypt, INPUT_STREAM};
+use sshx_core::web::{WsClient, WsServer, WsUser, WsWinsize};
+use sshx_core::{Sid, Uid, PROTOCOL_VERSION};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
+use tokio::task::{self, JoinHandle};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tracing::{debug, warn};
+
+pub use crate::url::SessionUrl;
+
+mod url;
+
+/// Number of decrypted output segments buffered for each subscribed shell.
+///
+/// When a subscriber falls behind, the background task waits for it, which
+/// applies backpressure to the whole connection.
+const SHELL_OUTPUT_BUFFER: usize = 64;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Decrypted terminal output of a subscribed shell, in order.
+///
+/// The stream ends when the shell is closed or the connection is lost.
+pub type ShellOutput = ReceiverStream<Bytes>;
+
+/// Error returned when the server closes the WebSocket connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionClosed {
+    /// WebSocket close code, such as 4404 when the session does not exist.
+    pub code: u16,
+
+    /// Human-readable reason given by the server.
+    pub reason: String,
+}
+
+impl Display for ConnectionClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection closed ({}): {}", self.code, self.reason)
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
+impl ConnectionClosed {
+    fn from_frame(frame: Option<CloseFrame>) -> Self {
+        match frame {
+            Some(frame) => Self {
+                code: frame.code.into(),
+                reason: frame.reason.to_string(),
+            },
+            None => Self::abnormal("no close frame received"),
+        }
+    }
+
+    fn abnormal(reason: &str) -> Self {
+        Self {
+            code: CloseCode::Abnormal.into(),
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Notable messages from the session that are not tracked as state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// Chat message tuple `(uid, name, text)` sent to the room.
+    Chat(Uid, String, String),
+    /// Latency measurement between the server and the backend shell.
+    ShellLatency(u64),
+    /// Echoed timestamp from a previous [`WsClient::Ping`].
+    Pong(u64),
+    /// Application error sent by the server, usually in reply to a request.
+    Error(String),
+}
+
+/// An authenticated connection to an sshx session over the web protocol.
+///
+/// Dropping the client disconnects from the session.
+pub struct SessionClient {
+    encrypt: Encrypt,
+    user_id: Uid,
+    name: String,
+
+    /// Next offset into the input stream, randomized like the web client.
+    input_offset: AtomicU64,
+
+    outgoing: mpsc::Sender<WsClient>,
+    subscriptions: Arc<Mutex<HashMap<Sid, mpsc::Sender<Bytes>>>>,
+    users: watch::Receiver<BTreeMap<Uid, WsUser>>,
+    shells: watch::Receiver<Vec<(Sid, WsWinsize)>>,
+    events: broadcast::Sender<Event>,
+    closed: watch::Receiver<Option<ConnectionClosed>>,
+    task: JoinHandle<()>,
+}
+
+impl SessionClient {
+    /// Connect to a session from its web URL, which includes the key.
+    pub async fn connect_url(url: &str) -> Result<Self> {
+        let url = SessionUrl::parse(url)?;
+        Self::connect(&url.ws_endpoint, &url.key, url.write_password.as_deref()).await
+    }
+
+    /// Connect to a session's WebSocket endpoint and authenticate.
+    ///
+    /// Without a write password, the user is read-only if the session was
+    /// started with one. Fails with [`ConnectionClosed`] if the server rejects
+    /// the connection, for instance because the session does not exist.
+    pub async fn connect(endpoint: &str, key: &str, write_password: Option<&str>) -> Result<Self> {
+        let kdf_task = {
+            let key = key.to_string();
+            task::spawn_blocking(move || Encrypt::new(&key))
+        };
+        let kdf_write_task = write_password.map(|password| {
+            let password = password.to_string();
+            task::spawn_blocking(move || Encrypt::new(&password))
+        });
+
+        debug!(%endpoint, "connecting to session");
+        let (mut socket, _) = tokio_tungstenite::connect_async(endpoint)
+            .await
+            .with_context(|| format!("failed to connect to {endpoint}"))?;
+
+        let encrypt = kdf_task.await?;
+        let write_zeros = match kdf_write_task {
+            Some(task) => Some(task.await?.zeros().into()),
+            None => None,
+        };
+        let auth = WsClient::Authenticate(encrypt.zeros().into(), write_zeros, PROTOCOL_VERSION);
+        send(&mut socket, &auth).await?;
+
+        let (user_id, name, compressed) = match recv(&mut socket).await? {
+            WsServer::Hello(user_id, name, compressed, _) => (user_id, name, compressed),
+            msg => bail!("expected hello message, got {msg:?}"),
+        };
+        let users = match recv(&mut socket).await? {
+            WsServer::Users(users) => BTreeMap::from_iter(users),
+            WsServer::InvalidAuth() => bail!("invalid encryption key or write password"),
+            msg => bail!("expected users message, got {msg:?}"),
+        };
+
+        let (outgoing, outgoing_rx) = mpsc::channel(16);
+        let subscriptions = Arc::new(Mutex::new(HashMap::new()));
+        let (users_tx, users) = watch::channel(users);
+        let (shells_tx, shells) = watch::channel(Vec::new());
+        let (events, _) = broadcast::channel(64);
+        let (closed_tx, closed) = watch::channel(None);
+
+        let background = Background {
+            socket,
+            encrypt: encrypt.clone(),
+            compressed,
+            outgoing_rx,
+            subscriptions: Arc::clone(&subscriptions),
+            users_tx,
+            shells_tx,
+            events: events.clone(),
+        };
+        let task = tokio::spawn(async move {
+            let reason = background.run().await;
+            debug!(?reason, "session connection closed");
+            closed_tx.send_replace(Some(reason));
+        });
+
+        Ok(Self {
+            encrypt,
+            user_id,
+            name,
+            input_offset: AtomicU64::new(rand::random::<u64>() >> 1),
+            outgoing,
+            subscriptions,
+            users,
+            shells,
+            events,
+            closed,
+            task,
+        })
+    }
+
+    /// Returns the ID assigned to this user by the server.
+    pub fn user_id(&self) -> Uid {
+        self.user_id
+    }
+
+    /// Returns the name of the session.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether this user is allowed to write to the session.
+    pub fn can_write(&self) -> bool {
+        let users = self.users.borrow();
+        users.get(&self.user_id).is_some_and(|user| user.can_write)
+    }
+
+    /// Returns the users currently present in the session.
+    pub fn users(&self) -> BTreeMap<Uid, WsUser> {
+        self.users.borrow().clone()
+    }
+
+    /// Observe users joining, leaving, or updating their cursor and name.
+    pub fn watch_users(&self) -> watch::Receiver<BTreeMap<Uid, WsUser>> {
+        self.users.clone()
+    }
+
+    /// Returns the ordered list of open shells and their sizes.
+    pub fn shells(&self) -> Vec<(Sid, WsWinsize)> {
+        self.shells.borrow().clone()
+    }
+
+    /// Observe shells being opened, closed, moved or resized.
+    pub fn watch_shells(&self) -> watch::Receiver<Vec<(Sid, WsWinsize)>> {
+        self.shells.clone()
+    }
+
+    /// Receive chat messages, errors and other events from now on.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Resolves when the connection to the session has been lost.
+    pub async fn closed(&self) -> ConnectionClosed {
+        let mut closed = self.closed.clone();
+        let result = match closed.wait_for(Option::is_some).await {
+            Ok(reason) => reason.clone().unwrap(),
+            Err(_) => ConnectionClosed::abnormal("connection task exited"),
+        };
+        result
+    }
+
+    /// Subscribe to the decrypted output of a shell, from a chunk index.
+    ///
+    /// The server only accepts one subscription per shell on a connection.
+    pub async fn subscribe(&self, id: Sid, chunknum: u64) -> Result<ShellOutput> {
+        let (tx, rx) = mpsc::channel(SHELL_OUTPUT_BUFFER);
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+            if subscriptions.contains_key(&id) {
+                bail!("already subscribed to shell {id}");
+            }
+            subscriptions.insert(id, tx);
+        }
+        self.send(WsClient::Subscribe(id, chunknum)).await?;
+        Ok(ReceiverStream::new(rx))
+    }
+
+    /// Encrypt and send keyboard input to a shell.
+    pub async fn send_input(&self, id: Sid, data: &[u8]) -> Result<()> {
+        // Offsets into the input stream must never be reused.
+        let offset = self
+            .input_offset
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        let data = self.encrypt.segment(INPUT_STREAM, offset, data);
+        self.send(WsClient::Data(id, data.into(), offset)).await
+    }
+
+    /// Send a chat message to the room.
+    pub async fn chat(&self, msg: &str) -> Result<()> {
+        self.send(WsClient::Chat(msg.into())).await
+    }
+
+    /// Set the display name of this user.
+    pub async fn set_name(&self, name: &str) -> Result<()> {
+        self.send(WsClient::SetName(name.into())).await
+    }
+
+    /// Send a raw protocol message to the server.
+    pub async fn send(&self, msg: WsClient) -> Result<()> {
+        self.outgoing
+            .send(msg)
+            .await
+            .ok()
+            .context("connection to the session was closed")
+    }
+}
+
+impl Drop for SessionClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// State owned by the background task that drives the WebSocket.
+struct Background {
+    socket: Socket,
+    encrypt: Encrypt,
+    /// Whether terminal output is compressed before encryption.
+    compressed: bool,
+    outgoing_rx: mpsc::Receiver<WsClient>,
+    subscriptions: Arc<Mutex<HashMap<Sid, mpsc::Sender<Bytes>>>>,
+    users_tx: watch::Sender<BTreeMap<Uid, WsUser>>,
+    shells_tx: watch::Sender<Vec<(Sid, WsWinsize)>>,
+    events: broadcast::Sender<Event>,
+}
+
+impl Background {
+    /// Run until the connection is closed, returning the reason.
+    async fn run(mut self) -> ConnectionClosed {
+        loop {
+            tokio::select! {
+                result = recv(&mut self.socket) => match result {
+                    Ok(msg) => self.handle_message(msg).await,
+                    Err(err) => {
+                        self.subscriptions.lock().await.clear();
+                        return match err.downcast::<ConnectionClosed>() {
+                            Ok(closed) => closed,
+                            Err(err) => ConnectionClosed::abnormal(&err.to_string()),
+                        };
+                    }
+                },
+                Some(msg) = self.outgoing_rx.recv() => {
+                    if let Err(err) = send(&mut self.socket, &msg).await {
+                        warn!(?err, "failed to send message to session");
+                    }
+                }
+            }
+        }
+    }
+
+    async fn handle_message(&mut self, msg: WsServer) {
+        match msg {
+            WsServer::Hello(..) | WsServer::InvalidAuth() => {
+                warn!(?msg, "unexpected handshake message");
+            }
+            WsServer::Deflated(_) => {
+                warn!("ignoring nested compressed message");
+            }
+            WsServer::Users(users) => {
+                self.users_tx.send_replace(BTreeMap::from_iter(users));
+            }
+            WsServer::UserDiff(id, maybe_user) => {
+                self.users_tx.send_modify(|users| match maybe_user {
+                    Some(user) => _ = users.insert(id, user),
+                    None => _ = users.remove(&id),
+                });
+            }
+            WsServer::Shells(shells) => {
+                // Shells that are no longer open will never send more output.
+                let previous = self.shells_tx.send_replace(shells);
+                let current = self.shells_tx.borrow().clone();
+                let mut subscriptions = self.subscriptions.lock().await;
+                for (id, _) in previous {
+                    if !current.iter().any(|&(sid, _)| sid == id) {
+                        subscriptions.remove(&id);
+                    }
+                }
+            }
+            WsServer::Chunks(id, seqnum, chunks) => {
+                let Some(tx) = self.subscriptions.lock().await.get(&id).cloned() else {
+                    return;
+                };
+                let mut offset = seqnum;
+                for chunk in chunks {
+                    let mut data = self.encrypt.segment(output_stream(id), offset, &chunk);
+                    offset += chunk.len() as u64;
+                    if self.compressed {
+                        data = match inflate(&data) {
+                            Ok(data) => data,
+                            Err(err) => {
+                                warn!(%id, ?err, "failed to decompress shell output");
+                                self.subscriptions.lock().await.remove(&id);
+                                break;
+                            }
+                        };
+                    }
+                    if tx.send(data.into()).await.is_err() {
+                        // The subscriber has stopped listening.
+                        self.subscriptions.lock().await.remove(&id);
+                        break;
+                    }
+                }
+            }
+            WsServer::Hear(id, name, msg) => {
+                self.events.send(Event::Chat(id, name, msg)).ok();
+            }
+            WsServer::ShellLatency(latency) => {
+                self.events.send(Event::ShellLatency(latency)).ok();
+            }
+            WsServer::Pong(ts) => {
+                self.events.send(Event::Pong(ts)).ok();
+            }
+            WsServer::Error(err) => {
+                self.events.send(Event::Error(err)).ok();
+            }
+        }
+    }
+}
+
+/// Send a message to the server over WebSocket.
+async fn send(socket: &mut Socket, msg: &WsClient) -> Result<()> {
+    let mut buf = Vec::new();
+    ciborium::ser::into_writer(msg, &mut buf)?;
+    socket.send(Message::Binary(buf.into())).await?;
+    Ok(())
+}
+
+/// Receive a message from the server over WebSocket.
+///
+/// Fails with [`ConnectionClosed`] when the connection ends.
+async fn recv(socket: &mut Socket) -> Result<WsServer> {
+    loop {
+        match socket.next().await.transpose()? {
+            Some(Message::Binary(msg)) => return decode(&msg),
+            Some(Message::Close(frame)) => return Err(ConnectionClosed::from_frame(frame).into()),
+            Some(_) => (), // ignore other message types, keep looping
+            None => return Err(ConnectionClosed::abnormal("connection reset").into()),
+        }
+    }
+}
+
+/// Decode a message from the server, decompressing it if it was deflated.
+fn decode(msg: &[u8]) -> Result<WsServer> {
+    match ciborium::de::from_reader(msg)? {
+        WsServer::Deflated(data) => {
+            let msg = inflate(&data).context("failed to decompress message")?;
+            Ok(ciborium::de::from_reader(&*msg)?)
+        }
+        msg => Ok(msg),
+    }
+}
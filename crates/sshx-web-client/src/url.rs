@@ -0,0 +1,77 @@
+//! Parsing of session URLs shared by users, like the browser does.
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// Location and credentials of a session, parsed from its web URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionUrl {
+    /// WebSocket endpoint of the session on the server.
+    pub ws_endpoint: String,
+
+    /// End-to-end encryption key, from the URL fragment.
+    pub key: String,
+
+    /// Write password, if it follows the key in the URL fragment.
+    pub write_password: Option<String>,
+}
+
+impl SessionUrl {
+    /// Parse a URL of the form `https://{host}/s/{name}#{key}[,{password}]`.
+    pub fn parse(url: &str) -> Result<Self> {
+        let (base, fragment) = url
+            .split_once('#')
+            .context("URL is missing the #key fragment")?;
+        let (key, write_password) = match fragment.split_once(',') {
+            Some((key, password)) => (key, Some(password.to_string())),
+            None => (fragment, None),
+        };
+        ensure!(!key.is_empty(), "URL has an empty encryption key");
+
+        let (scheme, rest) = base.split_once("://").context("URL is missing a scheme")?;
+        let ws_scheme = match scheme {
+            "https" => "wss",
+            "http" => "ws",
+            _ => bail!("unsupported URL scheme: {scheme}"),
+        };
+        let (host, path) = rest.split_once('/').context("URL is missing a path")?;
+        let name = path
+            .strip_prefix("s/")
+            .context("URL path should look like /s/{name}")?
+            .trim_end_matches('/');
+        ensure!(
+            !name.is_empty() && !name.contains(['/', '?']),
+            "invalid session name in URL"
+        );
+
+        Ok(Self {
+            ws_endpoint: format!("{ws_scheme}://{host}/api/s/{name}"),
+            key: key.into(),
+            write_password,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SessionUrl;
+
+    #[test]
+    fn parse_url() {
+        let url = SessionUrl::parse("https://sshx.io/s/abc123#key").unwrap();
+        assert_eq!(url.ws_endpoint, "wss://sshx.io/api/s/abc123");
+        assert_eq!(url.key, "key");
+        assert_eq!(url.write_password, None);
+
+        let url = SessionUrl::parse("http://localhost:8051/s/abc123#key,pass").unwrap();
+        assert_eq!(url.ws_endpoint, "ws://localhost:8051/api/s/abc123");
+        assert_eq!(url.write_password.as_deref(), Some("pass"));
+    }
+
+    #[test]
+    fn parse_bad_url() {
+        assert!(SessionUrl::parse("https://sshx.io/s/abc123").is_err());
+        assert!(SessionUrl::parse("https://sshx.io/s/abc123#").is_err());
+        assert!(SessionUrl::parse("ftp://sshx.io/s/abc123#key").is_err());
+        assert!(SessionUrl::parse("https://sshx.io/abc123#key").is_err());
+    }
+}
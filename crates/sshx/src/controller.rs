@@ -3,21 +3,23 @@
 use std::collections::HashMap;
 use std::pin::pin;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use sshx_core::crypto::{Encrypt, INPUT_STREAM};
 use sshx_core::proto::{
-    client_update::ClientMessage, server_update::ServerMessage,
-    sshx_service_client::SshxServiceClient, ClientUpdate, CloseRequest, NewShell, OpenRequest,
+    client_update::ClientMessage, server_update::ServerMessage, ClientUpdate, CloseRequest,
+    Compression, Hello, LiveShell, NewShell, OpenRequest, OpenResponse,
 };
-use sshx_core::{rand_alphanumeric, Sid};
+use sshx_core::{rand_alphanumeric, Sid, PROTOCOL_VERSION};
 use tokio::sync::mpsc;
 use tokio::task;
 use tokio::time::{self, Duration, Instant, MissedTickBehavior};
 use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tonic::transport::Channel;
-use tracing::{debug, error, warn};
+use tonic::Code;
+use tracing::{debug, error, info, warn};
 
-use crate::encrypt::Encrypt;
+use crate::recorder::Recorder;
 use crate::runner::{Runner, ShellData};
+use crate::transport::Transport;
 
 /// Interval for sending empty heartbeat messages to the server.
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
@@ -29,9 +31,15 @@ const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
 pub struct Controller {
     origin: String,
     runner: Runner,
+
+    /// Whether to connect over WebSocket, after the gRPC handshake failed.
+    websocket: bool,
     encrypt: Encrypt,
     encryption_key: String,
 
+    /// Compression of terminal output, as chosen by the server.
+    compression: Compression,
+
     name: String,
     token: String,
     url: String,
@@ -40,8 +48,13 @@ pub struct Controller {
     /// Original system info string used for reconnection.
     system_info: String,
 
+    /// Writes a local recording of each shell's output, if enabled.
+    recorder: Option<Recorder>,
+
     /// Channels with backpressure routing messages to each shell task.
     shells_tx: HashMap<Sid, mpsc::Sender<ShellData>>,
+    /// Last known window of each shell, sent to recover them on reconnect.
+    live_shells: HashMap<Sid, LiveShell>,
     /// Channel shared with tasks to allow them to output client messages.
     output_tx: mpsc::Sender<ClientMessage>,
     /// Owned receiving end of the `output_tx` channel.
@@ -50,11 +63,16 @@ pub struct Controller {
 
 impl Controller {
     /// Construct a new controller, connecting to the remote server.
+    ///
+    /// If `compress` is set, the server may choose to deflate terminal output
+    /// before it is encrypted. This is off by default, since the length of
+    /// compressed output can reveal some of its contents.
     pub async fn new(
         origin: &str,
         name: &str,
         runner: Runner,
         enable_readers: bool,
+        compress: bool,
     ) -> Result<Self> {
         debug!(%origin, "connecting to server");
         let encryption_key = rand_alphanumeric(14); // 83.3 bits of entropy
@@ -75,10 +93,10 @@ impl Controller {
             (None, None)
         };
 
-        let mut client = Self::connect(origin).await?;
+        let mut client = Self::connect(origin, false).await;
         let encrypt = kdf_task.await?;
         let write_password_hash = if let Some(task) = kdf_write_password_task {
-            task.await?.zeros().into()
+            task.await?.zeros()
         } else {
             Vec::new()
         };
@@ -88,8 +106,14 @@ impl Controller {
             encrypted_zeros: encrypt.zeros().into(),
             name: format!("{}|{}", name, encryption_key),
             write_password_hash: write_password_hash.into(),
+            compression: if compress {
+                vec![Compression::Deflate.into()]
+            } else {
+                Vec::new()
+            },
+            protocol_version: PROTOCOL_VERSION,
         };
-        let mut resp = client.open(req).await?.into_inner();
+        let mut resp = open_session(&mut client, req).await?;
         resp.url = resp.url + "#" + &encryption_key;
 
         let write_url = if let Some(write_password) = write_password {
@@ -102,26 +126,35 @@ impl Controller {
         Ok(Self {
             origin: origin.into(),
             runner,
+            websocket: client.is_websocket(),
             encrypt,
             encryption_key,
+            compression: resp.compression(),
             name: resp.name,
             token: resp.token,
             url: resp.url,
             write_url,
             system_info: name.to_string(),
+            recorder: None,
             shells_tx: HashMap::new(),
+            live_shells: HashMap::new(),
             output_tx,
             output_rx,
         })
     }
 
-    /// Create a new gRPC client to the HTTP(S) origin.
+    /// Create a new client to the HTTP(S) origin, over WebSocket if gRPC did
+    /// not work before.
     ///
     /// This is used on reconnection to the server, since some replicas may be
     /// gracefully shutting down, which means connected clients need to start a
     /// new TCP handshake.
-    async fn connect(origin: &str) -> Result<SshxServiceClient<Channel>, tonic::transport::Error> {
-        SshxServiceClient::connect(String::from(origin)).await
+    async fn connect(origin: &str, websocket: bool) -> Transport {
+        if websocket {
+            Transport::WebSocket(String::from(origin))
+        } else {
+            Transport::connect(origin).await
+        }
     }
 
     /// Returns the name of the session.
@@ -144,6 +177,11 @@ impl Controller {
         &self.encryption_key
     }
 
+    /// Record the plaintext output of every shell opened from now on.
+    pub fn record(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
     /// Run the controller forever, listening for requests from the server.
     pub async fn run(&mut self) -> ! {
         let mut last_retry = Instant::now();
@@ -197,7 +235,7 @@ impl Controller {
     async fn recreate_session(&mut self, original_name: &str) -> Result<()> {
         debug!("recreating session with original name: {}", original_name);
         
-        let mut client = Self::connect(&self.origin).await?;
+        let mut client = Self::connect(&self.origin, self.websocket).await;
         
         let req = OpenRequest {
             origin: self.origin.clone(),
@@ -208,9 +246,16 @@ impl Controller {
                 self.encryption_key
             ),
             write_password_hash: Vec::new().into(),
+            // Shells keep sending output in the same way as before.
+            compression: vec![self.compression.into()],
+            protocol_version: PROTOCOL_VERSION,
         };
         
-        let resp = client.open(req).await?.into_inner();
+        let resp = open_session(&mut client, req).await?;
+        ensure!(
+            resp.compression() == self.compression,
+            "server does not support the session's compression"
+        );
         
         // Update session info
         self.name = resp.name.clone();
@@ -231,18 +276,15 @@ impl Controller {
     async fn try_channel(&mut self) -> Result<()> {
         let (tx, rx) = mpsc::channel(16);
 
-        let mut hello_str = format!("{},{}", self.name, self.token);
-        if !self.shells_tx.is_empty() {
-            let ids: Vec<String> = self.shells_tx.keys().map(|sid| sid.0.to_string()).collect();
-            hello_str.push(';');
-            hello_str.push_str(&ids.join(","));
-        }
-        let hello = ClientMessage::Hello(hello_str);
+        let hello = ClientMessage::Hello(Hello {
+            name: self.name.clone(),
+            token: self.token.clone(),
+            shells: self.live_shells.values().cloned().collect(),
+        });
         send_msg(&tx, hello).await?;
 
-        let mut client = Self::connect(&self.origin).await?;
-        let resp = client.channel(ReceiverStream::new(rx)).await?;
-        let mut messages = resp.into_inner(); // A stream of server messages.
+        let mut client = Self::connect(&self.origin, self.websocket).await;
+        let mut messages = client.channel(ReceiverStream::new(rx)).await?;
 
         let mut interval = time::interval(HEARTBEAT_INTERVAL);
         interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
@@ -270,7 +312,9 @@ impl Controller {
 
             match message {
                 ServerMessage::Input(input) => {
-                    let data = self.encrypt.segment(0x200000000, input.offset, &input.data);
+                    let data = self
+                        .encrypt
+                        .segment(INPUT_STREAM, input.offset, &input.data);
                     if let Some(sender) = self.shells_tx.get(&Sid(input.id)) {
                         // This line applies backpressure if the shell task is overloaded.
                         sender.send(ShellData::Data(data)).await.ok();
@@ -290,6 +334,7 @@ impl Controller {
                 ServerMessage::CloseShell(id) => {
                     // Closes the channel when it is dropped, notifying the task to shut down.
                     self.shells_tx.remove(&Sid(id));
+                    self.live_shells.remove(&Sid(id));
                     send_msg(&tx, ClientMessage::ClosedShell(id)).await?;
                 }
                 ServerMessage::Sync(seqnums) => {
@@ -303,6 +348,10 @@ impl Controller {
                     }
                 }
                 ServerMessage::Resize(msg) => {
+                    if let Some(shell) = self.live_shells.get_mut(&Sid(msg.id)) {
+                        (shell.x, shell.y) = (msg.x, msg.y);
+                        (shell.rows, shell.cols) = (msg.rows, msg.cols);
+                    }
                     if let Some(sender) = self.shells_tx.get(&Sid(msg.id)) {
                         sender.send(ShellData::Size(msg.rows, msg.cols)).await.ok();
                     } else {
@@ -316,6 +365,12 @@ impl Controller {
                 ServerMessage::Error(err) => {
                     error!(?err, "error received from server");
                 }
+                ServerMessage::Reconnect(reconnect) => {
+                    // The server is draining, so move to the peer right away.
+                    info!(origin = %reconnect.origin, "server asked to reconnect elsewhere");
+                    self.origin = reconnect.origin;
+                    return Ok(());
+                }
             }
         }
     }
@@ -325,10 +380,20 @@ impl Controller {
         let (shell_tx, shell_rx) = mpsc::channel(16);
         let opt = self.shells_tx.insert(id, shell_tx);
         debug_assert!(opt.is_none(), "shell ID cannot be in existing tasks");
+        let shell = LiveShell {
+            id: id.0,
+            x: center.0,
+            y: center.1,
+            rows: 24,
+            cols: 80,
+        };
+        self.live_shells.insert(id, shell);
 
         let runner = self.runner.clone();
         let encrypt = self.encrypt.clone();
+        let compression = self.compression;
         let output_tx = self.output_tx.clone();
+        let recorder = self.recorder.clone();
         tokio::spawn(async move {
             debug!(%id, "spawning new shell");
             let new_shell = NewShell {
@@ -340,7 +405,17 @@ impl Controller {
                 error!(%id, ?err, "failed to send shell creation message");
                 return;
             }
-            if let Err(err) = runner.run(id, encrypt, shell_rx, output_tx.clone()).await {
+            if let Err(err) = runner
+                .run(
+                    id,
+                    encrypt,
+                    compression,
+                    shell_rx,
+                    output_tx.clone(),
+                    recorder,
+                )
+                .await
+            {
                 let err = ClientMessage::Error(err.to_string());
                 output_tx.send(err).await.ok();
             }
@@ -355,7 +430,7 @@ impl Controller {
             name: self.name.clone(),
             token: self.token.clone(),
         };
-        let mut client = Self::connect(&self.origin).await?;
+        let mut client = Self::connect(&self.origin, self.websocket).await;
         client.close(req).await?;
         Ok(())
     }
@@ -370,3 +445,13 @@ async fn send_msg(tx: &mpsc::Sender<ClientUpdate>, message: ClientMessage) -> Re
         .await
         .context("failed to send message to server")
 }
+
+/// Open a session on the server, with a readable error if this client is too
+/// old for it.
+async fn open_session(client: &mut Transport, req: OpenRequest) -> Result<OpenResponse> {
+    match client.open(req).await {
+        Ok(resp) => Ok(resp),
+        Err(status) if status.code() == Code::FailedPrecondition => bail!("{}", status.message()),
+        Err(status) => Err(status.into()),
+    }
+}
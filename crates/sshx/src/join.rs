@@ -0,0 +1,268 @@
+//! Terminal-native viewer that joins an existing session over WebSocket.
+//!
+//! This uses the same protocol as the browser frontend, so the server does not
+//! distinguish between the two kinds of viewers. Output of one shell at a time
+//! is written to the local terminal in raw mode, and keystrokes are sent back
+//! to that shell.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::thread;
+
+use anyhow::{bail, Context, Result};
+use sshx_core::web::{WsClient, WsWinsize};
+use sshx_core::Sid;
+use sshx_web_client::{Event, SessionClient, ShellOutput};
+use tokio::io::{self, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::{StreamExt, StreamMap};
+
+/// Prefix byte for viewer commands, typed as Ctrl+].
+const ESCAPE_BYTE: u8 = 0x1d;
+
+/// Keep at most this much output per shell, for redrawing after a switch.
+const HISTORY_BYTES: usize = 1 << 20; // 1 MiB
+
+/// Help text printed before attaching to the session.
+const HELP: &str = "Press Ctrl+] followed by: n/p to switch shells, 1-9 to pick a shell, r to \
+                    resize the shell to this terminal, q to detach.";
+
+/// Join a session by its web URL, attaching the local terminal to its shells.
+///
+/// Returns when the user detaches or the session is closed.
+pub async fn join(url: &str) -> Result<()> {
+    let client = SessionClient::connect_url(url).await?;
+    let mut shells_rx = client.watch_shells();
+    let mut events = client.events();
+
+    eprintln!("{HELP}");
+    let _raw_mode = RawMode::enable()?;
+    let mut stdin_rx = spawn_stdin_reader();
+    let mut viewer = Viewer::new(&client);
+    viewer.update_shells().await?;
+
+    loop {
+        tokio::select! {
+            Ok(()) = shells_rx.changed() => viewer.update_shells().await?,
+            Some((id, data)) = viewer.outputs.next() => viewer.handle_output(id, &data).await?,
+            event = events.recv() => match event {
+                Ok(Event::Error(err)) => {
+                    let msg = format!("\r\n[sshx] error: {err}\r\n");
+                    viewer.write(msg.as_bytes()).await?;
+                }
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            Some(input) = stdin_rx.recv() => {
+                if !viewer.handle_input(&input).await? {
+                    break;
+                }
+            }
+            closed = client.closed() => bail!(closed),
+        }
+    }
+
+    Ok(())
+}
+
+/// State of the viewer, driven by session updates and local keystrokes.
+struct Viewer<'a> {
+    client: &'a SessionClient,
+    stdout: io::Stdout,
+
+    /// Ordered list of open shells and their sizes.
+    shells: Vec<(Sid, WsWinsize)>,
+    /// Decrypted output of every subscribed shell.
+    outputs: StreamMap<Sid, ShellOutput>,
+    /// Recent output of each shell, used to redraw the screen.
+    history: HashMap<Sid, Vec<u8>>,
+    /// The shell currently attached to the local terminal.
+    active: Option<Sid>,
+    /// Set after the escape byte was typed, awaiting a command.
+    escaped: bool,
+}
+
+impl<'a> Viewer<'a> {
+    fn new(client: &'a SessionClient) -> Self {
+        Self {
+            client,
+            stdout: io::stdout(),
+            shells: Vec::new(),
+            outputs: StreamMap::new(),
+            history: HashMap::new(),
+            active: None,
+            escaped: false,
+        }
+    }
+
+    /// Subscribe to new shells, and switch away from the active shell if it
+    /// was closed.
+    async fn update_shells(&mut self) -> Result<()> {
+        self.shells = self.client.shells();
+        for &(id, _) in &self.shells {
+            if !self.outputs.contains_key(&id) && !self.history.contains_key(&id) {
+                self.history.insert(id, Vec::new());
+                let output = self.client.subscribe(id, 0).await?;
+                self.outputs.insert(id, output);
+            }
+        }
+        let shells = &self.shells;
+        self.history
+            .retain(|id, _| shells.iter().any(|&(sid, _)| sid == *id));
+
+        match self.active {
+            Some(id) if self.position(id).is_some() => self.set_title().await,
+            _ => {
+                let first = self.shells.first().map(|&(id, _)| id);
+                self.activate(first).await
+            }
+        }
+    }
+
+    /// Record output from a shell, writing it out if the shell is active.
+    async fn handle_output(&mut self, id: Sid, data: &[u8]) -> Result<()> {
+        if self.active == Some(id) {
+            self.write(data).await?;
+        }
+        let history = self.history.entry(id).or_default();
+        history.extend_from_slice(data);
+        if history.len() > HISTORY_BYTES {
+            history.drain(..history.len() - HISTORY_BYTES);
+        }
+        Ok(())
+    }
+
+    /// Handle keystrokes from the local terminal. Returns `false` to detach.
+    async fn handle_input(&mut self, input: &[u8]) -> Result<bool> {
+        let mut data = Vec::with_capacity(input.len());
+        for &byte in input {
+            if !self.escaped {
+                if byte == ESCAPE_BYTE {
+                    self.escaped = true;
+                } else {
+                    data.push(byte);
+                }
+                continue;
+            }
+            self.escaped = false;
+            match byte {
+                ESCAPE_BYTE => data.push(byte), // Typed twice, send it literally.
+                b'q' | b'.' => return Ok(false),
+                b'n' => self.cycle(1).await?,
+                b'p' => self.cycle(self.shells.len().saturating_sub(1)).await?,
+                b'1'..=b'9' => {
+                    let index = (byte - b'1') as usize;
+                    if let Some(&(id, _)) = self.shells.get(index) {
+                        self.activate(Some(id)).await?;
+                    }
+                }
+                b'r' => self.resize_active().await?,
+                _ => {}
+            }
+        }
+
+        if let (Some(id), false) = (self.active, data.is_empty()) {
+            self.client.send_input(id, &data).await?;
+        }
+        Ok(true)
+    }
+
+    /// Index of a shell in the ordered list, if it is still open.
+    fn position(&self, id: Sid) -> Option<usize> {
+        self.shells.iter().position(|&(sid, _)| sid == id)
+    }
+
+    /// Move the active shell forward by some number of places, wrapping around.
+    async fn cycle(&mut self, by: usize) -> Result<()> {
+        if self.shells.is_empty() {
+            return Ok(());
+        }
+        let current = self.active.and_then(|id| self.position(id)).unwrap_or(0);
+        let (id, _) = self.shells[(current + by) % self.shells.len()];
+        self.activate(Some(id)).await
+    }
+
+    /// Attach the local terminal to a shell, redrawing it from history.
+    async fn activate(&mut self, id: Option<Sid>) -> Result<()> {
+        self.active = id;
+        self.write(b"\x1b[H\x1b[2J").await?;
+        match id {
+            Some(id) => {
+                let history = self.history.get(&id).cloned().unwrap_or_default();
+                self.write(&history).await?;
+            }
+            None => {
+                self.write(b"[sshx] waiting for a shell to be opened...\r\n")
+                    .await?
+            }
+        }
+        self.set_title().await
+    }
+
+    /// Ask the server to resize the active shell to fit the local terminal.
+    async fn resize_active(&mut self) -> Result<()> {
+        let Some(index) = self.active.and_then(|id| self.position(id)) else {
+            return Ok(());
+        };
+        let (id, winsize) = self.shells[index];
+        let (cols, rows) = crossterm::terminal::size()?;
+        let winsize = WsWinsize {
+            rows,
+            cols,
+            ..winsize
+        };
+        self.client.send(WsClient::Move(id, Some(winsize))).await
+    }
+
+    /// Show the session name and active shell in the terminal title.
+    async fn set_title(&mut self) -> Result<()> {
+        let shell = match self.active.and_then(|id| self.position(id)) {
+            Some(index) => format!("shell {}/{}", index + 1, self.shells.len()),
+            None => String::from("no shells"),
+        };
+        let title = format!("\x1b]0;sshx: {} [{shell}]\x07", self.client.name());
+        self.write(title.as_bytes()).await
+    }
+
+    async fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.stdout.write_all(buf).await?;
+        self.stdout.flush().await?;
+        Ok(())
+    }
+}
+
+/// Guard that keeps the local terminal in raw mode until it is dropped.
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        crossterm::terminal::enable_raw_mode().context("failed to enable raw mode")?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        crossterm::terminal::disable_raw_mode().ok();
+    }
+}
+
+/// Read standard input on a separate thread, since reads are blocking.
+fn spawn_stdin_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(16);
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0u8; 1024];
+        loop {
+            match stdin.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.blocking_send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+    rx
+}
@@ -7,6 +7,8 @@
 #![warn(missing_docs)]
 
 pub mod controller;
-pub mod encrypt;
+pub mod join;
+pub mod recorder;
 pub mod runner;
 pub mod terminal;
+pub mod transport;
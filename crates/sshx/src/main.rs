@@ -1,9 +1,11 @@
+use std::path::PathBuf;
 use std::process::ExitCode;
 
-use ansi_term::Color::{Cyan, Fixed, Green};
 use anyhow::Result;
-use clap::Parser;
-use sshx::{controller::Controller, runner::Runner, terminal::get_default_shell};
+use clap::{Parser, Subcommand};
+use sshx::{
+    controller::Controller, join, recorder::Recorder, runner::Runner, terminal::get_default_shell,
+};
 use tokio::signal;
 use tracing::error;
 
@@ -31,6 +33,29 @@ struct Args {
     /// editors.
     #[clap(long)]
     enable_readers: bool,
+
+    /// Record the output of each shell to local asciicast v2 files, like
+    /// `session.1.cast` and `session.2.cast` for `--record session.cast`.
+    #[clap(long, value_name = "PATH")]
+    record: Option<PathBuf>,
+
+    /// Compress terminal output before encrypting it. This saves bandwidth, but
+    /// the length of each message can reveal some of the output's contents.
+    #[clap(long)]
+    compress: bool,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// Subcommands that do something other than sharing a new session.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Join an existing session from this terminal, instead of a web browser.
+    Join {
+        /// Web URL of the session, including the `#key` part.
+        url: String,
+    },
 }
 
 fn print_greeting(server: &str, controller: &Controller) {
@@ -44,6 +69,10 @@ fn print_greeting(server: &str, controller: &Controller) {
 
 #[tokio::main]
 async fn start(args: Args) -> Result<()> {
+    if let Some(Command::Join { url }) = &args.command {
+        return join::join(url).await;
+    }
+
     let shell = match args.shell {
         Some(shell) => shell,
         None => get_default_shell().await,
@@ -84,7 +113,17 @@ async fn start(args: Args) -> Result<()> {
     };
 
     let runner = Runner::Shell(shell.clone());
-    let mut controller = Controller::new(&args.server, &system_info, runner, args.enable_readers).await?;
+    let mut controller = Controller::new(
+        &args.server,
+        &system_info,
+        runner,
+        args.enable_readers,
+        args.compress,
+    )
+    .await?;
+    if let Some(path) = args.record {
+        controller.record(Recorder::new(path, controller.name()));
+    }
     if args.quiet {
         if let Some(write_url) = controller.write_url() {
             println!("{}", write_url);
@@ -0,0 +1,164 @@
+//! Local recording of shell output in the asciicast v2 format.
+//!
+//! Recordings are written from the plaintext output of each shell, before it is
+//! encrypted and sent to the server, so they can serve as an audit trail that
+//! does not depend on trusting the server. Each shell is written to its own
+//! file, which can be played back with `asciinema play`.
+//!
+//! See <https://docs.asciinema.org/manual/asciicast/v2/> for the file format.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use sshx_core::Sid;
+
+/// Creates recordings for each shell in a session, based on a file path.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    path: PathBuf,
+    title: String,
+}
+
+impl Recorder {
+    /// Construct a new recorder writing files next to the given path.
+    pub fn new(path: impl Into<PathBuf>, title: &str) -> Self {
+        Self {
+            path: path.into(),
+            title: title.into(),
+        }
+    }
+
+    /// Returns the path of the recording for a shell.
+    ///
+    /// For a path like `session.cast`, shell 2 is recorded to
+    /// `session.2.cast`.
+    pub fn shell_path(&self, id: Sid) -> PathBuf {
+        let stem = self.path.file_stem().unwrap_or_default().to_string_lossy();
+        let file_name = match self.path.extension() {
+            Some(ext) => format!("{stem}.{id}.{}", ext.to_string_lossy()),
+            None => format!("{stem}.{id}"),
+        };
+        self.path.with_file_name(file_name)
+    }
+
+    /// Start a new recording for a shell, with its initial size.
+    pub fn shell(&self, id: Sid, rows: u16, cols: u16) -> Result<ShellRecording> {
+        let path = self.shell_path(id);
+        let title = format!("{} (shell {id})", self.title);
+        ShellRecording::create(&path, &title, rows, cols)
+            .with_context(|| format!("failed to create recording at {}", path.display()))
+    }
+}
+
+/// Recording of a single shell, written to as output is produced.
+///
+/// Events are buffered, so that the shell is not held up by a write to disk
+/// for every chunk of output. Call [`ShellRecording::finish`] to flush them
+/// when the shell closes; they are also flushed when the recording is dropped,
+/// but errors are ignored then.
+#[derive(Debug)]
+pub struct ShellRecording {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl ShellRecording {
+    fn create(path: &Path, title: &str, rows: u16, cols: u16) -> Result<Self> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let header = json!({
+            "version": 2,
+            "width": cols,
+            "height": rows,
+            "timestamp": timestamp,
+            "title": title,
+        });
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "{header}")?;
+        Ok(Self {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    /// Record a segment of output from the shell.
+    pub fn output(&mut self, data: &str) -> Result<()> {
+        self.event("o", data)
+    }
+
+    /// Record that the shell was resized.
+    pub fn resize(&mut self, rows: u16, cols: u16) -> Result<()> {
+        self.event("r", &format!("{cols}x{rows}"))
+    }
+
+    fn event(&mut self, code: &str, data: &str) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let time = self.start.elapsed().as_secs_f64();
+        writeln!(self.file, "{}", json!([time, code, data]))?;
+        Ok(())
+    }
+
+    /// Flush buffered events to the file, once the shell has closed.
+    pub fn finish(mut self) -> Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+    use serde_json::Value;
+    use sshx_core::Sid;
+
+    use super::Recorder;
+
+    #[test]
+    fn shell_paths() {
+        let recorder = Recorder::new("/tmp/session.cast", "");
+        assert_eq!(
+            recorder.shell_path(Sid(2)).to_str(),
+            Some("/tmp/session.2.cast")
+        );
+        let recorder = Recorder::new("recording", "");
+        assert_eq!(recorder.shell_path(Sid(1)).to_str(), Some("recording.1"));
+    }
+
+    #[test]
+    fn write_asciicast() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("sshx-recorder-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let recorder = Recorder::new(dir.join("test.cast"), "name");
+
+        let mut recording = recorder.shell(Sid(1), 24, 80)?;
+        recording.output("hello \"world\"\r\n")?;
+        recording.output("")?;
+        recording.resize(30, 100)?;
+        recording.finish()?;
+
+        let text = fs::read_to_string(dir.join("test.1.cast"))?;
+        fs::remove_dir_all(&dir)?;
+        let lines: Vec<Value> = text
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()?;
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["version"], 2);
+        assert_eq!(lines[0]["width"], 80);
+        assert_eq!(lines[0]["height"], 24);
+        assert_eq!(lines[0]["title"], "name (shell 1)");
+        assert_eq!(lines[1][1], "o");
+        assert_eq!(lines[1][2], "hello \"world\"\r\n");
+        assert_eq!(lines[2][1], "r");
+        assert_eq!(lines[2][2], "100x30");
+        assert!(lines[1][0].as_f64().unwrap() <= lines[2][0].as_f64().unwrap());
+        Ok(())
+    }
+}
@@ -1,19 +1,24 @@
 //! Defines tasks that control the behavior of a single shell in the client.
 
+use std::collections::VecDeque;
+
 use anyhow::Result;
 use encoding_rs::{CoderResult, UTF_8};
-use sshx_core::proto::{client_update::ClientMessage, TerminalData};
+use sshx_core::compress::deflate;
+use sshx_core::crypto::{output_stream, Encrypt};
+use sshx_core::proto::{client_update::ClientMessage, Compression, TerminalData};
 use sshx_core::Sid;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     sync::mpsc,
 };
+use tracing::warn;
 
-use crate::encrypt::Encrypt;
+use crate::recorder::{Recorder, ShellRecording};
 use crate::terminal::Terminal;
 
 const CONTENT_CHUNK_SIZE: usize = 1 << 16; // Send at most this many bytes at a time.
-const CONTENT_ROLLING_BYTES: usize = 8 << 20; // Store at least this much content.
+const CONTENT_ROLLING_BYTES: usize = 8 << 20; // Store at least this much sent output.
 const CONTENT_PRUNE_BYTES: usize = 12 << 20; // Prune when we exceed this length.
 
 /// Variants of terminal behavior that are used by the controller.
@@ -42,29 +47,113 @@ impl Runner {
         &self,
         id: Sid,
         encrypt: Encrypt,
+        compression: Compression,
         shell_rx: mpsc::Receiver<ShellData>,
         output_tx: mpsc::Sender<ClientMessage>,
+        recorder: Option<Recorder>,
     ) -> Result<()> {
+        let encoder = Encoder {
+            id,
+            encrypt,
+            compression,
+        };
         match self {
-            Self::Shell(shell) => shell_task(id, encrypt, shell, shell_rx, output_tx).await,
-            Self::Echo => echo_task(id, encrypt, shell_rx, output_tx).await,
+            Self::Shell(shell) => shell_task(encoder, shell, shell_rx, output_tx, recorder).await,
+            Self::Echo => echo_task(encoder, shell_rx, output_tx, recorder).await,
         }
     }
 }
 
-/// Asynchronous task handling a single shell within the session.
-async fn shell_task(
+/// Compresses and encrypts the output of a shell for the server.
+struct Encoder {
     id: Sid,
     encrypt: Encrypt,
+    compression: Compression,
+}
+
+impl Encoder {
+    /// Encode a chunk of output at a sequence number.
+    ///
+    /// Sequence numbers count bytes of encoded output, which is encrypted at
+    /// that offset in the shell's stream.
+    ///
+    /// Compressing before encryption leaks the compressed length of each chunk
+    /// to the server and to anyone watching the connection. If a chunk mixes
+    /// secret output with text that an attacker can influence, like a file
+    /// name or an echoed URL, the length shrinks when a guess matches the
+    /// secret, as in the CRIME attack on TLS. This is why clients only ask for
+    /// compression when run with `--compress`.
+    fn encode(&self, seq: u64, data: &[u8]) -> TerminalData {
+        let data = match self.compression {
+            Compression::None => self.encrypt.segment(output_stream(self.id), seq, data),
+            Compression::Deflate => {
+                let data = deflate(data);
+                self.encrypt.segment(output_stream(self.id), seq, &data)
+            }
+        };
+        TerminalData {
+            id: self.id.0,
+            data: data.into(),
+            seq,
+            compression: self.compression.into(),
+        }
+    }
+}
+
+/// Chunks of output that were sent to the server, kept so that they can be
+/// sent again if the server falls behind.
+///
+/// Chunks are sent again exactly as before, since compressing different output
+/// at the same sequence number would reuse the keystream.
+#[derive(Default)]
+struct SentOutput {
+    chunks: VecDeque<TerminalData>,
+    bytes: usize,
+    end: u64, // sequence number after the last chunk
+}
+
+impl SentOutput {
+    /// Keep a chunk that is being sent, pruning old chunks if needed.
+    fn push(&mut self, data: TerminalData) {
+        self.bytes += data.data.len();
+        self.end = data.seq + data.data.len() as u64;
+        self.chunks.push_back(data);
+        if self.bytes > CONTENT_PRUNE_BYTES {
+            while let Some(front) = self.chunks.front() {
+                if self.bytes - front.data.len() < CONTENT_ROLLING_BYTES {
+                    break;
+                }
+                self.bytes -= front.data.len();
+                self.chunks.pop_front();
+            }
+        }
+    }
+
+    /// Returns the chunk containing a sequence number, or the oldest chunk if
+    /// it was already pruned.
+    fn find(&self, seq: u64) -> Option<&TerminalData> {
+        self.chunks
+            .iter()
+            .find(|data| data.seq + data.data.len() as u64 > seq)
+    }
+}
+
+/// Asynchronous task handling a single shell within the session.
+async fn shell_task(
+    encoder: Encoder,
     shell: &str,
     mut shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
+    recorder: Option<Recorder>,
 ) -> Result<()> {
+    let id = encoder.id;
     let mut term = Terminal::new(shell).await?;
     term.set_winsize(24, 80)?;
+    let (rows, cols) = term.get_winsize()?;
+    let mut recording = start_recording(id, recorder, rows, cols);
 
-    let mut content = String::new(); // content from the terminal
-    let mut content_offset = 0; // bytes before the first character of `content`
+    let mut content = String::new(); // content from the terminal, not yet sent
+    let mut sent = SentOutput::default(); // output sent to the server
     let mut decoder = UTF_8.new_decoder(); // UTF-8 streaming decoder
     let mut seq = 0; // our log of the server's sequence number
     let mut seq_outdated = 0; // number of times seq has been outdated
@@ -78,9 +167,11 @@ async fn shell_task(
                 if n == 0 {
                     finished = true;
                 } else {
+                    let len = content.len();
                     content.reserve(decoder.max_utf8_buffer_length(n).unwrap());
                     let (result, _, _) = decoder.decode_to_string(&buf[..n], &mut content, false);
                     debug_assert!(result == CoderResult::InputEmpty);
+                    record(id, &mut recording, |r| r.output(&content[len..]));
                 }
             }
             item = shell_rx.recv() => {
@@ -89,15 +180,16 @@ async fn shell_task(
                         term.write_all(&data).await?;
                     }
                     Some(ShellData::Sync(seq2)) => {
-                        if seq2 < seq as u64 {
+                        if seq2 < seq {
                             seq_outdated += 1;
                             if seq_outdated >= 3 {
-                                seq = seq2 as usize;
+                                seq = seq2;
                             }
                         }
                     }
                     Some(ShellData::Size(rows, cols)) => {
                         term.set_winsize(rows as u16, cols as u16)?;
+                        record(id, &mut recording, |r| r.resize(rows as u16, cols as u16));
                     }
                     None => finished = true, // Server closed this shell.
                 }
@@ -105,38 +197,72 @@ async fn shell_task(
         }
 
         if finished {
+            let len = content.len();
             content.reserve(decoder.max_utf8_buffer_length(0).unwrap());
             let (result, _, _) = decoder.decode_to_string(&[], &mut content, true);
             debug_assert!(result == CoderResult::InputEmpty);
+            record(id, &mut recording, |r| r.output(&content[len..]));
         }
 
-        // Send data if the server has fallen behind.
-        if content_offset + content.len() > seq {
-            let start = prev_char_boundary(&content, seq - content_offset);
-            let end = prev_char_boundary(&content, (start + CONTENT_CHUNK_SIZE).min(content.len()));
-            let data = encrypt.segment(
-                0x100000000 | id.0 as u64, // stream number
-                (content_offset + start) as u64,
-                &content.as_bytes()[start..end],
-            );
-            let data = TerminalData {
-                id: id.0,
-                data: data.into(),
-                seq: (content_offset + start) as u64,
-            };
+        // Send data again if the server has fallen behind, or else new data.
+        if seq < sent.end {
+            if let Some(data) = sent.find(seq) {
+                seq = data.seq + data.data.len() as u64;
+                output_tx.send(ClientMessage::Data(data.clone())).await?;
+            }
+            seq_outdated = 0;
+        } else if !content.is_empty() {
+            let end = prev_char_boundary(&content, CONTENT_CHUNK_SIZE.min(content.len()));
+            let data = encoder.encode(sent.end, &content.as_bytes()[..end]);
+            content.drain(..end);
+            sent.push(data.clone());
+            seq = sent.end;
             output_tx.send(ClientMessage::Data(data)).await?;
-            seq = content_offset + end;
             seq_outdated = 0;
         }
+    }
+    finish_recording(id, recording);
+    Ok(())
+}
 
-        if content.len() > CONTENT_PRUNE_BYTES && seq - CONTENT_ROLLING_BYTES > content_offset {
-            let pruned = (seq - CONTENT_ROLLING_BYTES) - content_offset;
-            let pruned = prev_char_boundary(&content, pruned);
-            content_offset += pruned;
-            content.drain(..pruned);
+/// Start recording a shell with its initial size, if there is a recorder.
+fn start_recording(
+    id: Sid,
+    recorder: Option<Recorder>,
+    rows: u16,
+    cols: u16,
+) -> Option<ShellRecording> {
+    match recorder?.shell(id, rows, cols) {
+        Ok(recording) => Some(recording),
+        Err(err) => {
+            warn!(%id, ?err, "failed to start recording");
+            None
+        }
+    }
+}
+
+/// Flush the shell's recording, if any, after the shell has closed.
+fn finish_recording(id: Sid, recording: Option<ShellRecording>) {
+    if let Some(Err(err)) = recording.map(ShellRecording::finish) {
+        warn!(%id, ?err, "failed to finish recording");
+    }
+}
+
+/// Write an event to the shell's recording, if any.
+///
+/// Recording is best-effort, so on failure it is stopped without affecting
+/// the shell itself.
+fn record(
+    id: Sid,
+    recording: &mut Option<ShellRecording>,
+    f: impl FnOnce(&mut ShellRecording) -> Result<()>,
+) {
+    if let Some(rec) = recording {
+        if let Err(err) = f(rec) {
+            warn!(%id, ?err, "failed to write recording, stopping it");
+            *recording = None;
         }
     }
-    Ok(())
 }
 
 /// Find the last char boundary before an index in O(1) time.
@@ -148,29 +274,76 @@ fn prev_char_boundary(s: &str, i: usize) -> usize {
 }
 
 async fn echo_task(
-    id: Sid,
-    encrypt: Encrypt,
+    encoder: Encoder,
     mut shell_rx: mpsc::Receiver<ShellData>,
     output_tx: mpsc::Sender<ClientMessage>,
+    recorder: Option<Recorder>,
 ) -> Result<()> {
+    let id = encoder.id;
+    // There is no terminal, so record the size that shells start with.
+    let mut recording = start_recording(id, recorder, 24, 80);
     let mut seq = 0;
     while let Some(item) = shell_rx.recv().await {
         match item {
             ShellData::Data(data) => {
                 let msg = String::from_utf8_lossy(&data);
-                let term_data = TerminalData {
-                    id: id.0,
-                    data: encrypt
-                        .segment(0x100000000 | id.0 as u64, seq, msg.as_bytes())
-                        .into(),
-                    seq,
-                };
+                record(id, &mut recording, |r| r.output(&msg));
+                let term_data = encoder.encode(seq, msg.as_bytes());
+                seq += term_data.data.len() as u64;
                 output_tx.send(ClientMessage::Data(term_data)).await?;
-                seq += msg.len() as u64;
             }
             ShellData::Sync(_) => (),
-            ShellData::Size(_, _) => (),
+            ShellData::Size(rows, cols) => {
+                record(id, &mut recording, |r| r.resize(rows as u16, cols as u16));
+            }
         }
     }
+    finish_recording(id, recording);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use sshx_core::compress::inflate;
+    use sshx_core::crypto::{output_stream, Encrypt};
+    use sshx_core::proto::Compression;
+    use sshx_core::Sid;
+
+    use super::{Encoder, SentOutput};
+
+    #[test]
+    fn encode_compressed() {
+        let encrypt = Encrypt::new("test");
+        let encoder = Encoder {
+            id: Sid(1),
+            encrypt: encrypt.clone(),
+            compression: Compression::Deflate,
+        };
+        let text = "hello world\r\n".repeat(50);
+        let data = encoder.encode(100, text.as_bytes());
+        assert_eq!(data.seq, 100);
+        assert_eq!(data.compression(), Compression::Deflate);
+        assert!(data.data.len() < text.len());
+
+        let decrypted = encrypt.segment(output_stream(Sid(1)), 100, &data.data);
+        assert_eq!(inflate(&decrypted).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn send_again() {
+        let encoder = Encoder {
+            id: Sid(1),
+            encrypt: Encrypt::new("test"),
+            compression: Compression::Deflate,
+        };
+        let mut sent = SentOutput::default();
+        for text in ["first", "second", "third"] {
+            sent.push(encoder.encode(sent.end, text.as_bytes()));
+        }
+        let second = sent.chunks[1].clone();
+        assert_eq!(sent.find(second.seq), Some(&second));
+        assert_eq!(sent.find(second.seq + 1), Some(&second));
+        assert_eq!(sent.find(sent.end), None);
+        assert_eq!(sent.find(0).map(|data| data.seq), Some(0));
+    }
+}
@@ -0,0 +1,190 @@
+//! Connection to the server, over gRPC or a WebSocket fallback.
+//!
+//! Some proxies break gRPC streaming over HTTP/2, so the client can make the
+//! same calls over WebSocket instead. See [`sshx_core::tunnel`] for details.
+
+use std::pin::Pin;
+
+use futures_util::{SinkExt, StreamExt};
+use sshx_core::proto::{
+    sshx_service_client::SshxServiceClient, ClientUpdate, CloseRequest, CloseResponse, OpenRequest,
+    OpenResponse, ServerUpdate,
+};
+use sshx_core::tunnel;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, Stream};
+use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tonic::{transport::Channel, Status};
+use tracing::info;
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Stream of updates received from the server on a channel.
+pub type ServerUpdates = Pin<Box<dyn Stream<Item = Result<ServerUpdate, Status>> + Send>>;
+
+/// Connection to the server, used to make calls from the client.
+pub enum Transport {
+    /// gRPC over HTTP/2, to the server at an HTTP(S) origin.
+    Grpc(SshxServiceClient<Channel>, String),
+
+    /// The same calls over WebSocket, to the server at an HTTP(S) origin.
+    WebSocket(String),
+}
+
+impl Transport {
+    /// Connect to the server at an HTTP(S) origin with gRPC, falling back to
+    /// WebSocket if the HTTP/2 handshake fails.
+    pub async fn connect(origin: &str) -> Self {
+        match SshxServiceClient::connect(String::from(origin)).await {
+            Ok(client) => Self::Grpc(client, String::from(origin)),
+            Err(err) => {
+                info!(?err, "gRPC handshake failed, falling back to WebSocket");
+                Self::WebSocket(String::from(origin))
+            }
+        }
+    }
+
+    /// Returns whether calls are made over WebSocket.
+    pub fn is_websocket(&self) -> bool {
+        matches!(self, Self::WebSocket(_))
+    }
+
+    /// Create a new session.
+    ///
+    /// The HTTP/2 handshake may only happen on the first call, so this also
+    /// falls back to WebSocket if that fails at the transport level.
+    pub async fn open(&mut self, req: OpenRequest) -> Result<OpenResponse, Status> {
+        match self {
+            Self::Grpc(client, origin) => match client.open(req.clone()).await {
+                Ok(resp) => Ok(resp.into_inner()),
+                Err(status) if is_transport_error(&status) => {
+                    info!(?status, "gRPC call failed, falling back to WebSocket");
+                    let resp = unary(origin, "open", req).await?;
+                    *self = Self::WebSocket(std::mem::take(origin));
+                    Ok(resp)
+                }
+                Err(status) => Err(status),
+            },
+            Self::WebSocket(origin) => unary(origin, "open", req).await,
+        }
+    }
+
+    /// Stream updates to and from a session.
+    pub async fn channel(
+        &mut self,
+        updates: ReceiverStream<ClientUpdate>,
+    ) -> Result<ServerUpdates, Status> {
+        match self {
+            Self::Grpc(client, _) => Ok(Box::pin(client.channel(updates).await?.into_inner())),
+            Self::WebSocket(origin) => channel(origin, updates).await,
+        }
+    }
+
+    /// Close a session gracefully.
+    pub async fn close(&mut self, req: CloseRequest) -> Result<CloseResponse, Status> {
+        match self {
+            Self::Grpc(client, _) => Ok(client.close(req).await?.into_inner()),
+            Self::WebSocket(origin) => unary(origin, "close", req).await,
+        }
+    }
+}
+
+/// Returns whether a gRPC call failed in the connection, rather than on the
+/// server.
+fn is_transport_error(status: &Status) -> bool {
+    std::error::Error::source(status).is_some_and(|err| err.is::<tonic::transport::Error>())
+}
+
+/// Open a WebSocket connection for a call.
+async fn connect(origin: &str, method: &str) -> Result<Socket, Status> {
+    let url = tunnel::url(origin, method);
+    match tokio_tungstenite::connect_async(&url).await {
+        Ok((socket, _)) => Ok(socket),
+        Err(err) => Err(Status::unavailable(format!(
+            "failed to connect to {url}: {err}"
+        ))),
+    }
+}
+
+/// Send a protobuf message to the server.
+async fn send(socket: &mut Socket, msg: &impl prost::Message) -> Result<(), Status> {
+    let msg = Message::Binary(msg.encode_to_vec().into());
+    socket
+        .send(msg)
+        .await
+        .map_err(|err| Status::unavailable(err.to_string()))
+}
+
+/// Receive the next protobuf message from the server, or `None` if the
+/// connection was closed normally.
+async fn recv<T: prost::Message + Default>(socket: &mut Socket) -> Result<Option<T>, Status> {
+    loop {
+        match socket.next().await {
+            Some(Ok(Message::Binary(msg))) => {
+                return T::decode(msg)
+                    .map(Some)
+                    .map_err(|err| Status::internal(err.to_string()));
+            }
+            Some(Ok(Message::Close(Some(frame)))) if frame.code != CloseCode::Normal => {
+                return Err(tunnel::close_to_status(frame.code.into(), &frame.reason));
+            }
+            Some(Ok(Message::Close(_))) | None => return Ok(None),
+            Some(Ok(_)) => (), // ignore other message types, keep looping
+            Some(Err(err)) => return Err(Status::unavailable(err.to_string())),
+        }
+    }
+}
+
+/// Make a call with a single request and response.
+async fn unary<U: prost::Message + Default>(
+    origin: &str,
+    method: &str,
+    req: impl prost::Message,
+) -> Result<U, Status> {
+    let mut socket = connect(origin, method).await?;
+    send(&mut socket, &req).await?;
+    let resp = recv(&mut socket).await?;
+    socket.close(None).await.ok();
+    resp.ok_or_else(|| Status::unavailable("server closed the connection"))
+}
+
+/// Start a channel, forwarding updates in both directions from a task.
+async fn channel(
+    origin: &str,
+    mut updates: ReceiverStream<ClientUpdate>,
+) -> Result<ServerUpdates, Status> {
+    let mut socket = connect(origin, "channel").await?;
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                update = updates.next() => match update {
+                    Some(update) => {
+                        if let Err(status) = send(&mut socket, &update).await {
+                            tx.send(Err(status)).await.ok();
+                            return;
+                        }
+                    }
+                    None => break,
+                },
+                msg = recv(&mut socket) => match msg {
+                    Ok(Some(update)) => {
+                        if tx.send(Ok(update)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(status) => {
+                        tx.send(Err(status)).await.ok();
+                        return;
+                    }
+                },
+                _ = tx.closed() => break,
+            }
+        }
+        socket.close(None).await.ok();
+    });
+    Ok(Box::pin(ReceiverStream::new(rx)))
+}
@@ -0,0 +1,12 @@
+/**
+ * @file Decompression of terminal output, which is compressed before it is
+ * encrypted. Keep this file consistent with the Rust implementation.
+ */
+
+/** Decompress a chunk of output that was compressed with raw deflate. */
+export async function inflate(data: Uint8Array): Promise<Uint8Array> {
+  const stream = new Blob([data])
+    .stream()
+    .pipeThrough(new DecompressionStream("deflate-raw"));
+  return new Uint8Array(await new Response(stream).arrayBuffer());
+}
@@ -1,6 +1,9 @@
 type Sid = number; // u32
 type Uid = number; // u32
 
+/** Version of the protocol, see `PROTOCOL_VERSION` in the Rust version. */
+export const PROTOCOL_VERSION = 2;
+
 /** Position and size of a window, see the Rust version. */
 export type WsWinsize = {
   x: number;
@@ -19,7 +22,7 @@ export type WsUser = {
 
 /** Server message type, see the Rust version. */
 export type WsServer = {
-  hello?: [Uid, string];
+  hello?: [Uid, string, boolean?, number?];
   invalidAuth?: [];
   users?: [Uid, WsUser][];
   userDiff?: [Uid, WsUser | null];
@@ -29,11 +32,12 @@ export type WsServer = {
   shellLatency?: number | bigint;
   pong?: number | bigint;
   error?: string;
+  deflated?: Uint8Array;
 };
 
 /** Client message type, see the Rust version. */
 export type WsClient = {
-  authenticate?: [Uint8Array, Uint8Array | null];
+  authenticate?: [Uint8Array, Uint8Array | null, number];
   setName?: string;
   setCursor?: [number, number] | null;
   setFocus?: number | null;
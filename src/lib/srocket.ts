@@ -10,6 +10,12 @@ import { encode, decode } from "cbor-x";
 /** How long to wait between reconnections (in milliseconds). */
 const RECONNECT_DELAY = 500;
 
+/** How long to wait after the server rejects a connection as rate limited. */
+const RATE_LIMITED_DELAY = 5000;
+
+/** Close code sent by the server when a client is rate limited. */
+const RATE_LIMITED_CODE = 4429;
+
 /** Number of messages to queue while disconnected. */
 const BUFFER_SIZE = 64;
 
@@ -95,7 +101,9 @@ export class Srocket<T, U> {
       this.#options.onClose?.(event);
       this.#ws = null;
       this.#stateChange(false);
-      setTimeout(() => this.#reconnect(), RECONNECT_DELAY);
+      const delay =
+        event.code === RATE_LIMITED_CODE ? RATE_LIMITED_DELAY : RECONNECT_DELAY;
+      setTimeout(() => this.#reconnect(), delay);
     };
     this.#ws.onmessage = (event) => {
       if (event.data instanceof ArrayBuffer) {